@@ -0,0 +1,9 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+use nes_emu::rom;
+
+fuzz_target!(|data: &[u8]| {
+    let _ = rom::Rom::new(data);
+});
@@ -0,0 +1,415 @@
+use crate::region::Timing;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use dmc::Dmc;
+use filter::Filter;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+mod dmc;
+mod filter;
+mod noise;
+mod pulse;
+mod triangle;
+mod units;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44100;
+
+// $4015
+const STATUS_PULSE1: u8 = 0b0000_0001;
+const STATUS_PULSE2: u8 = 0b0000_0010;
+const STATUS_TRIANGLE: u8 = 0b0000_0100;
+const STATUS_NOISE: u8 = 0b0000_1000;
+const STATUS_DMC: u8 = 0b0001_0000;
+const STATUS_FRAME_IRQ: u8 = 0b0100_0000;
+const STATUS_DMC_IRQ: u8 = 0b1000_0000;
+// $4017
+const FRAME_COUNTER_FIVE_STEP: u8 = 0b1000_0000;
+const FRAME_COUNTER_IRQ_INHIBIT: u8 = 0b0100_0000;
+
+// what the console's output stage filters out
+const HIGH_PASS_CUTOFFS: [f32; 2] = [90.0, 440.0];
+const LOW_PASS_CUTOFF: f32 = 14000.0;
+
+// The 2A03's sound: two pulse channels, a triangle, noise and the DMC,
+// stepped every CPU cycle. The frame counter clocks their envelopes and linear counter
+// every quarter frame and their length counters and sweeps every half,
+// and in 4-step mode raises an IRQ at the end of each sequence.
+pub struct Apu {
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+    // pulse timers only count on every other cycle
+    odd_cycle: bool,
+
+    frame_counter_steps: [usize; 5],
+    frame_cycle: usize,
+    five_step: bool,
+    irq_inhibit: bool,
+    // cleared by reading $4015
+    frame_irq: bool,
+
+    // the mixer's nonlinear output for every pulse sum and 3*triangle + 2*noise
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+    cpu_clock: f64,
+    sample_rate: u32,
+    // goes up by the sample rate every cycle, a sample is due each time
+    // it passes the CPU clock
+    sample_clock: f64,
+    // the mixer output is averaged over each sample, which keeps
+    // frequencies too high for the output rate from folding back down
+    sample_sum: f32,
+    sample_count: u32,
+    filters: Vec<Filter>,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new(timing: &Timing, sample_rate: u32) -> Self {
+        let mut apu = Apu {
+            pulse1: Pulse::new(true),
+            pulse2: Pulse::new(false),
+            triangle: Triangle::default(),
+            noise: Noise::new(timing.noise_periods),
+            dmc: Dmc::new(timing.dmc_rates),
+            odd_cycle: false,
+            frame_counter_steps: timing.frame_counter_steps,
+            frame_cycle: 0,
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: false,
+            pulse_table: std::array::from_fn(|n| match n {
+                0 => 0.0,
+                _ => 95.52 / (8128.0 / n as f32 + 100.0),
+            }),
+            tnd_table: std::array::from_fn(|n| match n {
+                0 => 0.0,
+                _ => 163.67 / (24329.0 / n as f32 + 100.0),
+            }),
+            cpu_clock: timing.cpu_clock(),
+            sample_rate,
+            sample_clock: 0.0,
+            sample_sum: 0.0,
+            sample_count: 0,
+            filters: vec![],
+            samples: vec![],
+        };
+        apu.set_sample_rate(sample_rate);
+        apu
+    }
+
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.sample_rate = sample_rate;
+        self.filters = HIGH_PASS_CUTOFFS
+            .iter()
+            .map(|&cutoff| Filter::high_pass(cutoff, sample_rate))
+            .chain([Filter::low_pass(LOW_PASS_CUTOFF, sample_rate)])
+            .collect();
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr - 0x4000, data),
+            0x4004..=0x4007 => self.pulse2.write(addr - 0x4004, data),
+            0x4008..=0x400B => self.triangle.write(addr - 0x4008, data),
+            0x400C..=0x400F => self.noise.write(addr - 0x400C, data),
+            0x4010..=0x4013 => self.dmc.write(addr - 0x4010, data),
+            0x4015 => {
+                self.pulse1.length.set_enabled(data & STATUS_PULSE1 != 0);
+                self.pulse2.length.set_enabled(data & STATUS_PULSE2 != 0);
+                self.triangle
+                    .length
+                    .set_enabled(data & STATUS_TRIANGLE != 0);
+                self.noise.length.set_enabled(data & STATUS_NOISE != 0);
+                self.dmc.set_enabled(data & STATUS_DMC != 0);
+                self.dmc.irq = false;
+            }
+            0x4017 => {
+                self.five_step = data & FRAME_COUNTER_FIVE_STEP != 0;
+                self.irq_inhibit = data & FRAME_COUNTER_IRQ_INHIBIT != 0;
+                if self.irq_inhibit {
+                    self.frame_irq = false;
+                }
+                self.frame_cycle = 0;
+                // starting the 5-step sequence clocks everything straight away
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+            _ => {}
+        }
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq = false;
+        status
+    }
+
+    // $4015 without acknowledging the frame IRQ
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        for (active, bit) in [
+            (self.pulse1.length.active(), STATUS_PULSE1),
+            (self.pulse2.length.active(), STATUS_PULSE2),
+            (self.triangle.length.active(), STATUS_TRIANGLE),
+            (self.noise.length.active(), STATUS_NOISE),
+            (self.dmc.active(), STATUS_DMC),
+            (self.dmc.irq, STATUS_DMC_IRQ),
+        ] {
+            if active {
+                status |= bit;
+            }
+        }
+        if self.frame_irq {
+            status |= STATUS_FRAME_IRQ;
+        }
+        status
+    }
+
+    pub fn irq(&self) -> bool {
+        self.frame_irq || self.dmc.irq
+    }
+
+    // where the DMC wants its next sample byte read from; the bus does the
+    // read and hands it over with dmc_fill
+    pub fn dmc_dma_address(&self) -> Option<u16> {
+        self.dmc.dma_address()
+    }
+
+    pub fn dmc_fill(&mut self, data: u8) {
+        self.dmc.fill(data);
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            self.clock();
+        }
+    }
+
+    fn clock(&mut self) {
+        self.clock_frame_counter();
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if !self.odd_cycle {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.odd_cycle = !self.odd_cycle;
+
+        self.sample_sum += self.mix();
+        self.sample_count += 1;
+        self.sample_clock += self.sample_rate as f64;
+        if self.sample_clock >= self.cpu_clock {
+            self.sample_clock -= self.cpu_clock;
+            let mut sample = self.sample_sum / self.sample_count as f32;
+            self.sample_sum = 0.0;
+            self.sample_count = 0;
+            for filter in &mut self.filters {
+                sample = filter.process(sample);
+            }
+            self.samples.push(sample.clamp(-1.0, 1.0));
+        }
+    }
+
+    fn clock_frame_counter(&mut self) {
+        let cycle = self.frame_cycle;
+        self.frame_cycle += 1;
+        let step = match self.frame_counter_steps.iter().position(|&c| c == cycle) {
+            Some(step) => step,
+            None => return,
+        };
+        match (self.five_step, step) {
+            // the 5-step sequence does nothing on its fourth step
+            (true, 3) => {}
+            (_, 1) | (false, 3) | (true, 4) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            _ => self.quarter_frame(),
+        }
+        // the sequence restarts the cycle after its last step
+        let last_step = if self.five_step { 4 } else { 3 };
+        if step == last_step {
+            self.frame_cycle = 0;
+            if !self.five_step && !self.irq_inhibit {
+                self.frame_irq = true;
+            }
+        }
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear();
+        self.noise.envelope.clock();
+    }
+
+    fn half_frame(&mut self) {
+        for length in [
+            &mut self.pulse1.length,
+            &mut self.pulse2.length,
+            &mut self.triangle.length,
+            &mut self.noise.length,
+        ] {
+            length.clock();
+        }
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    // 0.0 to about 1.0, the 2A03's nonlinear DAC
+    fn mix(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        let tnd = 3 * self.triangle.output() as usize
+            + 2 * self.noise.output() as usize
+            + self.dmc.output() as usize;
+        self.pulse_table[pulse as usize] + self.tnd_table[tnd]
+    }
+
+    // hands over everything generated since the last call
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+}
+
+impl Snapshot for Apu {
+    fn snapshot(&self, w: &mut StateWriter) {
+        self.pulse1.snapshot(w);
+        self.pulse2.snapshot(w);
+        self.triangle.snapshot(w);
+        self.noise.snapshot(w);
+        self.dmc.snapshot(w);
+        w.write_u8(self.odd_cycle as u8);
+        w.write_u16(self.frame_cycle as u16);
+        w.write_u8(self.five_step as u8);
+        w.write_u8(self.irq_inhibit as u8);
+        w.write_u8(self.frame_irq as u8);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.pulse1.restore(r)?;
+        self.pulse2.restore(r)?;
+        self.triangle.restore(r)?;
+        self.noise.restore(r)?;
+        self.dmc.restore(r)?;
+        self.odd_cycle = r.read_u8()? != 0;
+        self.frame_cycle = r.read_u16()? as usize;
+        self.five_step = r.read_u8()? != 0;
+        self.irq_inhibit = r.read_u8()? != 0;
+        self.frame_irq = r.read_u8()? != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::{NTSC, PAL};
+
+    fn test_timing() -> Timing {
+        Timing {
+            master_clock: 12_000_000.0,
+            ..NTSC
+        }
+    }
+
+    #[test]
+    fn test_sample_clock() {
+        let mut apu = Apu::new(&test_timing(), 1000);
+        apu.tick(999);
+        assert!(apu.take_samples().is_empty());
+        apu.tick(1);
+        apu.tick(2500);
+        assert_eq!(apu.take_samples().len(), 3);
+    }
+
+    #[test]
+    fn test_status() {
+        let mut apu = Apu::new(&test_timing(), 1000);
+        // lengths only load into enabled channels
+        apu.write_register(0x4003, 0x08);
+        apu.write_register(0x4015, 0x0F);
+        for addr in [0x4003, 0x4007, 0x400B, 0x400F] {
+            apu.write_register(addr, 0x08);
+        }
+        assert_eq!(apu.read_status(), 0x0F);
+        apu.write_register(0x4015, 0x05);
+        assert_eq!(apu.read_status(), 0x05);
+
+        // a length of 254 runs out after 127 frames of two half frames
+        apu.write_register(0x4017, 0x40);
+        apu.tick(29830 * 126);
+        assert_eq!(apu.read_status(), 0x05);
+        apu.tick(29830);
+        assert_eq!(apu.read_status(), 0x00);
+    }
+
+    #[test]
+    fn test_mixer_output() {
+        let mut apu = Apu::new(&NTSC, 44100);
+        apu.write_register(0x4015, 0x01);
+        // a 440Hz square at full constant volume
+        apu.write_register(0x4000, 0b1011_1111);
+        apu.write_register(0x4002, 0xFD);
+        apu.write_register(0x4003, 0x00);
+        apu.tick(NTSC.cpu_clock() as usize / 10);
+        let samples = apu.take_samples();
+        assert!((4409..=4410).contains(&samples.len()));
+        let peak = samples.iter().fold(0.0f32, |peak, s| peak.max(s.abs()));
+        // the pulse table's top is 95.52 / (8128 / 15 + 100), about 0.149;
+        // with the DC filtered out it swings either side of zero, the
+        // high-passes overshooting a little on every edge
+        assert!((0.1..0.4).contains(&peak), "{}", peak);
+        let crossings = samples[2205..]
+            .windows(2)
+            .filter(|w| w[0] < 0.0 && w[1] >= 0.0)
+            .count();
+        assert!((21..=23).contains(&crossings), "{}", crossings);
+
+        // with every channel off it all settles back to silence
+        apu.write_register(0x4015, 0x00);
+        apu.tick(NTSC.cpu_clock() as usize / 10);
+        assert!(apu.take_samples()[4000..].iter().all(|s| s.abs() < 0.001));
+    }
+
+    #[test]
+    fn test_frame_irq() {
+        let mut apu = Apu::new(&NTSC, 1000);
+        apu.tick(29829);
+        assert!(!apu.irq());
+        apu.tick(1);
+        assert!(apu.irq());
+        assert_eq!(apu.read_status(), 0x40);
+        assert!(!apu.irq());
+
+        // PAL's sequence is longer
+        let mut apu = Apu::new(&PAL, 1000);
+        apu.tick(29830);
+        assert!(!apu.irq());
+        apu.tick(33254 - 29830);
+        assert!(apu.irq());
+    }
+
+    #[test]
+    fn test_frame_irq_disabled() {
+        let mut apu = Apu::new(&NTSC, 1000);
+        apu.write_register(0x4017, 0x40);
+        apu.tick(29830);
+        assert!(!apu.irq());
+
+        apu.write_register(0x4017, 0x80);
+        apu.tick(37282);
+        assert!(!apu.irq());
+    }
+}
@@ -0,0 +1,259 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// $4010
+const FLAG_IRQ_ENABLE: u8 = 0b1000_0000;
+const FLAG_LOOP: u8 = 0b0100_0000;
+
+// Plays 1-bit delta samples out of PRG ROM: each bit moves a 7-bit output
+// level up or down by 2. Sample bytes are fetched by DMA, which the bus
+// does when `dma_address` asks for one, stalling the CPU.
+pub struct Dmc {
+    // output unit periods in CPU cycles, they differ on PAL
+    rates: [u16; 16],
+    irq_enabled: bool,
+    looping: bool,
+    period: u16,
+    timer: u16,
+    level: u8,
+    // where a sample starts and how long it is, from $4012/$4013
+    sample_address: u16,
+    sample_length: u16,
+    // the memory reader's progress through the current sample
+    address: u16,
+    bytes_remaining: u16,
+    buffer: Option<u8>,
+    // the output unit's byte being played
+    shift: u8,
+    bits_remaining: u8,
+    silence: bool,
+    pub irq: bool,
+}
+
+impl Dmc {
+    pub fn new(rates: [u16; 16]) -> Self {
+        Dmc {
+            rates,
+            irq_enabled: false,
+            looping: false,
+            period: rates[0],
+            timer: 0,
+            level: 0,
+            sample_address: 0xC000,
+            sample_length: 1,
+            address: 0xC000,
+            bytes_remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits_remaining: 8,
+            silence: true,
+            irq: false,
+        }
+    }
+
+    // `reg` is 0-3 for $4010-$4013
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.irq_enabled = data & FLAG_IRQ_ENABLE != 0;
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+                self.looping = data & FLAG_LOOP != 0;
+                self.period = self.rates[(data & 0b1111) as usize];
+            }
+            1 => self.level = data & 0b0111_1111,
+            2 => self.sample_address = 0xC000 + data as u16 * 64,
+            _ => self.sample_length = data as u16 * 16 + 1,
+        }
+    }
+
+    // bit 4 of $4015: stops the sample, or starts it if it had finished
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    fn restart(&mut self) {
+        self.address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    pub fn active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    // the address the next sample byte has to come from, if one is due
+    pub fn dma_address(&self) -> Option<u16> {
+        match self.buffer {
+            None if self.bytes_remaining > 0 => Some(self.address),
+            _ => None,
+        }
+    }
+
+    pub fn fill(&mut self, data: u8) {
+        self.buffer = Some(data);
+        // past the end of the address space it wraps round to $8000
+        self.address = self.address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    // every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.period - 1;
+
+        if !self.silence {
+            // it stays put rather than wrap at either end
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits_remaining -= 1;
+        if self.bits_remaining == 0 {
+            self.bits_remaining = 8;
+            match self.buffer.take() {
+                Some(data) => {
+                    self.shift = data;
+                    self.silence = false;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    // 0-127
+    pub fn output(&self) -> u8 {
+        self.level
+    }
+}
+
+impl Snapshot for Dmc {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.irq_enabled as u8);
+        w.write_u8(self.looping as u8);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_u8(self.level);
+        w.write_u16(self.sample_address);
+        w.write_u16(self.sample_length);
+        w.write_u16(self.address);
+        w.write_u16(self.bytes_remaining);
+        // the buffer's byte, with a flag for whether it's full
+        w.write_u8(self.buffer.is_some() as u8);
+        w.write_u8(self.buffer.unwrap_or(0));
+        w.write_u8(self.shift);
+        w.write_u8(self.bits_remaining);
+        w.write_u8(self.silence as u8);
+        w.write_u8(self.irq as u8);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = r.read_u8()? != 0;
+        self.looping = r.read_u8()? != 0;
+        self.period = r.read_u16()?.max(1);
+        self.timer = r.read_u16()?;
+        self.level = r.read_u8()? & 0b0111_1111;
+        self.sample_address = r.read_u16()?;
+        self.sample_length = r.read_u16()?;
+        self.address = r.read_u16()?;
+        self.bytes_remaining = r.read_u16()?;
+        let full = r.read_u8()? != 0;
+        let data = r.read_u8()?;
+        self.buffer = full.then_some(data);
+        self.shift = r.read_u8()?;
+        self.bits_remaining = r.read_u8()?.clamp(1, 8);
+        self.silence = r.read_u8()? != 0;
+        self.irq = r.read_u8()? != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::NTSC;
+
+    // runs the output unit through one whole byte
+    fn play_byte(dmc: &mut Dmc) {
+        for _ in 0..8 * dmc.period {
+            dmc.clock_timer();
+        }
+    }
+
+    #[test]
+    fn test_output_unit() {
+        let mut dmc = Dmc::new(NTSC.dmc_rates);
+        dmc.write(1, 64);
+        dmc.write(3, 0);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.dma_address(), Some(0xC000));
+        dmc.fill(0b0000_0111);
+        assert_eq!(dmc.dma_address(), None);
+
+        // the byte waits in the buffer until the silent one before it is done
+        play_byte(&mut dmc);
+        assert_eq!(dmc.output(), 64);
+        play_byte(&mut dmc);
+        assert_eq!(dmc.output(), 64 + 3 * 2 - 5 * 2);
+
+        // and clamps instead of wrapping
+        dmc.write(1, 127);
+        dmc.set_enabled(true);
+        dmc.fill(0xFF);
+        play_byte(&mut dmc);
+        play_byte(&mut dmc);
+        assert_eq!(dmc.output(), 127);
+    }
+
+    #[test]
+    fn test_loop_and_irq() {
+        let mut dmc = Dmc::new(NTSC.dmc_rates);
+        // the last 64 bytes of the address space and one more
+        dmc.write(2, 0xFF);
+        dmc.write(3, 4);
+        dmc.write(0, FLAG_IRQ_ENABLE);
+        dmc.set_enabled(true);
+        let mut addresses = vec![];
+        while let Some(addr) = dmc.dma_address() {
+            addresses.push(addr);
+            dmc.fill(0);
+            dmc.buffer = None;
+        }
+        assert_eq!(addresses.len(), 65);
+        assert_eq!(addresses[0], 0xFFC0);
+        assert_eq!(addresses[64], 0x8000);
+        assert!(dmc.irq);
+        // turning the IRQ off acknowledges it
+        dmc.write(0, 0);
+        assert!(!dmc.irq);
+
+        // looping starts again instead, and never raises the IRQ
+        dmc.write(0, FLAG_IRQ_ENABLE | FLAG_LOOP);
+        dmc.set_enabled(true);
+        for _ in 0..65 * 2 {
+            assert!(dmc.dma_address().is_some());
+            dmc.fill(0);
+            dmc.buffer = None;
+        }
+        assert!(dmc.active());
+        assert!(!dmc.irq);
+    }
+}
@@ -0,0 +1,68 @@
+use std::f32::consts::PI;
+
+// The first-order filters between the APU and the console's audio out: two
+// high-passes that take away the DC offset and some bass, and a low-pass.
+pub struct Filter {
+    kind: Kind,
+    factor: f32,
+    last_in: f32,
+    last_out: f32,
+}
+
+enum Kind {
+    HighPass,
+    LowPass,
+}
+
+impl Filter {
+    fn new(kind: Kind, cutoff: f32, sample_rate: u32) -> Self {
+        let rc = 1.0 / (2.0 * PI * cutoff);
+        let dt = 1.0 / sample_rate as f32;
+        let factor = match kind {
+            Kind::HighPass => rc / (rc + dt),
+            Kind::LowPass => dt / (rc + dt),
+        };
+        Filter {
+            kind,
+            factor,
+            last_in: 0.0,
+            last_out: 0.0,
+        }
+    }
+
+    pub fn high_pass(cutoff: f32, sample_rate: u32) -> Self {
+        Filter::new(Kind::HighPass, cutoff, sample_rate)
+    }
+
+    pub fn low_pass(cutoff: f32, sample_rate: u32) -> Self {
+        Filter::new(Kind::LowPass, cutoff, sample_rate)
+    }
+
+    pub fn process(&mut self, input: f32) -> f32 {
+        self.last_out = match self.kind {
+            Kind::HighPass => self.factor * (self.last_out + input - self.last_in),
+            Kind::LowPass => self.last_out + self.factor * (input - self.last_out),
+        };
+        self.last_in = input;
+        self.last_out
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_filters() {
+        // a steady level dies away through a high-pass and comes through a low-pass
+        let mut high = Filter::high_pass(90.0, 44100);
+        let mut low = Filter::low_pass(14000.0, 44100);
+        let (mut h, mut l) = (0.0, 0.0);
+        for _ in 0..44100 {
+            h = high.process(0.5);
+            l = low.process(0.5);
+        }
+        assert!(h.abs() < 0.001);
+        assert!((l - 0.5).abs() < 0.001);
+    }
+}
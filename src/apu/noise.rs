@@ -0,0 +1,120 @@
+use super::units::{Envelope, LengthCounter};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// Pseudo-random bits from a 15-bit shift register. The normal mode feeds
+// back bit 1 for a 32767-step hiss; the short mode feeds back bit 6, which
+// gets stuck in a 93 or 31 step loop and sounds metallic.
+pub struct Noise {
+    // timer periods in CPU cycles, they differ on PAL
+    periods: [u16; 16],
+    period: u16,
+    timer: u16,
+    short_mode: bool,
+    shift: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+}
+
+impl Noise {
+    pub fn new(periods: [u16; 16]) -> Self {
+        Noise {
+            periods,
+            period: periods[0],
+            timer: 0,
+            short_mode: false,
+            // it powers up at 1, all zeroes would never change
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    // `reg` is 0-3 for $400C-$400F, $400D does nothing
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {}
+            2 => {
+                self.short_mode = data & 0b1000_0000 != 0;
+                self.period = self.periods[(data & 0b1111) as usize];
+            }
+            _ => {
+                self.length.load(data);
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap = match self.short_mode {
+                true => 6,
+                false => 1,
+            };
+            let feedback = (self.shift ^ self.shift >> tap) & 1;
+            self.shift = self.shift >> 1 | feedback << 14;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || !self.length.active() {
+            return 0;
+        }
+        self.envelope.volume()
+    }
+}
+
+impl Snapshot for Noise {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        w.write_u8(self.short_mode as u8);
+        w.write_u16(self.shift);
+        self.envelope.snapshot(w);
+        self.length.snapshot(w);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.period = r.read_u16()?.max(1);
+        self.timer = r.read_u16()?;
+        self.short_mode = r.read_u8()? != 0;
+        self.shift = r.read_u16()? & 0x7FFF;
+        self.envelope.restore(r)?;
+        self.length.restore(r)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::NTSC;
+
+    fn loop_length(short_mode: bool) -> usize {
+        let mut noise = Noise::new(NTSC.noise_periods);
+        noise.write(2, (short_mode as u8) << 7);
+        let start = noise.shift;
+        (1..)
+            .find(|_| {
+                for _ in 0..noise.period {
+                    noise.clock_timer();
+                }
+                noise.shift == start
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn test_shift_register_modes() {
+        assert_eq!(loop_length(false), 32767);
+        assert_eq!(loop_length(true), 93);
+    }
+}
@@ -0,0 +1,209 @@
+use super::units::{Envelope, LengthCounter};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+#[rustfmt::skip]
+const DUTY_CYCLES: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+// A square wave at one of four duty cycles. The timer counts APU cycles, two
+// CPU cycles each, and the sweep unit can bend the period every half frame.
+pub struct Pulse {
+    // pulse 1 negates in ones' complement, so its sweeps down go one further
+    ones_complement: bool,
+    duty: u8,
+    step: u8,
+    period: u16,
+    timer: u16,
+    pub envelope: Envelope,
+    pub length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub fn new(ones_complement: bool) -> Self {
+        Pulse {
+            ones_complement,
+            duty: 0,
+            step: 0,
+            period: 0,
+            timer: 0,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+            sweep_enabled: false,
+            sweep_period: 0,
+            sweep_negate: false,
+            sweep_shift: 0,
+            sweep_divider: 0,
+            sweep_reload: false,
+        }
+    }
+
+    // `reg` is 0-3 for $4000-$4003 or $4004-$4007
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.duty = data >> 6;
+                self.length.halt = data & 0b0010_0000 != 0;
+                self.envelope.write(data);
+            }
+            1 => {
+                self.sweep_enabled = data & 0b1000_0000 != 0;
+                self.sweep_period = data >> 4 & 0b111;
+                self.sweep_negate = data & 0b0000_1000 != 0;
+                self.sweep_shift = data & 0b111;
+                self.sweep_reload = true;
+            }
+            2 => self.period = self.period & 0x700 | data as u16,
+            _ => {
+                self.period = self.period & 0xFF | ((data & 0b111) as u16) << 8;
+                self.length.load(data);
+                self.step = 0;
+                self.envelope.start = true;
+            }
+        }
+    }
+
+    // every other CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step = (self.step + 1) % 8;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    fn sweep_target(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        match (self.sweep_negate, self.ones_complement) {
+            (false, _) => self.period + change,
+            (true, true) => self.period.saturating_sub(change + 1),
+            (true, false) => self.period.saturating_sub(change),
+        }
+    }
+
+    // too high a note, or one the sweep would take out of range, is cut
+    // off whether or not the sweep is enabled
+    fn muted(&self) -> bool {
+        self.period < 8 || self.sweep_target() > 0x7FF
+    }
+
+    pub fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.sweep_target();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    // 0-15
+    pub fn output(&self) -> u8 {
+        if self.muted() || !self.length.active() {
+            return 0;
+        }
+        DUTY_CYCLES[self.duty as usize][self.step as usize] * self.envelope.volume()
+    }
+}
+
+impl Snapshot for Pulse {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.duty);
+        w.write_u8(self.step);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        self.envelope.snapshot(w);
+        self.length.snapshot(w);
+        w.write_u8(self.sweep_enabled as u8);
+        w.write_u8(self.sweep_period);
+        w.write_u8(self.sweep_negate as u8);
+        w.write_u8(self.sweep_shift);
+        w.write_u8(self.sweep_divider);
+        w.write_u8(self.sweep_reload as u8);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.duty = r.read_u8()? & 0b11;
+        self.step = r.read_u8()? % 8;
+        self.period = r.read_u16()? & 0x7FF;
+        self.timer = r.read_u16()?;
+        self.envelope.restore(r)?;
+        self.length.restore(r)?;
+        self.sweep_enabled = r.read_u8()? != 0;
+        self.sweep_period = r.read_u8()?;
+        self.sweep_negate = r.read_u8()? != 0;
+        self.sweep_shift = r.read_u8()? & 0b111;
+        self.sweep_divider = r.read_u8()?;
+        self.sweep_reload = r.read_u8()? != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn playing(ones_complement: bool) -> Pulse {
+        let mut pulse = Pulse::new(ones_complement);
+        pulse.length.set_enabled(true);
+        // 50% duty at constant volume 10, period $100
+        pulse.write(0, 0b1011_1010);
+        pulse.write(2, 0x00);
+        pulse.write(3, 0b0000_1001);
+        pulse
+    }
+
+    #[test]
+    fn test_duty_cycle() {
+        let mut pulse = playing(false);
+        let mut wave = vec![];
+        for _ in 0..8 {
+            wave.push(pulse.output());
+            for _ in 0..=0x100 {
+                pulse.clock_timer();
+            }
+        }
+        assert_eq!(wave, [0, 10, 10, 10, 10, 0, 0, 0]);
+    }
+
+    #[test]
+    fn test_sweep_negate_differs() {
+        // shift 1 down: $100 - $80, and one less again on pulse 1
+        for (ones_complement, period) in [(true, 0x7F), (false, 0x80)] {
+            let mut pulse = playing(ones_complement);
+            pulse.write(1, 0b1000_1001);
+            pulse.clock_sweep();
+            assert_eq!(pulse.period, period);
+        }
+    }
+
+    #[test]
+    fn test_sweep_mutes() {
+        let mut pulse = playing(false);
+        pulse.write(2, 0xFF);
+        pulse.write(3, 0b0000_1111);
+        // going up by half would pass $7FF, even with the sweep disabled
+        pulse.write(1, 0b0000_0001);
+        assert_eq!(pulse.output(), 0);
+        // going down never does
+        pulse.write(1, 0b0000_1001);
+        pulse.clock_timer();
+        assert_eq!(pulse.output(), 10);
+
+        pulse.write(2, 0x07);
+        pulse.write(3, 0b0000_1000);
+        assert_eq!(pulse.output(), 0);
+    }
+}
@@ -0,0 +1,128 @@
+use super::units::LengthCounter;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+#[rustfmt::skip]
+const SEQUENCE: [u8; 32] = [
+    15, 14, 13, 12, 11, 10, 9, 8, 7, 6, 5, 4, 3, 2, 1, 0,
+    0, 1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12, 13, 14, 15,
+];
+
+// A 32-step triangle with no volume control. Its timer runs at the CPU's
+// rate, and besides the length counter a linear counter, reloaded every
+// quarter frame while the control bit is set, can stop it mid-note.
+#[derive(Default)]
+pub struct Triangle {
+    step: u8,
+    period: u16,
+    timer: u16,
+    pub length: LengthCounter,
+    // bit 7 of $4008, which is also the length counter's halt
+    control: bool,
+    linear_reload_value: u8,
+    linear_counter: u8,
+    linear_reload: bool,
+}
+
+impl Triangle {
+    // `reg` is 0-3 for $4008-$400B, $4009 does nothing
+    pub fn write(&mut self, reg: u16, data: u8) {
+        match reg {
+            0 => {
+                self.control = data & 0b1000_0000 != 0;
+                self.length.halt = self.control;
+                self.linear_reload_value = data & 0b0111_1111;
+            }
+            1 => {}
+            2 => self.period = self.period & 0x700 | data as u16,
+            _ => {
+                self.period = self.period & 0xFF | ((data & 0b111) as u16) << 8;
+                self.length.load(data);
+                self.linear_reload = true;
+            }
+        }
+    }
+
+    // every CPU cycle
+    pub fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            // the hardware would keep going at periods under 2, but that's
+            // far above hearing and just pops, so it holds where it is
+            if self.length.active() && self.linear_counter > 0 && self.period >= 2 {
+                self.step = (self.step + 1) % 32;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub fn clock_linear(&mut self) {
+        if self.linear_reload {
+            self.linear_counter = self.linear_reload_value;
+        } else if self.linear_counter > 0 {
+            self.linear_counter -= 1;
+        }
+        if !self.control {
+            self.linear_reload = false;
+        }
+    }
+
+    // 0-15; a stopped triangle holds its level rather than going quiet
+    pub fn output(&self) -> u8 {
+        SEQUENCE[self.step as usize]
+    }
+}
+
+impl Snapshot for Triangle {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.step);
+        w.write_u16(self.period);
+        w.write_u16(self.timer);
+        self.length.snapshot(w);
+        w.write_u8(self.control as u8);
+        w.write_u8(self.linear_reload_value);
+        w.write_u8(self.linear_counter);
+        w.write_u8(self.linear_reload as u8);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.step = r.read_u8()? % 32;
+        self.period = r.read_u16()? & 0x7FF;
+        self.timer = r.read_u16()?;
+        self.length.restore(r)?;
+        self.control = r.read_u8()? != 0;
+        self.linear_reload_value = r.read_u8()?;
+        self.linear_counter = r.read_u8()?;
+        self.linear_reload = r.read_u8()? != 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_linear_counter() {
+        let mut triangle = Triangle::default();
+        triangle.length.set_enabled(true);
+        // linear counter of 2, period 2
+        triangle.write(0, 0x02);
+        triangle.write(2, 0x02);
+        triangle.write(3, 0x08);
+        let run = |triangle: &mut Triangle| {
+            for _ in 0..3 {
+                triangle.clock_timer();
+            }
+            triangle.output()
+        };
+        // nothing moves until the first quarter frame loads the counter
+        assert_eq!(run(&mut triangle), 15);
+        triangle.clock_linear();
+        assert_eq!(run(&mut triangle), 14);
+        assert_eq!(run(&mut triangle), 13);
+        triangle.clock_linear();
+        triangle.clock_linear();
+        assert_eq!(run(&mut triangle), 13);
+    }
+}
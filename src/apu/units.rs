@@ -0,0 +1,171 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// what the top 5 bits of $4003/$4007/$400B/$400F load the length counter with
+#[rustfmt::skip]
+const LENGTHS: [u8; 32] = [
+    10, 254, 20, 2, 40, 4, 80, 6, 160, 8, 60, 10, 14, 12, 26, 14,
+    12, 16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+// Silences a channel once a note has played for its length, counted down
+// every half frame unless halted. Only loads while the channel is enabled.
+#[derive(Default)]
+pub struct LengthCounter {
+    enabled: bool,
+    pub halt: bool,
+    counter: u8,
+}
+
+impl LengthCounter {
+    pub fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.counter = 0;
+        }
+    }
+
+    pub fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.counter = LENGTHS[(index >> 3) as usize];
+        }
+    }
+
+    pub fn clock(&mut self) {
+        if !self.halt && self.counter > 0 {
+            self.counter -= 1;
+        }
+    }
+
+    pub fn active(&self) -> bool {
+        self.counter > 0
+    }
+}
+
+impl Snapshot for LengthCounter {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.enabled as u8);
+        w.write_u8(self.halt as u8);
+        w.write_u8(self.counter);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = r.read_u8()? != 0;
+        self.halt = r.read_u8()? != 0;
+        self.counter = r.read_u8()?;
+        Ok(())
+    }
+}
+
+// Volume for the pulse and noise channels: either the register's value as
+// it is, or a decay from 15 at a quarter-frame divider set by that value,
+// optionally looping.
+#[derive(Default)]
+pub struct Envelope {
+    pub start: bool,
+    looping: bool,
+    constant: bool,
+    // the constant volume, or the decay's period
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    // bits 0-5 of $4000/$4004/$400C, bit 5 doubles as the length counter halt
+    pub fn write(&mut self, data: u8) {
+        self.looping = data & 0b0010_0000 != 0;
+        self.constant = data & 0b0001_0000 != 0;
+        self.volume = data & 0b0000_1111;
+    }
+
+    pub fn clock(&mut self) {
+        if self.start {
+            self.start = false;
+            self.decay = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub fn volume(&self) -> u8 {
+        match self.constant {
+            true => self.volume,
+            false => self.decay,
+        }
+    }
+}
+
+impl Snapshot for Envelope {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.start as u8);
+        w.write_u8(self.looping as u8);
+        w.write_u8(self.constant as u8);
+        w.write_u8(self.volume);
+        w.write_u8(self.divider);
+        w.write_u8(self.decay);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.start = r.read_u8()? != 0;
+        self.looping = r.read_u8()? != 0;
+        self.constant = r.read_u8()? != 0;
+        self.volume = r.read_u8()?;
+        self.divider = r.read_u8()?;
+        self.decay = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_length_counter() {
+        let mut length = LengthCounter::default();
+        // index 3 is a length of 2
+        length.load(0b0001_1000);
+        assert!(!length.active());
+
+        length.set_enabled(true);
+        length.load(0b0001_1000);
+        length.clock();
+        assert!(length.active());
+        length.clock();
+        assert!(!length.active());
+
+        length.load(0);
+        length.set_enabled(false);
+        assert!(!length.active());
+    }
+
+    #[test]
+    fn test_envelope_decay() {
+        let mut envelope = Envelope::default();
+        // a period of 1 steps down every other clock
+        envelope.write(0x01);
+        envelope.start = true;
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+        for _ in 0..30 {
+            envelope.clock();
+        }
+        assert_eq!(envelope.volume(), 0);
+
+        envelope.write(0x21);
+        envelope.clock();
+        envelope.clock();
+        assert_eq!(envelope.volume(), 15);
+
+        envelope.write(0x17);
+        assert_eq!(envelope.volume(), 7);
+    }
+}
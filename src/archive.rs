@@ -0,0 +1,257 @@
+use flate2::read::GzDecoder;
+use std::fmt;
+use std::io::{self, Cursor, Read};
+use std::path::Path;
+
+// file types we know how to boot, by extension
+const ROM_EXTENSIONS: [&str; 5] = ["nes", "unf", "unif", "fds", "nsf"];
+// nothing legitimate comes close, this just stops zip bombs
+const MAX_ROM_SIZE: u64 = 32 * 1024 * 1024;
+
+const ZIP_MAGIC: &[u8] = b"PK\x03\x04";
+const GZIP_MAGIC: &[u8] = b"\x1f\x8b";
+const SEVEN_ZIP_MAGIC: &[u8] = b"7z\xbc\xaf\x27\x1c";
+const RAR_MAGIC: &[u8] = b"Rar!\x1a\x07";
+
+#[derive(Debug)]
+pub enum ArchiveError {
+    Io(io::Error),
+    Zip(zip::result::ZipError),
+    Unsupported(&'static str),
+    NoRom,
+    MultipleRoms(Vec<String>),
+    EntryNotFound(String),
+    TooLarge(String),
+}
+
+impl fmt::Display for ArchiveError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ArchiveError::Io(err) => write!(f, "{}", err),
+            ArchiveError::Zip(err) => write!(f, "bad zip archive: {}", err),
+            ArchiveError::Unsupported(kind) => write!(f, "{} archives are not supported", kind),
+            ArchiveError::NoRom => write!(
+                f,
+                "archive has no {} files",
+                ROM_EXTENSIONS.map(|ext| format!(".{}", ext)).join("/")
+            ),
+            ArchiveError::MultipleRoms(names) => write!(
+                f,
+                "archive has several ROMs, pick one with --entry: {}",
+                names.join(", ")
+            ),
+            ArchiveError::EntryNotFound(name) => write!(f, "archive has no entry {:?}", name),
+            ArchiveError::TooLarge(name) => {
+                write!(f, "{} is larger than {} bytes", name, MAX_ROM_SIZE)
+            }
+        }
+    }
+}
+
+impl std::error::Error for ArchiveError {}
+
+impl From<io::Error> for ArchiveError {
+    fn from(err: io::Error) -> Self {
+        ArchiveError::Io(err)
+    }
+}
+
+impl From<zip::result::ZipError> for ArchiveError {
+    fn from(err: zip::result::ZipError) -> Self {
+        ArchiveError::Zip(err)
+    }
+}
+
+pub struct LoadedFile {
+    // name of the file inside the archive, or the file itself
+    pub name: String,
+    pub data: Vec<u8>,
+}
+
+// reads a ROM from disk, unpacking it first if it's a zip or gzip file
+pub fn load(path: &Path, entry: Option<&str>) -> Result<LoadedFile, ArchiveError> {
+    let data = std::fs::read(path)?;
+    let name = path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    load_bytes(&name, data, entry)
+}
+
+pub fn load_bytes(
+    name: &str,
+    data: Vec<u8>,
+    entry: Option<&str>,
+) -> Result<LoadedFile, ArchiveError> {
+    if data.starts_with(ZIP_MAGIC) {
+        read_zip(data, entry)
+    } else if data.starts_with(GZIP_MAGIC) {
+        read_gzip(name, data)
+    } else if data.starts_with(SEVEN_ZIP_MAGIC) {
+        Err(ArchiveError::Unsupported("7z"))
+    } else if data.starts_with(RAR_MAGIC) {
+        Err(ArchiveError::Unsupported("RAR"))
+    } else {
+        Ok(LoadedFile {
+            name: name.to_string(),
+            data,
+        })
+    }
+}
+
+fn is_rom_name(name: &str) -> bool {
+    Path::new(name)
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_ascii_lowercase())
+        .is_some_and(|ext| ROM_EXTENSIONS.contains(&ext.as_str()))
+}
+
+// an entry matches either by its full path in the archive or its file name
+fn entry_matches(entry_name: &str, wanted: &str) -> bool {
+    entry_name == wanted || Path::new(entry_name).file_name() == Some(wanted.as_ref())
+}
+
+fn read_limited(reader: impl Read, name: &str) -> Result<Vec<u8>, ArchiveError> {
+    let mut data = vec![];
+    reader.take(MAX_ROM_SIZE + 1).read_to_end(&mut data)?;
+    if data.len() as u64 > MAX_ROM_SIZE {
+        return Err(ArchiveError::TooLarge(name.to_string()));
+    }
+    Ok(data)
+}
+
+fn read_zip(data: Vec<u8>, entry: Option<&str>) -> Result<LoadedFile, ArchiveError> {
+    let mut zip = zip::ZipArchive::new(Cursor::new(data))?;
+    let names: Vec<String> = zip
+        .file_names()
+        .filter(|name| !name.ends_with('/'))
+        .map(String::from)
+        .collect();
+
+    let name = match entry {
+        Some(wanted) => names
+            .iter()
+            .find(|name| entry_matches(name, wanted))
+            .ok_or_else(|| ArchiveError::EntryNotFound(wanted.to_string()))?
+            .clone(),
+        None => {
+            let mut roms: Vec<String> = names.into_iter().filter(|n| is_rom_name(n)).collect();
+            roms.sort();
+            match roms.len() {
+                0 => return Err(ArchiveError::NoRom),
+                1 => roms.remove(0),
+                _ => return Err(ArchiveError::MultipleRoms(roms)),
+            }
+        }
+    };
+
+    let file = zip.by_name(&name)?;
+    let data = read_limited(file, &name)?;
+    Ok(LoadedFile { name, data })
+}
+
+fn read_gzip(name: &str, data: Vec<u8>) -> Result<LoadedFile, ArchiveError> {
+    let mut decoder = GzDecoder::new(&data[..]);
+    // gzip headers may carry the original name, otherwise drop the .gz
+    let inner_name = decoder
+        .header()
+        .and_then(|header| header.filename())
+        .map(|name| String::from_utf8_lossy(name).into_owned())
+        .unwrap_or_else(|| {
+            let lower = name.to_ascii_lowercase();
+            match lower.strip_suffix(".gz") {
+                Some(stem) => name[..stem.len()].to_string(),
+                None => name.to_string(),
+            }
+        });
+    let data = read_limited(&mut decoder, &inner_name)?;
+    Ok(LoadedFile {
+        name: inner_name,
+        data,
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use flate2::write::GzEncoder;
+    use flate2::Compression;
+    use std::io::Write;
+    use zip::write::FileOptions;
+
+    fn make_zip(files: &[(&str, &[u8])]) -> Vec<u8> {
+        let mut zip = zip::ZipWriter::new(Cursor::new(vec![]));
+        for (name, data) in files {
+            zip.start_file(*name, FileOptions::default()).unwrap();
+            zip.write_all(data).unwrap();
+        }
+        zip.finish().unwrap().into_inner()
+    }
+
+    #[test]
+    fn test_plain_file() {
+        let file = load_bytes("game.nes", b"NES\x1a".to_vec(), None).unwrap();
+        assert_eq!(file.name, "game.nes");
+        assert_eq!(file.data, b"NES\x1a");
+    }
+
+    #[test]
+    fn test_zip_picks_the_rom() {
+        let zip = make_zip(&[("readme.txt", b"hello"), ("Game (U).nes", b"rom")]);
+        let file = load_bytes("game.zip", zip, None).unwrap();
+        assert_eq!(file.name, "Game (U).nes");
+        assert_eq!(file.data, b"rom");
+    }
+
+    #[test]
+    fn test_zip_multiple_roms() {
+        let zip = make_zip(&[("b.nes", b"b"), ("a.NES", b"a")]);
+        match load_bytes("set.zip", zip.clone(), None) {
+            Err(ArchiveError::MultipleRoms(names)) => assert_eq!(names, vec!["a.NES", "b.nes"]),
+            _ => panic!("expected MultipleRoms"),
+        }
+
+        let file = load_bytes("set.zip", zip.clone(), Some("b.nes")).unwrap();
+        assert_eq!(file.data, b"b");
+        assert!(matches!(
+            load_bytes("set.zip", zip, Some("c.nes")),
+            Err(ArchiveError::EntryNotFound(_))
+        ));
+    }
+
+    #[test]
+    fn test_zip_entry_by_file_name() {
+        let zip = make_zip(&[("roms/usa/game.nes", b"rom")]);
+        let file = load_bytes("set.zip", zip, Some("game.nes")).unwrap();
+        assert_eq!(file.name, "roms/usa/game.nes");
+    }
+
+    #[test]
+    fn test_zip_without_rom() {
+        let zip = make_zip(&[("readme.txt", b"hello")]);
+        assert!(matches!(
+            load_bytes("x.zip", zip, None),
+            Err(ArchiveError::NoRom)
+        ));
+    }
+
+    #[test]
+    fn test_gzip() {
+        let mut encoder = GzEncoder::new(vec![], Compression::default());
+        encoder.write_all(b"rom").unwrap();
+        let gz = encoder.finish().unwrap();
+
+        let file = load_bytes("Game.nes.GZ", gz, None).unwrap();
+        assert_eq!(file.name, "Game.nes");
+        assert_eq!(file.data, b"rom");
+    }
+
+    #[test]
+    fn test_7z_is_rejected() {
+        let data = [SEVEN_ZIP_MAGIC, b"junk"].concat();
+        assert!(matches!(
+            load_bytes("x.7z", data, None),
+            Err(ArchiveError::Unsupported("7z"))
+        ));
+    }
+}
@@ -0,0 +1,226 @@
+use std::sync::{Arc, Mutex};
+
+// how far rate control may bend the pitch, half a percent is too little to hear
+pub const MAX_RATE_DELTA: f64 = 0.005;
+pub const VOLUME_STEP: f32 = 0.1;
+
+// Samples waiting for the sound card, oldest first
+pub struct RingBuffer {
+    samples: Vec<f32>,
+    start: usize,
+    len: usize,
+    // what an underrun repeats, so running dry is a gap rather than a click
+    last: f32,
+}
+
+impl RingBuffer {
+    pub fn new(capacity: usize) -> Self {
+        RingBuffer {
+            samples: vec![0.0; capacity.max(1)],
+            start: 0,
+            len: 0,
+            last: 0.0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.samples.len()
+    }
+
+    // anything past capacity is dropped, returns how many fitted
+    pub fn push(&mut self, samples: &[f32]) -> usize {
+        let count = samples.len().min(self.capacity() - self.len);
+        for &sample in &samples[..count] {
+            let end = (self.start + self.len) % self.capacity();
+            self.samples[end] = sample;
+            self.len += 1;
+        }
+        count
+    }
+
+    // fills `out`, holding the last sample if it runs out; returns how many
+    // real samples there were
+    pub fn pop_into(&mut self, out: &mut [f32]) -> usize {
+        let count = out.len().min(self.len);
+        for slot in out[..count].iter_mut() {
+            *slot = self.samples[self.start];
+            self.start = (self.start + 1) % self.capacity();
+        }
+        self.len -= count;
+        if count > 0 {
+            self.last = out[count - 1];
+        }
+        out[count..].fill(self.last);
+        count
+    }
+}
+
+// Linear interpolation at a ratio that can change between batches
+#[derive(Default)]
+struct Resampler {
+    // how far past `last` the next output sample is, in input samples
+    position: f64,
+    last: f32,
+}
+
+impl Resampler {
+    // `ratio` is output samples per input sample
+    fn process(&mut self, input: &[f32], ratio: f64, out: &mut Vec<f32>) {
+        let step = 1.0 / ratio;
+        for &sample in input {
+            while self.position < 1.0 {
+                let t = self.position as f32;
+                out.push(self.last + (sample - self.last) * t);
+                self.position += step;
+            }
+            self.position -= 1.0;
+            self.last = sample;
+        }
+    }
+}
+
+// Gets the emulator's samples to a sound card that runs on its own clock.
+// The two never agree exactly, so rather than let the buffer run dry and
+// crackle or fill up and lag, every batch is resampled a little faster or
+// slower depending on how full the buffer is, which keeps it near half.
+pub struct AudioQueue {
+    buffer: Arc<Mutex<RingBuffer>>,
+    resampler: Resampler,
+    volume: f32,
+    muted: bool,
+}
+
+impl AudioQueue {
+    // `capacity` in samples; it starts half full of silence
+    pub fn new(capacity: usize) -> Self {
+        let mut buffer = RingBuffer::new(capacity);
+        buffer.push(&vec![0.0; capacity / 2]);
+        AudioQueue {
+            buffer: Arc::new(Mutex::new(buffer)),
+            resampler: Resampler::default(),
+            volume: 1.0,
+            muted: false,
+        }
+    }
+
+    // for the sound card's side
+    pub fn buffer(&self) -> Arc<Mutex<RingBuffer>> {
+        Arc::clone(&self.buffer)
+    }
+
+    // output samples per input sample for a buffer this full (0.0-1.0)
+    pub fn rate_ratio(fill: f64) -> f64 {
+        1.0 + MAX_RATE_DELTA * (1.0 - 2.0 * fill.clamp(0.0, 1.0))
+    }
+
+    pub fn push(&mut self, samples: &[f32]) {
+        let mut buffer = self.buffer.lock().unwrap();
+        let fill = buffer.len() as f64 / buffer.capacity() as f64;
+        let gain = if self.muted { 0.0 } else { self.volume };
+        let mut out = Vec::with_capacity(samples.len() + samples.len() / 100 + 2);
+        self.resampler
+            .process(samples, AudioQueue::rate_ratio(fill), &mut out);
+        out.iter_mut().for_each(|sample| *sample *= gain);
+        buffer.push(&out);
+    }
+
+    pub fn volume(&self) -> f32 {
+        self.volume
+    }
+
+    // 0.0-1.0
+    pub fn set_volume(&mut self, volume: f32) {
+        self.volume = volume.clamp(0.0, 1.0);
+    }
+
+    pub fn is_muted(&self) -> bool {
+        self.muted
+    }
+
+    pub fn set_muted(&mut self, muted: bool) {
+        self.muted = muted;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ring_buffer() {
+        let mut buffer = RingBuffer::new(4);
+        assert_eq!(buffer.push(&[1.0, 2.0, 3.0]), 3);
+        let mut out = [0.0; 2];
+        assert_eq!(buffer.pop_into(&mut out), 2);
+        assert_eq!(out, [1.0, 2.0]);
+        // wraps round the end, and drops what doesn't fit
+        assert_eq!(buffer.push(&[4.0, 5.0, 6.0, 7.0]), 3);
+        let mut out = [0.0; 6];
+        assert_eq!(buffer.pop_into(&mut out), 4);
+        assert_eq!(out, [3.0, 4.0, 5.0, 6.0, 6.0, 6.0]);
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn test_resampler_ratio() {
+        let mut resampler = Resampler::default();
+        let input: Vec<f32> = (0..1000).map(|i| i as f32).collect();
+        let mut out = vec![];
+        resampler.process(&input, 1.005, &mut out);
+        assert!((1004..=1006).contains(&out.len()));
+        // a ramp stays a ramp
+        assert!(out.windows(2).all(|w| w[1] >= w[0]));
+        assert!(out[out.len() - 1] > 990.0);
+    }
+
+    // where the buffer ends up when the emulator makes `made` samples for
+    // every 480 the sound card plays
+    fn settled_fill(made: usize) -> usize {
+        let mut queue = AudioQueue::new(4800);
+        let buffer = queue.buffer();
+        let mut out = vec![0.0; 480];
+        for _ in 0..10000 {
+            queue.push(&vec![0.0; made]);
+            buffer.lock().unwrap().pop_into(&mut out);
+        }
+        let len = buffer.lock().unwrap().len();
+        len
+    }
+
+    #[test]
+    fn test_rate_control_settles() {
+        // 0.2% either way would drift 20000 samples over this run, enough to
+        // overflow or run dry many times over; instead it settles at the
+        // fill where the bent rate makes up the difference
+        assert!((3200..3600).contains(&settled_fill(481)));
+        assert!((1200..1600).contains(&settled_fill(479)));
+        assert!((2300..2500).contains(&settled_fill(480)));
+    }
+
+    #[test]
+    fn test_volume_and_mute() {
+        let mut queue = AudioQueue::new(100);
+        let buffer = queue.buffer();
+        let mut out = vec![0.0; 50];
+        buffer.lock().unwrap().pop_into(&mut out);
+        queue.set_volume(0.5);
+        queue.push(&[0.8; 20]);
+        let mut out = vec![0.0; 10];
+        buffer.lock().unwrap().pop_into(&mut out);
+        assert!((out[9] - 0.4).abs() < 0.001);
+
+        queue.set_muted(true);
+        queue.push(&[0.8; 20]);
+        let mut out = vec![0.0; 40];
+        buffer.lock().unwrap().pop_into(&mut out);
+        assert_eq!(out[39], 0.0);
+    }
+}
@@ -0,0 +1,142 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// keeps battery-backed PRG RAM in a .sav file next to the ROM
+pub struct BatterySave {
+    path: PathBuf,
+    interval: Option<Duration>,
+    last_write: Instant,
+    saved: Vec<u8>,
+}
+
+impl BatterySave {
+    // with no interval the save is only written by flush()
+    pub fn new(rom_path: &Path, interval: Option<Duration>) -> Self {
+        BatterySave {
+            path: rom_path.with_extension("sav"),
+            interval,
+            last_write: Instant::now(),
+            saved: vec![],
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    // fills prg_ram from the .sav file, returns false if there wasn't one
+    pub fn load(&mut self, prg_ram: &mut [u8]) -> io::Result<bool> {
+        // with no file, or one that can't be read, the RAM as it starts out
+        // is what's on disk; nothing gets written until the game changes it
+        self.saved = prg_ram.to_vec();
+        let data = match fs::read(&self.path) {
+            Ok(data) => data,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(false),
+            Err(err) => return Err(err),
+        };
+        if data.len() != prg_ram.len() {
+            eprintln!(
+                "warning: {} is {} bytes, expected {}",
+                self.path.display(),
+                data.len(),
+                prg_ram.len()
+            );
+        }
+        let len = data.len().min(prg_ram.len());
+        prg_ram[..len].copy_from_slice(&data[..len]);
+        self.saved = prg_ram.to_vec();
+        Ok(true)
+    }
+
+    // writes the save once per interval, and only if something changed
+    pub fn tick(&mut self, prg_ram: &[u8]) -> io::Result<()> {
+        match self.interval {
+            Some(interval) if self.last_write.elapsed() >= interval => {}
+            _ => return Ok(()),
+        }
+        self.last_write = Instant::now();
+        self.flush(prg_ram)
+    }
+
+    pub fn flush(&mut self, prg_ram: &[u8]) -> io::Result<()> {
+        if self.saved == prg_ram {
+            return Ok(());
+        }
+        write_atomic(&self.path, prg_ram)?;
+        self.saved = prg_ram.to_vec();
+        Ok(())
+    }
+}
+
+// write to a temp file in the same directory and rename it over the target,
+// so a crash mid-write leaves the old file intact
+pub fn write_atomic(path: &Path, data: &[u8]) -> io::Result<()> {
+    let mut tmp_name = path.file_name().unwrap_or_default().to_os_string();
+    tmp_name.push(".tmp");
+    let tmp_path = path.with_file_name(tmp_name);
+
+    let mut file = fs::File::create(&tmp_path)?;
+    file.write_all(data)?;
+    file.sync_all()?;
+    drop(file);
+    fs::rename(&tmp_path, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_rom_path(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("nes_emu_battery_{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        dir.join(name)
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let rom_path = temp_rom_path("zelda.nes");
+        let mut save = BatterySave::new(&rom_path, Some(Duration::ZERO));
+        assert_eq!(save.path(), rom_path.with_extension("sav"));
+
+        let mut prg_ram = [0u8; 0x2000];
+        assert!(!save.load(&mut prg_ram).unwrap());
+
+        prg_ram[0x10] = 0xAA;
+        save.tick(&prg_ram).unwrap();
+        assert_eq!(fs::read(save.path()).unwrap()[0x10], 0xAA);
+
+        let mut restored = [0u8; 0x2000];
+        let mut save = BatterySave::new(&rom_path, Some(Duration::ZERO));
+        assert!(save.load(&mut restored).unwrap());
+        assert_eq!(restored, prg_ram);
+        fs::remove_file(save.path()).unwrap();
+    }
+
+    #[test]
+    fn test_unchanged_ram_is_not_written() {
+        let rom_path = temp_rom_path("metroid.nes");
+        let mut save = BatterySave::new(&rom_path, Some(Duration::ZERO));
+        let mut prg_ram = [0u8; 0x2000];
+        assert!(!save.load(&mut prg_ram).unwrap());
+        save.tick(&prg_ram).unwrap();
+        save.flush(&prg_ram).unwrap();
+        assert!(!save.path().exists());
+
+        prg_ram[0] = 1;
+        save.flush(&prg_ram).unwrap();
+        assert!(save.path().exists());
+        fs::remove_file(save.path()).unwrap();
+    }
+
+    #[test]
+    fn test_write_atomic_replaces_file() {
+        let path = temp_rom_path("atomic.sav");
+        write_atomic(&path, b"old").unwrap();
+        write_atomic(&path, b"new").unwrap();
+        assert_eq!(fs::read(&path).unwrap(), b"new");
+        assert!(!path.with_file_name("atomic.sav.tmp").exists());
+        fs::remove_file(&path).unwrap();
+    }
+}
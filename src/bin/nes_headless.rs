@@ -0,0 +1,386 @@
+// Runs a ROM with no window, sound or input, then dumps what it ended up
+// with. Meant for regression testing ROMs on machines without a display.
+// NSF rips are rendered to a WAV file instead.
+
+use nes_emu::config::{self, Config};
+use nes_emu::render::{self, Image, NtscFilter, NtscPreset, Palette, Scale, Scaler};
+use nes_emu::{frontend, nsf, Console};
+use std::path::PathBuf;
+
+// a memory location compared after every instruction
+struct Condition {
+    addr: u16,
+    value: u8,
+    equal: bool,
+}
+
+impl Condition {
+    // "6000=80" or "6000!=80", hex with an optional $ or 0x
+    fn parse(text: &str) -> Option<Condition> {
+        let (addr, value, equal) = match text.split_once("!=") {
+            Some((addr, value)) => (addr, value, false),
+            None => {
+                let (addr, value) = text.split_once('=')?;
+                (addr, value, true)
+            }
+        };
+        Some(Condition {
+            addr: u16::from_str_radix(strip_hex_prefix(addr), 16).ok()?,
+            value: u8::from_str_radix(strip_hex_prefix(value), 16).ok()?,
+            equal,
+        })
+    }
+
+    // peeks, so watching a register doesn't disturb the run
+    fn is_met(&self, console: &Console) -> bool {
+        let value = console.bus().peek(self.addr);
+        (value == self.value) == self.equal
+    }
+}
+
+fn strip_hex_prefix(text: &str) -> &str {
+    let text = text.trim();
+    text.strip_prefix('$')
+        .or_else(|| text.strip_prefix("0x"))
+        .unwrap_or(text)
+}
+
+struct Options {
+    // what's loaded and how, the same as the windowed frontend
+    config: Config,
+    palette: String,
+    ntsc: Option<NtscPreset>,
+    scaler: Option<Scaler>,
+    scale: usize,
+    aspect: bool,
+    frames: Option<u64>,
+    cycles: Option<u64>,
+    until: Option<Condition>,
+    frame_out: Option<PathBuf>,
+    ram_out: Option<PathBuf>,
+    prg_ram_out: Option<PathBuf>,
+    registers_out: Option<PathBuf>,
+}
+
+// the options about running and dumping are handled here, the rest (the
+// rom, --entry, --patch, --bios, --region and so on) go to config::parse
+fn parse_args(mut args: impl Iterator<Item = String>) -> Options {
+    let mut rest = vec![];
+    let mut palette = String::from("default");
+    let mut ntsc = None;
+    let mut scaler = None;
+    let mut scale = 1;
+    let mut aspect = false;
+    let mut frames = None;
+    let mut cycles = None;
+    let mut until = None;
+    let mut frame_out = None;
+    let mut ram_out = None;
+    let mut prg_ram_out = None;
+    let mut registers_out = None;
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| {
+            args.next()
+                .unwrap_or_else(|| usage(&format!("{} needs {}", arg, what)))
+        };
+        match arg.as_str() {
+            "--palette" => palette = value("a name or a .pal file"),
+            "--ntsc" => {
+                let name = value("composite, svideo or rgb");
+                ntsc = Some(
+                    NtscPreset::from_name(&name)
+                        .unwrap_or_else(|| usage(&format!("unknown NTSC preset {}", name))),
+                );
+            }
+            "--scaler" => {
+                let name = value("a scaler");
+                scaler = Some(
+                    Scaler::from_name(&name)
+                        .unwrap_or_else(|| usage(&format!("unknown scaler {}", name))),
+                );
+            }
+            "--scale" => {
+                scale = value("a factor")
+                    .parse()
+                    .unwrap_or_else(|_| usage("--scale needs a whole number"));
+            }
+            "--aspect" => aspect = true,
+            "--frames" => {
+                frames = Some(
+                    value("a number of frames")
+                        .parse()
+                        .unwrap_or_else(|_| usage("--frames needs a number")),
+                );
+            }
+            "--cycles" => {
+                cycles = Some(
+                    value("a number of CPU cycles")
+                        .parse()
+                        .unwrap_or_else(|_| usage("--cycles needs a number")),
+                );
+            }
+            "--until" => {
+                let text = value("a condition like 6000!=80");
+                until = Some(
+                    Condition::parse(&text)
+                        .unwrap_or_else(|| usage(&format!("bad condition {}", text))),
+                );
+            }
+            "--frame-out" => frame_out = Some(value("a file name").into()),
+            "--ram-out" => ram_out = Some(value("a file name").into()),
+            "--prg-ram-out" => prg_ram_out = Some(value("a file name").into()),
+            "--registers-out" => registers_out = Some(value("a file name").into()),
+            _ => rest.push(arg),
+        }
+    }
+    let config = config::parse(rest.into_iter()).unwrap_or_else(|err| usage(&err));
+    Options {
+        config,
+        palette,
+        ntsc,
+        scaler,
+        scale,
+        aspect,
+        frames,
+        cycles,
+        until,
+        frame_out,
+        ram_out,
+        prg_ram_out,
+        registers_out,
+    }
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("error: {}", error);
+    eprintln!("usage: nes_headless [--frames N] [--cycles N] [--until ADDR=VALUE|ADDR!=VALUE]");
+    eprintln!("                    [--frame-out FILE.ppm] [--ram-out FILE] [--prg-ram-out FILE]");
+    eprintln!("                    [--registers-out FILE] [--entry NAME] [--patch FILE]");
+    eprintln!("                    [--bios FILE] [--region REGION] [--no-sprite-limit]");
+    eprintln!("                    [--palette NAME|FILE] [--ntsc PRESET] [--scaler SCALER]");
+    eprintln!("                    [--scale N] [--aspect] <rom>");
+    eprintln!("       nes_headless --wav FILE [--track N] [--length SECS] [--sample-rate HZ]");
+    eprintln!("                    [--region REGION] <nsf>");
+    eprintln!("scalers: {}", render::scale::SCALERS.join(", "));
+    std::process::exit(2);
+}
+
+fn fail(message: String) -> ! {
+    eprintln!("{}", message);
+    std::process::exit(1);
+}
+
+// there's no sound card to play to, so a rip is only ever written out
+fn render_nsf(config: &Config, data: &[u8]) -> Result<(), String> {
+    if config.wav.is_none() {
+        return Err("an NSF needs --wav to write the track to".to_string());
+    }
+    frontend::play_nsf(config, data, config.sample_rate, None)
+}
+
+// returns whether --until was met
+fn run(console: &mut Console, options: &Options) -> bool {
+    let frames_done = |console: &Console| options.frames.is_some_and(|n| console.frames() >= n);
+    let cycles_done = |console: &Console| options.cycles.is_some_and(|n| console.cycles() >= n);
+    while !console.is_halted() && !frames_done(console) && !cycles_done(console) {
+        console.step();
+        if let Some(condition) = &options.until {
+            if condition.is_met(console) {
+                return true;
+            }
+        }
+    }
+    false
+}
+
+fn registers(console: &Console) -> String {
+    let cpu = console.cpu();
+    format!(
+        "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}\nframes:{} cycles:{}\n",
+        cpu.program_counter,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status,
+        cpu.stack_pointer,
+        console.frames(),
+        console.cycles()
+    )
+}
+
+fn write(path: &Option<PathBuf>, data: &[u8]) {
+    if let Some(path) = path {
+        std::fs::write(path, data)
+            .unwrap_or_else(|err| fail(format!("failed to write {}: {}", path.display(), err)));
+    }
+}
+
+fn main() {
+    let options = parse_args(std::env::args().skip(1));
+    // the same path from file to console as the windowed frontend, patches
+    // and disk images included
+    let file = frontend::load_file(&options.config).unwrap_or_else(|err| fail(err));
+    if nsf::is_nsf(&file.data) {
+        if let Err(err) = render_nsf(&options.config, &file.data) {
+            fail(err);
+        }
+        return;
+    }
+    if options.frames.is_none() && options.cycles.is_none() && options.until.is_none() {
+        usage("give at least one of --frames, --cycles and --until");
+    }
+    let mut console =
+        frontend::load_console(&options.config, &file).unwrap_or_else(|err| fail(err));
+    let palette = Palette::load(&options.palette).unwrap_or_else(|err| {
+        fail(format!(
+            "failed to load palette {}: {}",
+            options.palette, err
+        ))
+    });
+
+    let met = run(&mut console, &options);
+
+    let registers = registers(&console);
+    print!("{}", registers);
+    if console.is_halted() {
+        println!("stopped on BRK");
+    }
+    let mut image = match options.ntsc {
+        Some(preset) => Image::new(
+            render::NTSC_WIDTH,
+            render::HEIGHT,
+            NtscFilter::new(preset).apply(console.frame()),
+        ),
+        None => Image::new(
+            render::WIDTH,
+            render::HEIGHT,
+            console.frame().to_rgb(&palette),
+        ),
+    };
+    // --scale or --aspect on their own mean plain integer scaling
+    if options.scaler.is_some() || options.scale > 1 || options.aspect {
+        let scale = Scale {
+            scaler: options.scaler.unwrap_or(Scaler::Integer),
+            factor: options.scale,
+            aspect: options.aspect,
+        };
+        image = scale.apply(&image);
+    }
+    write(&options.frame_out, &image.to_ppm());
+    write(&options.ram_out, &console.bus().cpu_vram);
+    write(&options.prg_ram_out, &console.bus().prg_ram);
+    write(&options.registers_out, registers.as_bytes());
+
+    // an unmet condition is a failed run, so CI can go by the exit status
+    if options.until.is_some() && !met {
+        eprintln!("condition not met");
+        std::process::exit(1);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use nes_emu::rom::Rom;
+
+    fn parse_str(args: &str) -> Options {
+        parse_args(args.split_whitespace().map(String::from))
+    }
+
+    // an NROM image with the program at $8000 and the reset vector on it
+    fn test_rom(program: &[u8]) -> Vec<u8> {
+        let mut raw = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00, 0x00];
+        raw.resize(16, 0);
+        let mut prg = vec![0xEA; 0x8000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x7FFC..0x7FFE].copy_from_slice(&0x8000u16.to_le_bytes());
+        raw.extend(prg);
+        raw.extend([0; 0x2000]);
+        raw
+    }
+
+    #[test]
+    fn test_condition() {
+        let condition = Condition::parse("$6000!=0x80").unwrap();
+        assert_eq!(condition.addr, 0x6000);
+        assert_eq!(condition.value, 0x80);
+        assert!(!condition.equal);
+        assert!(Condition::parse("6000").is_none());
+        assert!(Condition::parse("10000=1").is_none());
+    }
+
+    #[test]
+    fn test_load_finds_patch() {
+        let dir = std::env::temp_dir().join(format!("nes_headless_patch_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let rom_path = dir.join("game.nes");
+        std::fs::write(&rom_path, test_rom(&[0xEA])).unwrap();
+        // an IPS patch turning the first PRG byte into a BRK
+        let mut ips = b"PATCH".to_vec();
+        ips.extend([0x00, 0x00, 0x10, 0x00, 0x01, 0x00]);
+        ips.extend(b"EOF");
+        std::fs::write(dir.join("game.ips"), ips).unwrap();
+
+        let options = parse_str(&format!("--frames 1 {}", rom_path.display()));
+        let file = frontend::load_file(&options.config).unwrap();
+        let console = frontend::load_console(&options.config, &file).unwrap();
+        assert_eq!(console.bus().peek(0x8000), 0x00);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_nsf_to_wav() {
+        let dir = std::env::temp_dir().join(format!("nes_headless_nsf_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let nsf_path = dir.join("music.nsf");
+        let wav_path = dir.join("music.wav");
+        // one song, with INIT and PLAY both an RTS at $8000
+        let mut raw = b"NESM\x1A".to_vec();
+        raw.extend([1, 1, 1]);
+        raw.extend([0x00, 0x80, 0x00, 0x80, 0x00, 0x80]);
+        raw.extend([0; 96]);
+        raw.extend([0; 2]);
+        raw.extend([0; 8]);
+        raw.extend([0; 2]);
+        raw.extend([0; 6]);
+        raw.push(0x60);
+        std::fs::write(&nsf_path, raw).unwrap();
+
+        let options = parse_str(&format!(
+            "--wav {} --length 1 --sample-rate 8000 {}",
+            wav_path.display(),
+            nsf_path.display()
+        ));
+        let file = frontend::load_file(&options.config).unwrap();
+        assert!(nsf::is_nsf(&file.data));
+        render_nsf(&options.config, &file.data).unwrap();
+        let wav = std::fs::read(&wav_path).unwrap();
+        assert_eq!(&wav[0..4], b"RIFF");
+        assert_eq!(wav[24..28], 8000u32.to_le_bytes());
+        // a second of 16 bit samples, give or take a partial frame
+        let samples = (wav.len() - 44) / 2;
+        assert!((7800..=8000).contains(&samples), "{} samples", samples);
+
+        let options = parse_str(&nsf_path.display().to_string());
+        assert!(render_nsf(&options.config, &file.data).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_watching_status_leaves_vblank_alone() {
+        let rom = Rom::new(&test_rom(&[
+            0x2C, 0x02, 0x20, // BIT $2002
+            0x10, 0xFB, // BPL $8000
+            0xE6, 0x00, // INC $00
+            0x4C, 0x00, 0x80, // JMP $8000
+        ]))
+        .unwrap();
+        let mut console = Console::new(rom);
+        // never met, but read after every instruction
+        let options = parse_str("--frames 3 --until 2002=01 game.nes");
+        assert!(!run(&mut console, &options));
+        assert_eq!(console.frames(), 3);
+        // a frame ends as vblank starts, so the ROM has seen two of them
+        assert_eq!(console.bus().cpu_vram[0], 2);
+    }
+}
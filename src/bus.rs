@@ -1,5 +1,12 @@
+use crate::apu::{Apu, DEFAULT_SAMPLE_RATE};
 use crate::cpu::Mem;
+use crate::fds::FdsBoard;
+use crate::joypad::Joypad;
+use crate::nsf::NsfBoard;
+use crate::ppu::Ppu;
+use crate::region::Timing;
 use crate::rom::Rom;
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 
 //  _______________ $10000  _______________
 // | PRG-ROM       |       |               |
@@ -29,25 +36,177 @@ use crate::rom::Rom;
 // | Zero Page     |       |               |
 // |_______________| $0000 |_______________|
 
+// what sits in the cartridge slot
+pub enum Board {
+    Cartridge,
+    // NSF rips bankswitch in 4K pages and need the player's driver code
+    Nsf(NsfBoard),
+    // the Famicom Disk System's RAM adapter, with the BIOS as PRG ROM
+    Fds(FdsBoard),
+}
+
 pub struct Bus {
     pub cpu_vram: [u8; 2048],
+    pub prg_ram: [u8; 0x2000],
     pub rom: Rom,
+    pub board: Board,
+    pub ppu: Ppu,
+    pub apu: Apu,
+    pub joypad1: Joypad,
+    pub timing: Timing,
+    // CPU cycles run, DMA timing depends on whether it starts on an odd one
+    cycles: u64,
+    // cycles the CPU owes after an OAM or DMC DMA
+    dma_stall: usize,
 }
 
 impl Bus {
     pub fn new(rom: Rom) -> Self {
+        let timing = rom.region.timing();
         Self {
             cpu_vram: [0; 2048],
+            prg_ram: [0; 0x2000],
             rom,
+            board: Board::Cartridge,
+            ppu: Ppu::new(&timing),
+            apu: Apu::new(&timing, DEFAULT_SAMPLE_RATE),
+            joypad1: Joypad::new(),
+            timing,
+            cycles: 0,
+            dma_stall: 0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        // trainers were made for copiers that mapped them to $7000-$71FF
+        if let Some(trainer) = &self.rom.trainer {
+            let start = (TRAINER - PRG_RAM) as usize;
+            self.prg_ram[start..start + trainer.len()].copy_from_slice(trainer);
+        }
+    }
+
+    // runs everything clocked alongside the CPU
+    pub fn tick(&mut self, cycles: usize) {
+        self.cycles += cycles as u64;
+        self.ppu
+            .tick(cycles, &self.rom.chr, self.rom.screen_mirroring);
+        // a cycle at a time, so DMC fetches land on the cycle they're due
+        for _ in 0..cycles {
+            self.apu.tick(1);
+            if let Some(addr) = self.apu.dmc_dma_address() {
+                let data = self.mem_read(addr);
+                self.apu.dmc_fill(data);
+                self.dma_stall += DMC_DMA_STALL;
+            }
         }
+        if let Board::Fds(board) = &mut self.board {
+            board.tick(cycles);
+        }
+    }
+
+    // Copies a page of CPU memory to OAM, starting at the current OAM address.
+    // The CPU is halted for 513 cycles while it runs, 514 if it has to wait
+    // for an even cycle to start on.
+    fn oam_dma(&mut self, page: u8) {
+        let start = (page as u16) << 8;
+        for i in 0..256 {
+            let data = self.mem_read(start + i);
+            self.ppu.write_oam_data(data);
+        }
+        self.dma_stall += 513 + (self.cycles % 2) as usize;
+    }
+
+    pub fn take_dma_stall(&mut self) -> usize {
+        std::mem::take(&mut self.dma_stall)
+    }
+
+    pub fn poll_nmi(&mut self) -> bool {
+        self.ppu.poll_nmi()
     }
 
-    pub fn read_prg_rom(&self, mut addr: u16) -> u8 {
-        addr -= PRG_ROM;
-        if self.rom.prg_rom.len() == 0x4000 && addr >= 0x4000 {
-            addr = addr % 0x4000
+    pub fn irq(&self) -> bool {
+        let board_irq = match &self.board {
+            Board::Fds(board) => board.irq(),
+            _ => false,
         };
-        self.rom.prg_rom[addr as usize]
+        board_irq || self.apu.irq()
+    }
+
+    // what a read would return, without the side effects some registers
+    // have on being read; for tracing and debuggers
+    pub fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            RAM..=RAM_MIRRORS_END => self.cpu_vram[(addr & 0b0000_0111_1111_1111) as usize],
+            PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => match addr & 0b0010_0000_0000_0111 {
+                0x2002 => self.ppu.peek_status(),
+                0x2004 => self.ppu.read_oam_data(),
+                // $2007 would move the address along, the rest are write-only
+                _ => 0,
+            },
+            APU_STATUS => self.apu.peek_status(),
+            JOYPAD1 => self.joypad1.peek(),
+            EXPANSION..=EXPANSION_END => match &self.board {
+                Board::Nsf(board) => board.read_driver(addr).unwrap_or(0),
+                // the disk registers acknowledge IRQs when read
+                _ => 0,
+            },
+            PRG_RAM..=PRG_RAM_END => match &self.board {
+                Board::Fds(board) => board.read_ram(addr),
+                _ => self.prg_ram[(addr - PRG_RAM) as usize],
+            },
+            PRG_ROM..=PRG_ROM_END => self.read_prg_rom(addr),
+            _ => 0,
+        }
+    }
+
+    pub fn read_prg_rom(&self, addr: u16) -> u8 {
+        match &self.board {
+            Board::Nsf(board) => return board.read_prg(&self.rom.prg_rom, addr),
+            Board::Fds(board) if addr < FDS_BIOS => return board.read_ram(addr),
+            Board::Fds(_) => return self.rom.prg_rom[(addr - FDS_BIOS) as usize],
+            Board::Cartridge => {}
+        }
+        // anything smaller than the 32K window repeats to fill it, which
+        // covers 16K carts as well as the odd sizes UNIF and NES 2.0 allow
+        let prg = &self.rom.prg_rom;
+        prg[(addr - PRG_ROM) as usize % prg.len()]
+    }
+}
+
+impl Snapshot for Bus {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.cpu_vram);
+        w.write_bytes(&self.prg_ram);
+        self.rom.chr.snapshot(w);
+        self.ppu.snapshot(w);
+        self.apu.snapshot(w);
+        self.joypad1.snapshot(w);
+        w.write_u8((self.cycles % 2) as u8);
+        match &self.board {
+            Board::Cartridge => {}
+            Board::Nsf(board) => board.snapshot(w),
+            Board::Fds(board) => board.snapshot(w),
+        }
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_into("RAM", &mut self.cpu_vram)?;
+        r.read_into("PRG RAM", &mut self.prg_ram)?;
+        self.rom.chr.restore(r)?;
+        self.ppu.restore(r)?;
+        self.apu.restore(r)?;
+        self.joypad1.restore(r)?;
+        self.cycles = r.read_u8()? as u64;
+        match &mut self.board {
+            Board::Cartridge => {}
+            Board::Nsf(board) => board.restore(r)?,
+            Board::Fds(board) => {
+                board.restore(r)?;
+                self.rom.screen_mirroring = board.mirroring;
+            }
+        }
+        self.dma_stall = 0;
+        Ok(())
     }
 }
 
@@ -55,20 +214,53 @@ const RAM: u16 = 0x0000;
 const RAM_MIRRORS_END: u16 = 0x1FFF;
 const PPU_REGISTERS: u16 = 0x2000;
 const PPU_REGISTERS_MIRRORS_END: u16 = 0x3FFF;
+const APU_REGISTERS: u16 = 0x4000;
+const APU_REGISTERS_END: u16 = 0x4013;
+const OAM_DMA: u16 = 0x4014;
+// the usual case, a fetch that lands on a write or during OAM DMA takes less
+const DMC_DMA_STALL: usize = 4;
+const APU_STATUS: u16 = 0x4015;
+const JOYPAD1: u16 = 0x4016;
+const APU_FRAME_COUNTER: u16 = 0x4017;
+const EXPANSION: u16 = 0x4020;
+const EXPANSION_END: u16 = 0x5FFF;
+const PRG_RAM: u16 = 0x6000;
+const TRAINER: u16 = 0x7000;
+const PRG_RAM_END: u16 = 0x7FFF;
 const PRG_ROM: u16 = 0x8000;
 const PRG_ROM_END: u16 = 0xFFFF;
+const FDS_BIOS: u16 = 0xE000;
 
 impl Mem for Bus {
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
         match addr {
             RAM..=RAM_MIRRORS_END => {
                 let mirror_down_addr = addr & 0b0000_0111_1111_1111;
                 self.cpu_vram[mirror_down_addr as usize]
             }
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b0010_0000_0000_0111;
-                todo!("ppu not implemented yet!")
+                let mirror_down_addr = addr & 0b0010_0000_0000_0111;
+                match mirror_down_addr {
+                    0x2002 => self.ppu.read_status(),
+                    0x2004 => self.ppu.read_oam_data(),
+                    0x2007 => self.ppu.read_data(&self.rom.chr, self.rom.screen_mirroring),
+                    // write-only registers
+                    _ => 0,
+                }
             }
+            APU_STATUS => self.apu.read_status(),
+            JOYPAD1 => self.joypad1.read(),
+            // no second controller
+            APU_FRAME_COUNTER => 0,
+            EXPANSION..=EXPANSION_END => match &mut self.board {
+                Board::Nsf(board) => board.read_driver(addr).unwrap_or(0),
+                Board::Fds(board) => board.read_register(addr),
+                Board::Cartridge => 0,
+            },
+            PRG_RAM..=PRG_RAM_END => match &self.board {
+                Board::Fds(board) => board.read_ram(addr),
+                _ => self.prg_ram[(addr - PRG_RAM) as usize],
+            },
             PRG_ROM..=PRG_ROM_END => self.read_prg_rom(addr),
             _ => {
                 println!("ignoring mem read from address {:04X}", addr);
@@ -84,15 +276,167 @@ impl Mem for Bus {
                 self.cpu_vram[mirror_down_addr as usize] = data
             }
             PPU_REGISTERS..=PPU_REGISTERS_MIRRORS_END => {
-                let _mirror_down_addr = addr & 0b0010_0000_0000_0111;
-                todo!("ppu not implemented yet!")
+                let mirror_down_addr = addr & 0b0010_0000_0000_0111;
+                match mirror_down_addr {
+                    0x2000 => self.ppu.write_ctrl(data),
+                    0x2001 => self.ppu.write_mask(data),
+                    0x2003 => self.ppu.write_oam_addr(data),
+                    0x2004 => self.ppu.write_oam_data(data),
+                    0x2005 => self.ppu.write_scroll(data),
+                    0x2006 => self.ppu.write_addr(data),
+                    0x2007 => {
+                        self.ppu
+                            .write_data(&mut self.rom.chr, self.rom.screen_mirroring, data)
+                    }
+                    // $2002 is read-only
+                    _ => {}
+                }
             }
-            PRG_ROM..=PRG_ROM_END => {
-                panic!("attempted to write (u8) to prg rom address {:04X}", addr);
+            OAM_DMA => self.oam_dma(data),
+            JOYPAD1 => self.joypad1.write(data),
+            APU_REGISTERS..=APU_REGISTERS_END | APU_STATUS | APU_FRAME_COUNTER => {
+                self.apu.write_register(addr, data)
             }
+            PRG_RAM..=PRG_RAM_END => match &mut self.board {
+                Board::Fds(board) => board.write_ram(addr, data),
+                _ => self.prg_ram[(addr - PRG_RAM) as usize] = data,
+            },
+            EXPANSION..=EXPANSION_END => match &mut self.board {
+                Board::Nsf(board) => board.write_bank(addr, data),
+                Board::Fds(board) => {
+                    board.write_register(addr, data);
+                    self.rom.screen_mirroring = board.mirroring;
+                }
+                Board::Cartridge => {}
+            },
+            PRG_ROM..=PRG_ROM_END => match &mut self.board {
+                Board::Fds(board) if addr < FDS_BIOS => board.write_ram(addr, data),
+                // ROM can't change; on a cartridge this is a mapper's bank
+                // switch, and without mapper support there's nothing to switch
+                Board::Nsf(_) | Board::Fds(_) | Board::Cartridge => {}
+            },
             _ => {
                 println!("ignoring mem write to address {:04X}", addr);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::joypad;
+    use crate::rom::test::test_rom;
+
+    #[test]
+    fn test_prg_ram() {
+        let mut bus = Bus::new(test_rom());
+        bus.mem_write(0x6000, 0x12);
+        bus.mem_write(0x7FFF, 0x34);
+        assert_eq!(bus.mem_read(0x6000), 0x12);
+        assert_eq!(bus.mem_read(0x7FFF), 0x34);
+    }
+
+    #[test]
+    fn test_prg_rom_write_ignored() {
+        let mut rom = test_rom();
+        rom.mapper = 0;
+        let mut bus = Bus::new(rom);
+        bus.mem_write(0x8000, 0x55);
+        bus.mem_write(0xFFFF, 0x55);
+        assert_eq!(bus.mem_read(0x8000), 1);
+        assert_eq!(bus.mem_read(0xFFFF), 1);
+    }
+
+    #[test]
+    fn test_prg_rom_mirroring() {
+        let mut rom = test_rom();
+        rom.prg_rom = (0..0x2000).map(|i| (i >> 8) as u8).collect();
+        let mut bus = Bus::new(rom);
+        // 8K shows up four times
+        for base in [0x8000, 0xA000, 0xC000, 0xE000] {
+            assert_eq!(bus.mem_read(base), 0x00);
+            assert_eq!(bus.mem_read(base + 0x1FFF), 0x1F);
+        }
+    }
+
+    #[test]
+    fn test_trainer_loaded_on_reset() {
+        let mut rom = test_rom();
+        rom.trainer = Some((0..=255).chain(0..=255).collect());
+        let mut bus = Bus::new(rom);
+        bus.reset();
+        assert_eq!(bus.mem_read(0x6FFF), 0);
+        assert_eq!(bus.mem_read(0x7000), 0);
+        assert_eq!(bus.mem_read(0x70FF), 0xFF);
+        assert_eq!(bus.mem_read(0x71FF), 0xFF);
+        assert_eq!(bus.mem_read(0x7200), 0);
+    }
+
+    #[test]
+    fn test_ppu_register_mirrors() {
+        let mut bus = Bus::new(test_rom());
+        // $3FFE/$3FFF are $2006/$2007
+        bus.mem_write(0x3FFE, 0x24);
+        bus.mem_write(0x3FFE, 0x00);
+        bus.mem_write(0x3FFF, 0x55);
+        assert_eq!(bus.ppu.vram[0x0400], 0x55);
+    }
+
+    #[test]
+    fn test_joypad_port() {
+        let mut bus = Bus::new(test_rom());
+        bus.joypad1.set_button(joypad::START, true);
+        bus.mem_write(0x4016, 1);
+        bus.mem_write(0x4016, 0);
+        let bits: Vec<u8> = (0..4).map(|_| bus.mem_read(0x4016)).collect();
+        assert_eq!(bits, [0, 0, 0, 1]);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        let mut bus = Bus::new(test_rom());
+        for i in 0..256 {
+            bus.mem_write(0x0300 + i, i as u8);
+        }
+        // the copy wraps around from wherever the OAM address was left
+        bus.mem_write(0x2003, 0x10);
+        bus.mem_write(0x4014, 0x03);
+        assert_eq!(bus.ppu.oam[0x10], 0x00);
+        assert_eq!(bus.ppu.oam[0xFF], 0xEF);
+        assert_eq!(bus.ppu.oam[0x00], 0xF0);
+        assert_eq!(bus.take_dma_stall(), 513);
+        assert_eq!(bus.take_dma_stall(), 0);
+
+        bus.tick(1);
+        bus.mem_write(0x4014, 0x03);
+        assert_eq!(bus.take_dma_stall(), 514);
+    }
+
+    #[test]
+    fn test_dmc_dma() {
+        let mut rom = test_rom();
+        rom.prg_rom[0x4000] = 0xFF;
+        let mut bus = Bus::new(rom);
+        // a one byte sample from $C000 at the fastest rate, with its IRQ on
+        bus.mem_write(0x4010, 0x8F);
+        bus.mem_write(0x4011, 0x40);
+        bus.mem_write(0x4012, 0x00);
+        bus.mem_write(0x4013, 0x00);
+        bus.mem_write(0x4015, 0x10);
+        assert_eq!(bus.mem_read(0x4015) & 0x10, 0x10);
+
+        bus.tick(1);
+        assert_eq!(bus.take_dma_stall(), DMC_DMA_STALL);
+        let status = bus.mem_read(0x4015);
+        assert_eq!(status & 0x10, 0);
+        assert_eq!(status & 0x80, 0x80);
+        assert!(bus.irq());
+
+        // writing $4015 acknowledges the IRQ
+        bus.mem_write(0x4015, 0x00);
+        assert!(!bus.irq());
+        bus.tick(54 * 20);
+        assert_eq!(bus.take_dma_stall(), 0);
+    }
+}
@@ -0,0 +1,232 @@
+use crate::apu::DEFAULT_SAMPLE_RATE;
+use crate::region::Region;
+use crate::render::{self, NtscPreset, Scale, Scaler};
+use std::time::Duration;
+
+const DEFAULT_SAVE_INTERVAL_SECS: u64 = 30;
+// NSF rips don't say how long a song is
+const DEFAULT_TRACK_LENGTH_SECS: u64 = 150;
+pub const WINDOW_SCALE: u32 = 3;
+
+// the windowed frontend's command line
+pub struct Config {
+    pub rom_path: String,
+    pub entry: Option<String>,
+    pub patch: Option<String>,
+    pub save_interval: Option<Duration>,
+    pub track: Option<u8>,
+    pub wav: Option<String>,
+    pub track_length: Duration,
+    pub sample_rate: u32,
+    pub audio: bool,
+    // 0.0-1.0
+    pub volume: f32,
+    pub bios: Option<String>,
+    // None follows the header and ROM database
+    pub region: Option<Region>,
+    pub trace: bool,
+    pub sprite_limit: bool,
+    // a built in palette's name or a .pal file
+    pub palette: String,
+    pub ntsc: Option<NtscPreset>,
+    // None leaves scaling to SDL, nearest neighbor at WINDOW_SCALE
+    pub scale: Option<Scale>,
+}
+
+// `args` without the program name; the error is for showing above usage()
+pub fn parse(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+    let mut rom_path = None;
+    let mut entry = None;
+    let mut patch = None;
+    let mut save_interval = Some(Duration::from_secs(DEFAULT_SAVE_INTERVAL_SECS));
+    let mut track = None;
+    let mut wav = None;
+    let mut track_length = Duration::from_secs(DEFAULT_TRACK_LENGTH_SECS);
+    let mut sample_rate = DEFAULT_SAMPLE_RATE;
+    let mut audio = true;
+    let mut volume = 1.0;
+    let mut bios = None;
+    let mut region = None;
+    let mut trace = false;
+    let mut sprite_limit = true;
+    let mut palette = String::from("default");
+    let mut ntsc = None;
+    let mut scaler = None;
+    let mut factor = None;
+    let mut aspect = false;
+    while let Some(arg) = args.next() {
+        let mut value = |what: &str| args.next().ok_or_else(|| format!("{} needs {}", arg, what));
+        match arg.as_str() {
+            "--entry" => entry = Some(value("a file name")?),
+            "--patch" => patch = Some(value("a patch file")?),
+            "--save-interval" => {
+                let secs: u64 = value("a number of seconds")?
+                    .parse()
+                    .map_err(|_| "--save-interval needs a number of seconds")?;
+                // 0 means only write the save on exit
+                save_interval = (secs > 0).then(|| Duration::from_secs(secs));
+            }
+            "--track" => {
+                track = Some(
+                    value("a song number")?
+                        .parse()
+                        .map_err(|_| "--track needs a song number")?,
+                );
+            }
+            "--wav" => wav = Some(value("an output file")?),
+            "--length" => {
+                let secs = value("a number of seconds")?
+                    .parse()
+                    .map_err(|_| "--length needs a number of seconds")?;
+                track_length = Duration::from_secs(secs);
+            }
+            "--sample-rate" => {
+                sample_rate = value("a rate in Hz")?
+                    .parse()
+                    .ok()
+                    .filter(|&rate| rate > 0)
+                    .ok_or("--sample-rate needs a rate in Hz")?;
+            }
+            "--volume" => {
+                let percent: u32 = value("a percentage from 0 to 100")?
+                    .parse()
+                    .ok()
+                    .filter(|&percent| percent <= 100)
+                    .ok_or("--volume needs a percentage from 0 to 100")?;
+                volume = percent as f32 / 100.0;
+            }
+            "--no-audio" => audio = false,
+            "--bios" => bios = Some(value("the FDS BIOS file")?),
+            "--region" => {
+                let name = value("auto, ntsc, pal or dendy")?;
+                region = match name.as_str() {
+                    "auto" => None,
+                    _ => Some(Region::from_name(&name).ok_or_else(|| {
+                        format!("unknown region {}, use auto, ntsc, pal or dendy", name)
+                    })?),
+                };
+            }
+            "--palette" => palette = value("a name or a .pal file")?,
+            "--ntsc" => {
+                let name = value("composite, svideo or rgb")?;
+                ntsc = Some(
+                    NtscPreset::from_name(&name)
+                        .ok_or_else(|| format!("unknown NTSC preset {}", name))?,
+                );
+            }
+            "--scaler" => {
+                let name = value("a scaler name")?;
+                scaler = Some(
+                    Scaler::from_name(&name).ok_or_else(|| format!("unknown scaler {}", name))?,
+                );
+            }
+            "--scale" => {
+                factor = Some(
+                    value("a factor")?
+                        .parse()
+                        .map_err(|_| "--scale needs a whole number")?,
+                );
+            }
+            "--aspect" => aspect = true,
+            "--trace" => trace = true,
+            "--no-sprite-limit" => sprite_limit = false,
+            _ if rom_path.is_none() => rom_path = Some(arg),
+            _ => return Err(format!("unexpected argument {}", arg)),
+        }
+    }
+    Ok(Config {
+        rom_path: rom_path.ok_or("missing rom file")?,
+        entry,
+        patch,
+        save_interval,
+        track,
+        wav,
+        track_length,
+        sample_rate,
+        audio,
+        volume,
+        bios,
+        region,
+        trace,
+        sprite_limit,
+        palette,
+        ntsc,
+        scale: (scaler.is_some() || factor.is_some() || aspect).then(|| Scale {
+            scaler: scaler.unwrap_or(Scaler::Integer),
+            factor: factor.unwrap_or(WINDOW_SCALE as usize),
+            aspect,
+        }),
+    })
+}
+
+pub fn usage() -> String {
+    [
+        "usage: nes_emu [--entry NAME] [--patch FILE] [--save-interval SECS] [--bios FILE] [--region REGION] [--palette NAME|FILE] [--ntsc PRESET] [--scaler SCALER] [--scale N] [--aspect] [--trace] [--no-sprite-limit] [--sample-rate HZ] [--volume PERCENT] [--no-audio] <rom>".to_string(),
+        "       nes_emu [--track N] [--length SECS] [--wav FILE] [--sample-rate HZ] [--volume PERCENT] [--no-audio] [--region REGION] <nsf>".to_string(),
+        "       nes_emu info [--json] <file or directory>...".to_string(),
+        format!(
+            "palettes: {} or a 192 or 1536 byte .pal file",
+            render::palette::BUILTIN_PALETTES.join(", ")
+        ),
+        format!(
+            "NTSC presets: {}, which decode their own colors",
+            render::ntsc::NTSC_PRESETS.join(", ")
+        ),
+        format!("scalers: {}", render::scale::SCALERS.join(", ")),
+    ]
+    .join("\n")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn parse_str(args: &str) -> Result<Config, String> {
+        parse(args.split_whitespace().map(String::from))
+    }
+
+    #[test]
+    fn test_defaults() {
+        let config = parse_str("game.nes").unwrap();
+        assert_eq!(config.rom_path, "game.nes");
+        assert_eq!(config.save_interval, Some(Duration::from_secs(30)));
+        assert_eq!(config.sample_rate, DEFAULT_SAMPLE_RATE);
+        assert!(config.audio);
+        assert_eq!(config.volume, 1.0);
+        assert_eq!(config.region, None);
+        assert!(config.scale.is_none());
+    }
+
+    #[test]
+    fn test_options() {
+        let config = parse_str(
+            "--save-interval 0 --volume 25 --no-audio --region pal --scaler smooth --trace game.nes",
+        )
+        .unwrap();
+        assert_eq!(config.save_interval, None);
+        assert_eq!(config.volume, 0.25);
+        assert!(!config.audio);
+        assert_eq!(config.region, Some(Region::Pal));
+        assert!(config.trace);
+        let scale = config.scale.unwrap();
+        assert_eq!(scale.scaler, Scaler::Smooth);
+        assert_eq!(scale.factor, WINDOW_SCALE as usize);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(parse_str("").err().unwrap(), "missing rom file");
+        assert_eq!(
+            parse_str("--volume 150 game.nes").err().unwrap(),
+            "--volume needs a percentage from 0 to 100"
+        );
+        assert_eq!(
+            parse_str("game.nes --entry").err().unwrap(),
+            "--entry needs a file name"
+        );
+        assert_eq!(
+            parse_str("a.nes b.nes").err().unwrap(),
+            "unexpected argument b.nes"
+        );
+    }
+}
@@ -0,0 +1,246 @@
+use crate::bus::{Board, Bus};
+use crate::cpu::CPU;
+use crate::region::{Region, Timing};
+use crate::render::Frame;
+use crate::rom::{Rom, RomError};
+use crate::state::StateError;
+
+// a console with a game plugged in, run a frame at a time
+pub struct Console {
+    cpu: CPU,
+    // set when the CPU stops on a BRK
+    halted: bool,
+    frames: u64,
+    cycles: u64,
+}
+
+impl Console {
+    pub fn new(rom: Rom) -> Self {
+        Console::with_board(rom, Board::Cartridge)
+    }
+
+    // for disk systems and music rips, which need more than a cartridge
+    pub fn with_board(rom: Rom, board: Board) -> Self {
+        let mut bus = Bus::new(rom);
+        bus.board = board;
+        let mut console = Console {
+            cpu: CPU::new(bus),
+            halted: false,
+            frames: 0,
+            cycles: 0,
+        };
+        console.reset();
+        console
+    }
+
+    // parses an iNES, NES 2.0 or UNIF image
+    pub fn load(data: &[u8]) -> Result<Self, RomError> {
+        Ok(Console::new(Rom::new(data)?))
+    }
+
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+        self.halted = false;
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.bus.rom.region
+    }
+
+    pub fn timing(&self) -> &Timing {
+        &self.cpu.bus.timing
+    }
+
+    // runs until the PPU finishes a picture
+    pub fn run_frame(&mut self) {
+        self.run_frame_with_callback(|_| {});
+    }
+
+    // the callback sees the CPU before every instruction, like CPU::run_with_callback
+    pub fn run_frame_with_callback<F>(&mut self, mut callback: F)
+    where
+        F: FnMut(&mut CPU),
+    {
+        while !self.halted {
+            callback(&mut self.cpu);
+            if self.step() {
+                return;
+            }
+        }
+    }
+
+    // runs one instruction, returning true if it finished a frame
+    pub fn step(&mut self) -> bool {
+        if self.halted {
+            return false;
+        }
+        match self.cpu.step() {
+            Some(cycles) => self.cycles += cycles as u64,
+            None => {
+                self.halted = true;
+                return false;
+            }
+        }
+        let finished = self.cpu.bus.ppu.take_frame();
+        if finished {
+            self.frames += 1;
+        }
+        finished
+    }
+
+    // frames and CPU cycles run since power on
+    pub fn frames(&self) -> u64 {
+        self.frames
+    }
+
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    pub fn frame(&self) -> &Frame {
+        &self.cpu.bus.ppu.frame
+    }
+
+    // drawing more than 8 sprites a line gets rid of most flicker
+    pub fn set_sprite_limit(&mut self, on: bool) {
+        self.cpu.bus.ppu.set_sprite_limit(on);
+    }
+
+    // samples a second for take_samples, apu::DEFAULT_SAMPLE_RATE to begin with
+    pub fn set_sample_rate(&mut self, sample_rate: u32) {
+        self.cpu.bus.apu.set_sample_rate(sample_rate);
+    }
+
+    // audio generated since the last call, mono, -1.0..=1.0
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    // buttons are the joypad::BUTTON_A..RIGHT bits, on controller 1
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        self.cpu.bus.joypad1.set_button(button, pressed);
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        self.cpu.save_state()
+    }
+
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        self.cpu.load_state(data)?;
+        self.halted = false;
+        Ok(())
+    }
+
+    pub fn cpu(&self) -> &CPU {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    pub fn bus(&self) -> &Bus {
+        &self.cpu.bus
+    }
+
+    pub fn bus_mut(&mut self) -> &mut Bus {
+        &mut self.cpu.bus
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::RomFormat;
+
+    // a 32K PRG image with the program at $8000 and vectors pointing into it
+    fn test_console(program: &[u8], nmi: u16) -> Console {
+        let mut prg = vec![0xEA; 0x8000];
+        prg[..program.len()].copy_from_slice(program);
+        prg[0x7FFA..0x7FFC].copy_from_slice(&nmi.to_le_bytes());
+        prg[0x7FFC..0x7FFE].copy_from_slice(&0x8000u16.to_le_bytes());
+        Console::new(Rom::from_prg(RomFormat::INes, prg, Region::Ntsc))
+    }
+
+    #[test]
+    fn test_nmi_every_frame() {
+        let console = &mut test_console(
+            &[
+                0xA9, 0x80, // LDA #$80
+                0x8D, 0x00, 0x20, // STA $2000
+                0x4C, 0x05, 0x80, // JMP $8005
+                0xE6, 0x00, // $8008: INC $00
+                0x40, // RTI
+            ],
+            0x8008,
+        );
+        for _ in 0..3 {
+            console.run_frame();
+        }
+        assert!(!console.is_halted());
+        assert_eq!(console.frames(), 3);
+        // vblank starts 241 lines in, then every 262 lines less half a dot
+        assert!((86955..86963).contains(&console.cycles()));
+        // the third frame's NMI is taken at the start of the next one
+        assert_eq!(console.bus().cpu_vram[0], 2);
+    }
+
+    #[test]
+    fn test_oam_dma_stalls_cpu() {
+        let mut console = test_console(
+            &[
+                0xA9, 0x02, // LDA #$02
+                0x8D, 0x14, 0x40, // STA $4014
+            ],
+            0x8000,
+        );
+        console.bus_mut().cpu_vram[0x200] = 0x42;
+        console.step();
+        console.step();
+        // the store starts the DMA on cycle 6, an even one
+        assert_eq!(console.cycles(), 2 + 4 + 513);
+        assert_eq!(console.bus().ppu.oam[0], 0x42);
+    }
+
+    #[test]
+    fn test_halts_on_brk() {
+        let mut console = test_console(&[0x00], 0x8000);
+        console.run_frame();
+        assert!(console.is_halted());
+        console.reset();
+        assert!(!console.is_halted());
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let mut console = test_console(&[0xE6, 0x10, 0x4C, 0x00, 0x80], 0x8000);
+        console.run_frame();
+        let state = console.save_state();
+        let count = console.bus().cpu_vram[0x10];
+        console.run_frame();
+        assert_ne!(console.bus().cpu_vram[0x10], count);
+        console.load_state(&state).unwrap();
+        assert_eq!(console.bus().cpu_vram[0x10], count);
+    }
+
+    #[test]
+    fn test_bad_state_changes_nothing() {
+        let mut console = test_console(&[0xE6, 0x10, 0x4C, 0x00, 0x80], 0x8000);
+        console.run_frame();
+        let state = console.save_state();
+        console.run_frame();
+        let count = console.bus().cpu_vram[0x10];
+        let current = console.save_state();
+        // cut off near the end, after the CPU and most of the bus have been read
+        assert_eq!(
+            console.load_state(&state[..state.len() - 2]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(console.save_state(), current);
+        assert_eq!(console.bus().cpu_vram[0x10], count);
+    }
+}
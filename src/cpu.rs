@@ -1,5 +1,6 @@
 use crate::bus::Bus;
 use crate::opcodes::{AddressingMode, OpCode, CPU_OPCODES};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
 
 pub struct CPU {
     pub register_a: u8,
@@ -8,13 +9,21 @@ pub struct CPU {
     pub status: u8,
     pub program_counter: u16,
     pub stack_pointer: u8,
-    bus: Bus,
+    pub bus: Bus,
+    // cycles the running instruction has clocked the bus for so far, None
+    // outside step() so that tracing and loading don't move time along
+    step_cycles: Option<usize>,
+    // set by indexed addressing that carried into the high byte, which costs
+    // reads a cycle
+    page_crossed: bool,
+    // taken branches cost a cycle, two if they land on another page
+    branch_cycles: usize,
 }
 
 pub trait Mem {
-    fn mem_read(&self, addr: u16) -> u8;
+    fn mem_read(&mut self, addr: u16) -> u8;
     fn mem_write(&mut self, addr: u16, data: u8);
-    fn mem_read_u16(&self, pos: u16) -> u16 {
+    fn mem_read_u16(&mut self, pos: u16) -> u16 {
         let lo = self.mem_read(pos) as u16;
         let hi = self.mem_read(pos + 1) as u16;
         (hi << 8) | (lo as u16)
@@ -27,27 +36,47 @@ pub trait Mem {
     }
 }
 
+// each access is a cycle, and the rest of the machine is clocked up to it
+// first, so the PPU and APU see reads and writes when they really happen
 impl Mem for CPU {
-    fn mem_read(&self, addr: u16) -> u8 {
+    fn mem_read(&mut self, addr: u16) -> u8 {
+        self.tick_access();
         self.bus.mem_read(addr)
     }
 
     fn mem_write(&mut self, addr: u16, data: u8) {
+        self.tick_access();
         self.bus.mem_write(addr, data);
     }
+}
 
-    fn mem_read_u16(&self, addr: u16) -> u16 {
-        self.bus.mem_read_u16(addr)
-    }
-
-    fn mem_write_u16(&mut self, addr: u16, data: u16) {
-        self.bus.mem_write_u16(addr, data)
+impl Snapshot for CPU {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.register_a);
+        w.write_u8(self.register_x);
+        w.write_u8(self.register_y);
+        w.write_u8(self.status);
+        w.write_u16(self.program_counter);
+        w.write_u8(self.stack_pointer);
+        self.bus.snapshot(w);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.register_a = r.read_u8()?;
+        self.register_x = r.read_u8()?;
+        self.register_y = r.read_u8()?;
+        self.status = r.read_u8()?;
+        self.program_counter = r.read_u16()?;
+        self.stack_pointer = r.read_u8()?;
+        self.bus.restore(r)
     }
 }
 
 const STACK_ADDRESS: u16 = 0x0100;
 const STACK_RESET: u8 = 0xFD;
 const RESET_VECTOR: u16 = 0xFFFC;
+const NMI_VECTOR: u16 = 0xFFFA;
+const IRQ_VECTOR: u16 = 0xFFFE;
 
 impl CPU {
     pub fn new(bus: Bus) -> Self {
@@ -59,10 +88,32 @@ impl CPU {
             program_counter: 0,
             stack_pointer: STACK_RESET,
             bus,
+            step_cycles: None,
+            page_crossed: false,
+            branch_cycles: 0,
+        }
+    }
+
+    fn tick_access(&mut self) {
+        if let Some(cycles) = &mut self.step_cycles {
+            *cycles += 1;
+            self.bus.tick(1);
         }
     }
 
-    pub fn get_operand_address(&self, mode: &AddressingMode) -> u16 {
+    // clocks the bus for whatever part of `cycles` the accesses didn't cover,
+    // and for any DMA the instruction started
+    fn finish_step(&mut self, cycles: usize) -> usize {
+        let ticked = self.step_cycles.take().unwrap_or(0);
+        if cycles > ticked {
+            self.bus.tick(cycles - ticked);
+        }
+        let stall = self.bus.take_dma_stall();
+        self.bus.tick(stall);
+        cycles.max(ticked) + stall
+    }
+
+    pub fn get_operand_address(&mut self, mode: &AddressingMode) -> u16 {
         match mode {
             AddressingMode::Immediate => self.program_counter + 1,
             AddressingMode::ZeroPage => self.mem_read(self.program_counter + 1) as u16,
@@ -79,13 +130,11 @@ impl CPU {
             AddressingMode::Absolute => self.mem_read_u16(self.program_counter + 1),
             AddressingMode::Absolute_X => {
                 let pos = self.mem_read_u16(self.program_counter + 1);
-                let addr = pos.wrapping_add(self.register_x as u16);
-                addr
+                self.indexed(pos, self.register_x)
             }
             AddressingMode::Absolute_Y => {
                 let pos = self.mem_read_u16(self.program_counter + 1);
-                let addr = pos.wrapping_add(self.register_y as u16);
-                addr
+                self.indexed(pos, self.register_y)
             }
             AddressingMode::Indirect => {
                 let ptr = self.mem_read_u16(self.program_counter + 1);
@@ -101,11 +150,11 @@ impl CPU {
                 (hi as u16) << 8 | (lo as u16)
             }
             AddressingMode::Indirect_Y => {
-                let base = self.mem_read(self.program_counter + 1);
-                let ptr: u8 = base.wrapping_add(self.register_y);
+                // Y is added to the pointer's target, not the pointer
+                let ptr = self.mem_read(self.program_counter + 1);
                 let lo = self.mem_read(ptr as u16);
                 let hi = self.mem_read(ptr.wrapping_add(1) as u16);
-                (hi as u16) << 8 | (lo as u16)
+                self.indexed((hi as u16) << 8 | (lo as u16), self.register_y)
             }
             AddressingMode::Relative => {
                 // in this case the returned addr is the jump target
@@ -122,6 +171,12 @@ impl CPU {
         }
     }
 
+    fn indexed(&mut self, base: u16, index: u8) -> u16 {
+        let addr = base.wrapping_add(index as u16);
+        self.page_crossed = base & 0xFF00 != addr & 0xFF00;
+        addr
+    }
+
     // pub fn mem_read(&self, addr: u16) -> u8 {
     //     self.memory[addr as usize]
     // }
@@ -144,6 +199,7 @@ impl CPU {
     // }
 
     pub fn reset(&mut self) {
+        self.bus.reset();
         self.register_a = 0;
         self.register_x = 0;
         self.register_y = 0;
@@ -153,11 +209,41 @@ impl CPU {
         self.stack_pointer = STACK_RESET;
     }
 
+    // pushes PC and status and jumps through the vector, like a BRK without the B flag
+    fn interrupt(&mut self, vector: u16) {
+        let [lo, hi] = self.program_counter.to_le_bytes();
+        self.push_to_stack(hi);
+        self.push_to_stack(lo);
+        self.push_to_stack(self.status & 0b1110_1111 | 0b0010_0000);
+        self.set_interrupt_flag(true);
+        self.program_counter = self.mem_read_u16(vector);
+    }
+
+    pub fn save_state(&self) -> Vec<u8> {
+        let mut w = StateWriter::new();
+        self.snapshot(&mut w);
+        w.finish()
+    }
+
+    // all or nothing: a state that turns out to be truncated or made for
+    // another cartridge partway through puts back what was there before
+    pub fn load_state(&mut self, data: &[u8]) -> Result<(), StateError> {
+        let mut r = StateReader::new(data)?;
+        let before = self.save_state();
+        let result = self.restore(&mut r);
+        if result.is_err() {
+            self.restore(&mut StateReader::new(&before)?)
+                .expect("restoring the machine's own state failed");
+        }
+        result
+    }
+
+    // copies a bare 6502 program into RAM at $0600 and points PC at it
     pub fn load(&mut self, program: Vec<u8>) {
         for i in 0..(program.len() as u16) {
             self.mem_write(0x0600 + i, program[i as usize]);
         }
-        self.mem_write_u16(0xFFFC, 0x0600);
+        self.program_counter = 0x0600;
     }
 
     pub fn add(&mut self, value: u8) -> u8 {
@@ -706,7 +792,10 @@ impl CPU {
 
     fn branch(&mut self, opcode: &OpCode, conditional: bool) {
         if conditional {
+            let next = self.program_counter.wrapping_add(opcode.length);
             self.program_counter = self.get_operand_address(&opcode.mode);
+            let target = self.program_counter.wrapping_add(opcode.length);
+            self.branch_cycles = 1 + (next & 0xFF00 != target & 0xFF00) as usize;
         }
         self.program_counter += opcode.length;
     }
@@ -721,85 +810,178 @@ impl CPU {
     {
         loop {
             callback(self);
-            let byte = self.mem_read(self.program_counter);
-            let opcode = CPU_OPCODES
-                .get(&byte)
-                .expect(format!("opcode {:X} not found", byte).as_str());
-            // println!("===========");
-            // println!("registers:");
-            // println!("A: {:02X}", self.register_a);
-            // println!("X: {:02X}", self.register_x);
-            // println!("Y: {:02X}", self.register_y);
-            // println!("status: {:08b}", self.status);
-            // println!("opcode: {:02X}", byte);
-            // println!("opcode: {:?}", opcode.name);
-            // println!("PC: {:04X}", self.program_counter);
-            // println!("stack contents:");
-            // for byte in
-            //     &self.memory[(STACK_ADDRESS + self.stack_pointer as u16) as usize..=0x01FF]
-            // {
-            //     println!("{:02X}", byte);
-            // }
-
-            match &*opcode.name.to_lowercase() {
-                "adc" => self.adc(opcode),
-                "and" => self.and(opcode),
-                "asl" => self.asl(opcode),
-                "bcc" => self.bcc(opcode),
-                "bcs" => self.bcs(opcode),
-                "beq" => self.beq(opcode),
-                "bit" => self.bit(opcode),
-                "bmi" => self.bmi(opcode),
-                "bne" => self.bne(opcode),
-                "bpl" => self.bpl(opcode),
-                "brk" => return,
-                "bvc" => self.bvc(opcode),
-                "bvs" => self.bvs(opcode),
-                "clc" => self.clc(opcode),
-                "cld" => self.cld(opcode),
-                "cli" => self.cli(opcode),
-                "clv" => self.clv(opcode),
-                "cmp" => self.cmp(opcode),
-                "cpx" => self.cpx(opcode),
-                "cpy" => self.cpy(opcode),
-                "dec" => self.dec(opcode),
-                "dex" => self.dex(opcode),
-                "dey" => self.dey(opcode),
-                "eor" => self.eor(opcode),
-                "inc" => self.inc(opcode),
-                "inx" => self.inx(opcode),
-                "iny" => self.iny(opcode),
-                "jmp" => self.jmp(opcode),
-                "jsr" => self.jsr(opcode),
-                "lda" => self.lda(opcode),
-                "ldx" => self.ldx(opcode),
-                "ldy" => self.ldy(opcode),
-                "lsr" => self.lsr(opcode),
-                "nop" => self.nop(opcode),
-                "ora" => self.ora(opcode),
-                "pha" => self.pha(opcode),
-                "php" => self.php(opcode),
-                "pla" => self.pla(opcode),
-                "plp" => self.plp(opcode),
-                "rol" => self.rol(opcode),
-                "ror" => self.ror(opcode),
-                "rti" => self.rti(opcode),
-                "rts" => self.rts(opcode),
-                "sbc" => self.sbc(opcode),
-                "sec" => self.sec(opcode),
-                "sed" => self.sed(opcode),
-                "sei" => self.sei(opcode),
-                "sta" => self.sta(opcode),
-                "stx" => self.stx(opcode),
-                "sty" => self.sty(opcode),
-                "tax" => self.tax(opcode),
-                "tay" => self.tay(opcode),
-                "tsx" => self.tsx(opcode),
-                "txa" => self.txa(opcode),
-                "txs" => self.txs(opcode),
-                "tya" => self.tya(opcode),
-                other => panic!("unrecognized opcode {other}"),
+            if self.step().is_none() {
+                return;
+            }
+        }
+    }
+
+    // runs one instruction, returning the cycles it took or None on BRK
+    pub fn step(&mut self) -> Option<usize> {
+        self.step_cycles = Some(0);
+        self.page_crossed = false;
+        self.branch_cycles = 0;
+        if self.bus.poll_nmi() {
+            self.interrupt(NMI_VECTOR);
+            return Some(self.finish_step(7));
+        }
+        if self.bus.irq() && self.status & 0b0000_0100 == 0 {
+            self.interrupt(IRQ_VECTOR);
+            return Some(self.finish_step(7));
+        }
+
+        let byte = self.mem_read(self.program_counter);
+        let opcode = CPU_OPCODES
+            .get(&byte)
+            .expect(format!("opcode {:X} not found", byte).as_str());
+        // println!("===========");
+        // println!("registers:");
+        // println!("A: {:02X}", self.register_a);
+        // println!("X: {:02X}", self.register_x);
+        // println!("Y: {:02X}", self.register_y);
+        // println!("status: {:08b}", self.status);
+        // println!("opcode: {:02X}", byte);
+        // println!("opcode: {:?}", opcode.name);
+        // println!("PC: {:04X}", self.program_counter);
+        // println!("stack contents:");
+        // for byte in
+        //     &self.memory[(STACK_ADDRESS + self.stack_pointer as u16) as usize..=0x01FF]
+        // {
+        //     println!("{:02X}", byte);
+        // }
+
+        match &*opcode.name.to_lowercase() {
+            "adc" => self.adc(opcode),
+            "and" => self.and(opcode),
+            "asl" => self.asl(opcode),
+            "bcc" => self.bcc(opcode),
+            "bcs" => self.bcs(opcode),
+            "beq" => self.beq(opcode),
+            "bit" => self.bit(opcode),
+            "bmi" => self.bmi(opcode),
+            "bne" => self.bne(opcode),
+            "bpl" => self.bpl(opcode),
+            "brk" => {
+                self.step_cycles = None;
+                return None;
+            }
+            "bvc" => self.bvc(opcode),
+            "bvs" => self.bvs(opcode),
+            "clc" => self.clc(opcode),
+            "cld" => self.cld(opcode),
+            "cli" => self.cli(opcode),
+            "clv" => self.clv(opcode),
+            "cmp" => self.cmp(opcode),
+            "cpx" => self.cpx(opcode),
+            "cpy" => self.cpy(opcode),
+            "dec" => self.dec(opcode),
+            "dex" => self.dex(opcode),
+            "dey" => self.dey(opcode),
+            "eor" => self.eor(opcode),
+            "inc" => self.inc(opcode),
+            "inx" => self.inx(opcode),
+            "iny" => self.iny(opcode),
+            "jmp" => self.jmp(opcode),
+            "jsr" => self.jsr(opcode),
+            "lda" => self.lda(opcode),
+            "ldx" => self.ldx(opcode),
+            "ldy" => self.ldy(opcode),
+            "lsr" => self.lsr(opcode),
+            "nop" => self.nop(opcode),
+            "ora" => self.ora(opcode),
+            "pha" => self.pha(opcode),
+            "php" => self.php(opcode),
+            "pla" => self.pla(opcode),
+            "plp" => self.plp(opcode),
+            "rol" => self.rol(opcode),
+            "ror" => self.ror(opcode),
+            "rti" => self.rti(opcode),
+            "rts" => self.rts(opcode),
+            "sbc" => self.sbc(opcode),
+            "sec" => self.sec(opcode),
+            "sed" => self.sed(opcode),
+            "sei" => self.sei(opcode),
+            "sta" => self.sta(opcode),
+            "stx" => self.stx(opcode),
+            "sty" => self.sty(opcode),
+            "tax" => self.tax(opcode),
+            "tay" => self.tay(opcode),
+            "tsx" => self.tsx(opcode),
+            "txa" => self.txa(opcode),
+            "txs" => self.txs(opcode),
+            "tya" => self.tya(opcode),
+            other => panic!("unrecognized opcode {other}"),
+        }
+        // stores and read-modify-writes always take the extra cycle, the
+        // table already counts it
+        let page_cycles = match opcode.name {
+            "ADC" | "AND" | "CMP" | "EOR" | "LDA" | "LDX" | "LDY" | "ORA" | "SBC" => {
+                self.page_crossed as usize
             }
+            _ => 0,
+        };
+        Some(self.finish_step(opcode.cycles + page_cycles + self.branch_cycles))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::test_rom;
+
+    // runs the first instruction of `program`, placed at `addr` in RAM
+    fn step_at(addr: u16, program: &[u8], setup: impl Fn(&mut CPU)) -> usize {
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        for (i, &byte) in program.iter().enumerate() {
+            cpu.mem_write(addr + i as u16, byte);
         }
+        cpu.program_counter = addr;
+        setup(&mut cpu);
+        cpu.step().unwrap()
+    }
+
+    #[test]
+    fn test_branch_cycles() {
+        // BNE +4
+        let bne = [0xD0, 0x04];
+        assert_eq!(step_at(0x0600, &bne, |cpu| cpu.status |= 0b10), 2);
+        assert_eq!(step_at(0x0600, &bne, |_| {}), 3);
+        // from $06FA the next instruction is at $06FC, the target $0700
+        assert_eq!(step_at(0x06FA, &bne, |_| {}), 4);
+        // BNE -4 from $0700 goes back to $06FE
+        assert_eq!(step_at(0x0700, &[0xD0, 0xFC], |_| {}), 4);
+    }
+
+    #[test]
+    fn test_page_crossing_reads() {
+        // LDA $06F0,X
+        let lda = [0xBD, 0xF0, 0x06];
+        assert_eq!(step_at(0x0600, &lda, |cpu| cpu.register_x = 0x0F), 4);
+        assert_eq!(step_at(0x0600, &lda, |cpu| cpu.register_x = 0x10), 5);
+        // STA $06F0,X takes 5 either way
+        let sta = [0x9D, 0xF0, 0x06];
+        assert_eq!(step_at(0x0600, &sta, |cpu| cpu.register_x = 0x00), 5);
+        assert_eq!(step_at(0x0600, &sta, |cpu| cpu.register_x = 0x10), 5);
+
+        // LDA ($10),Y with $10 pointing at $06F0
+        let lda = [0xB1, 0x10];
+        let pointer = |cpu: &mut CPU, y| {
+            cpu.mem_write_u16(0x10, 0x06F0);
+            cpu.mem_write(0x0700, 0x42);
+            cpu.register_y = y;
+        };
+        assert_eq!(step_at(0x0600, &lda, |cpu| pointer(cpu, 0x0F)), 5);
+        assert_eq!(step_at(0x0600, &lda, |cpu| pointer(cpu, 0x10)), 6);
+    }
+
+    #[test]
+    fn test_indirect_y_indexes_target() {
+        let mut cpu = CPU::new(Bus::new(test_rom()));
+        cpu.load(vec![0xB1, 0x10]);
+        cpu.mem_write_u16(0x10, 0x0300);
+        cpu.mem_write(0x0305, 0x42);
+        cpu.register_y = 5;
+        cpu.step();
+        assert_eq!(cpu.register_a, 0x42);
     }
 }
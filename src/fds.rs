@@ -0,0 +1,834 @@
+use crate::battery;
+use crate::patch;
+use crate::region::Region;
+use crate::rom::{Mirroring, Rom, RomFormat};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+// fwNES images start with a 16 byte header, bare dumps go straight into side A
+pub const FDS_TAG: &[u8] = b"FDS\x1a";
+const DISK_TAG: &[u8] = b"\x01*NINTENDO-HVC*";
+const HEADER_SIZE: usize = 16;
+pub const SIDE_SIZE: usize = 65500;
+pub const BIOS_SIZE: usize = 0x2000;
+const RAM_SIZE: usize = 0x8000;
+
+// The drive sees gaps and a start mark around every block, which image files
+// leave out. Sides are expanded to what's on the magnetic surface while loaded.
+const LEAD_IN_GAP: usize = 28300 / 8;
+const BLOCK_GAP: usize = 976 / 8;
+const BLOCK_START: u8 = 0x80;
+// the drive never reports CRC errors, so the stored CRC is just a placeholder
+const FAKE_CRC: [u8; 2] = [0x4D, 0x62];
+// a bit more than a real disk holds, so there's room to write new files
+const DISK_CAPACITY: usize = 0x13000;
+
+// CPU cycles per byte at the drive's ~96.4kbit/s, and the motor spin up time
+const BYTE_CYCLES: u32 = 150;
+const SPIN_UP_CYCLES: u32 = 50000;
+// how long the drive stays empty when flipping the disk, long enough for the
+// BIOS to notice
+const SWAP_CYCLES: u32 = 1_789_773;
+
+#[derive(Debug, PartialEq)]
+pub enum FdsError {
+    BadMagic,
+    NoSides,
+    BadSide(usize),
+    BadBios { len: usize },
+}
+
+impl fmt::Display for FdsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FdsError::BadMagic => write!(f, "Invalid FDS image"),
+            FdsError::NoSides => write!(f, "FDS image has no disk sides"),
+            FdsError::BadSide(side) => write!(f, "FDS side {} has no disk header", side + 1),
+            FdsError::BadBios { len } => write!(
+                f,
+                "FDS BIOS should be {} bytes, this one is {}",
+                BIOS_SIZE, len
+            ),
+        }
+    }
+}
+
+impl std::error::Error for FdsError {}
+
+pub fn is_fds(raw: &[u8]) -> bool {
+    raw.starts_with(FDS_TAG) || raw.starts_with(DISK_TAG)
+}
+
+// the BIOS is copyrighted, users point us at their own disksys.rom
+pub fn bios_rom(bios: Vec<u8>) -> Result<Rom, FdsError> {
+    if bios.len() != BIOS_SIZE {
+        return Err(FdsError::BadBios { len: bios.len() });
+    }
+    Ok(Rom::from_prg(RomFormat::Fds, bios, Region::Ntsc))
+}
+
+// disk writes are kept as an IPS patch against the image, the image itself is
+// never modified
+pub fn save_path(image_path: &Path) -> PathBuf {
+    image_path.with_extension("sav")
+}
+
+// applies earlier disk writes from the save next to the image, if there is one
+pub fn load_save(image_path: &Path, image: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let data = match std::fs::read(save_path(image_path)) {
+        Ok(data) => data,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.to_string()),
+    };
+    patch::apply(&data, image)
+        .map(Some)
+        .map_err(|err| err.to_string())
+}
+
+// block 1 is the disk info, 2 the file count, then a header (3) and data (4)
+// block for every file, the data block's size coming from its header
+fn block_len(block_type: u8, file_size: usize) -> Option<usize> {
+    match block_type {
+        1 => Some(56),
+        2 => Some(2),
+        3 => Some(16),
+        4 => Some(1 + file_size),
+        _ => None,
+    }
+}
+
+fn file_size(header_block: &[u8]) -> usize {
+    u16::from_le_bytes([header_block[13], header_block[14]]) as usize
+}
+
+fn add_gaps(side: &[u8]) -> Vec<u8> {
+    let mut disk = vec![0; LEAD_IN_GAP];
+    let mut pos = 0;
+    let mut size = 0;
+    while let Some(len) = side.get(pos).and_then(|&t| block_len(t, size)) {
+        let Some(block) = side.get(pos..pos + len) else {
+            break;
+        };
+        if block[0] == 3 {
+            size = file_size(block);
+        }
+        disk.push(BLOCK_START);
+        disk.extend(block);
+        disk.extend(FAKE_CRC);
+        disk.extend([0; BLOCK_GAP]);
+        pos += len;
+    }
+    disk.resize(disk.len().max(DISK_CAPACITY), 0);
+    disk
+}
+
+// reads the blocks back off the surface, over the top of the side as loaded
+fn strip_gaps(disk: &[u8], original: &[u8]) -> Vec<u8> {
+    let mut side = original.to_vec();
+    let mut out = 0;
+    let mut pos = 0;
+    let mut size = 0;
+    // skip the gap up to each block's start mark
+    // a block cut short at the end of the surface leaves `pos` past it
+    while let Some(offset) = disk
+        .get(pos..)
+        .and_then(|rest| rest.iter().position(|&b| b == BLOCK_START))
+    {
+        pos += offset + 1;
+        let Some(len) = disk.get(pos).and_then(|&t| block_len(t, size)) else {
+            break;
+        };
+        let Some(block) = disk.get(pos..pos + len) else {
+            break;
+        };
+        if block[0] == 3 {
+            size = file_size(block);
+        }
+        if side.len() < out + len {
+            side.resize(out + len, 0);
+        }
+        side[out..out + len].copy_from_slice(block);
+        out += len;
+        pos += len + FAKE_CRC.len();
+    }
+    side
+}
+
+pub struct FdsImage {
+    header: Option<Vec<u8>>,
+    original: Vec<Vec<u8>>,
+    // sides with their gaps, as the drive reads and writes them
+    disks: Vec<Vec<u8>>,
+}
+
+impl FdsImage {
+    pub fn new(raw: &[u8]) -> Result<FdsImage, FdsError> {
+        let (header, data) = if raw.starts_with(FDS_TAG) {
+            let header = raw.get(..HEADER_SIZE).ok_or(FdsError::NoSides)?;
+            (Some(header.to_vec()), &raw[HEADER_SIZE..])
+        } else if raw.starts_with(DISK_TAG) {
+            (None, raw)
+        } else {
+            return Err(FdsError::BadMagic);
+        };
+
+        // the header's side count is often wrong, trust the file size
+        let original: Vec<Vec<u8>> = data.chunks(SIDE_SIZE).map(|s| s.to_vec()).collect();
+        if original.is_empty() {
+            return Err(FdsError::NoSides);
+        }
+        if let Some(bad) = original.iter().position(|s| !s.starts_with(DISK_TAG)) {
+            return Err(FdsError::BadSide(bad));
+        }
+        let disks = original.iter().map(|side| add_gaps(side)).collect();
+        Ok(FdsImage {
+            header,
+            original,
+            disks,
+        })
+    }
+
+    pub fn side_count(&self) -> usize {
+        self.disks.len()
+    }
+
+    // the image file with everything the game has written
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut raw = self.header.clone().unwrap_or_default();
+        for (disk, original) in self.disks.iter().zip(&self.original) {
+            raw.extend(strip_gaps(disk, original));
+        }
+        raw
+    }
+}
+
+// The RAM adapter: 32K of PRG RAM at $6000-$DFFF, the BIOS at $E000, CHR RAM,
+// an IRQ timer and the disk drive. Sound ($4040-$4097) isn't emulated.
+pub struct FdsBoard {
+    pub image: FdsImage,
+    ram: Vec<u8>,
+    pub mirroring: Mirroring,
+    dirty: bool,
+
+    // None while the drive is empty
+    side: Option<usize>,
+    last_side: usize,
+    // a side waiting to go in once the drive has been empty a while
+    swap: Option<(usize, u32)>,
+
+    disk_registers_enabled: bool,
+    timer_reload: u16,
+    timer_counter: u16,
+    timer_repeat: bool,
+    timer_enabled: bool,
+
+    // reading $4030/$4031 acknowledges these
+    timer_irq: bool,
+    disk_irq: bool,
+    transfer_complete: bool,
+
+    motor_on: bool,
+    reset_transfer: bool,
+    read_mode: bool,
+    crc_control: bool,
+    previous_crc_control: bool,
+    disk_ready: bool,
+    disk_irq_enabled: bool,
+    write_data: u8,
+    read_data: u8,
+
+    position: usize,
+    delay: u32,
+    end_of_head: bool,
+    scanning: bool,
+    gap_ended: bool,
+    crc: u16,
+}
+
+impl FdsBoard {
+    pub fn new(image: FdsImage) -> Self {
+        FdsBoard {
+            image,
+            ram: vec![0; RAM_SIZE],
+            mirroring: Mirroring::Horizontal,
+            dirty: false,
+            side: Some(0),
+            last_side: 0,
+            swap: None,
+            disk_registers_enabled: true,
+            timer_reload: 0,
+            timer_counter: 0,
+            timer_repeat: false,
+            timer_enabled: false,
+            timer_irq: false,
+            disk_irq: false,
+            transfer_complete: false,
+            motor_on: false,
+            reset_transfer: false,
+            read_mode: true,
+            crc_control: false,
+            previous_crc_control: false,
+            disk_ready: false,
+            disk_irq_enabled: false,
+            write_data: 0,
+            read_data: 0,
+            position: 0,
+            delay: 0,
+            end_of_head: true,
+            scanning: false,
+            gap_ended: false,
+            crc: 0,
+        }
+    }
+
+    pub fn side(&self) -> Option<usize> {
+        self.side
+    }
+
+    pub fn eject(&mut self) {
+        self.side = None;
+        self.swap = None;
+    }
+
+    // puts back whatever side was in the drive last
+    pub fn reinsert(&mut self) {
+        self.insert(self.last_side);
+    }
+
+    fn insert(&mut self, side: usize) {
+        self.side = Some(side);
+        self.last_side = side;
+        self.swap = None;
+    }
+
+    // ejects and, after a pause the BIOS can see, inserts the next side
+    pub fn flip(&mut self) -> usize {
+        let next = match self.swap {
+            Some((side, _)) => (side + 1) % self.image.side_count(),
+            None => (self.last_side + 1) % self.image.side_count(),
+        };
+        self.side = None;
+        self.swap = Some((next, SWAP_CYCLES));
+        next
+    }
+
+    pub fn irq(&self) -> bool {
+        self.timer_irq || self.disk_irq
+    }
+
+    pub fn read_ram(&self, addr: u16) -> u8 {
+        self.ram[(addr - 0x6000) as usize]
+    }
+
+    pub fn write_ram(&mut self, addr: u16, data: u8) {
+        self.ram[(addr - 0x6000) as usize] = data;
+    }
+
+    // $4030-$4033, anything else in the expansion area is open bus
+    pub fn read_register(&mut self, addr: u16) -> u8 {
+        match addr {
+            0x4030 => {
+                let mut status = 0;
+                status |= self.timer_irq as u8;
+                status |= (self.transfer_complete as u8) << 1;
+                status |= (self.end_of_head as u8) << 6;
+                status |= (self.disk_registers_enabled as u8) << 7;
+                self.transfer_complete = false;
+                self.timer_irq = false;
+                self.disk_irq = false;
+                status
+            }
+            0x4031 => {
+                self.transfer_complete = false;
+                self.disk_irq = false;
+                self.read_data
+            }
+            0x4032 => {
+                let empty = self.side.is_none();
+                // bit 2 is write protect, which an empty drive also reports
+                0x40 | empty as u8 | ((empty || !self.scanning) as u8) << 1 | (empty as u8) << 2
+            }
+            // battery good, nothing on the expansion port
+            0x4033 => 0x80,
+            _ => 0,
+        }
+    }
+
+    pub fn write_register(&mut self, addr: u16, data: u8) {
+        match addr {
+            0x4020 => self.timer_reload = self.timer_reload & 0xFF00 | data as u16,
+            0x4021 => self.timer_reload = self.timer_reload & 0x00FF | (data as u16) << 8,
+            0x4022 => {
+                self.timer_repeat = data & 0b01 != 0;
+                self.timer_enabled = data & 0b10 != 0 && self.disk_registers_enabled;
+                if self.timer_enabled {
+                    self.timer_counter = self.timer_reload;
+                } else {
+                    self.timer_irq = false;
+                }
+            }
+            0x4023 => {
+                self.disk_registers_enabled = data & 0b01 != 0;
+                if !self.disk_registers_enabled {
+                    self.timer_enabled = false;
+                    self.timer_irq = false;
+                    self.disk_irq = false;
+                }
+            }
+            0x4024 if self.disk_registers_enabled => {
+                self.write_data = data;
+                self.transfer_complete = false;
+                self.disk_irq = false;
+            }
+            0x4025 if self.disk_registers_enabled => {
+                self.motor_on = data & 0b0000_0001 != 0;
+                self.reset_transfer = data & 0b0000_0010 != 0;
+                self.read_mode = data & 0b0000_0100 != 0;
+                self.mirroring = if data & 0b0000_1000 != 0 {
+                    Mirroring::Horizontal
+                } else {
+                    Mirroring::Vertical
+                };
+                self.crc_control = data & 0b0001_0000 != 0;
+                self.disk_ready = data & 0b0100_0000 != 0;
+                self.disk_irq_enabled = data & 0b1000_0000 != 0;
+                self.disk_irq = false;
+            }
+            _ => {}
+        }
+    }
+
+    pub fn tick(&mut self, cycles: usize) {
+        for _ in 0..cycles {
+            self.clock_timer();
+            self.clock_swap();
+            self.clock_disk();
+        }
+    }
+
+    fn clock_timer(&mut self) {
+        if !self.timer_enabled {
+            return;
+        }
+        if self.timer_counter == 0 {
+            self.timer_irq = true;
+            self.timer_counter = self.timer_reload;
+            if !self.timer_repeat {
+                self.timer_enabled = false;
+            }
+        } else {
+            self.timer_counter -= 1;
+        }
+    }
+
+    fn clock_swap(&mut self) {
+        if let Some((side, cycles)) = self.swap {
+            match cycles {
+                0 => self.insert(side),
+                _ => self.swap = Some((side, cycles - 1)),
+            }
+        }
+    }
+
+    fn update_crc(&mut self, value: u8) {
+        for bit in 0..8 {
+            let carry = self.crc & 1 != 0;
+            self.crc >>= 1;
+            if carry {
+                self.crc ^= 0x8408;
+            }
+            if value & (1 << bit) != 0 {
+                self.crc ^= 0x8000;
+            }
+        }
+    }
+
+    fn clock_disk(&mut self) {
+        let side = match self.side {
+            Some(side) if self.motor_on => side,
+            _ => {
+                self.end_of_head = true;
+                self.scanning = false;
+                return;
+            }
+        };
+        if self.reset_transfer && !self.scanning {
+            return;
+        }
+        if self.end_of_head {
+            // back to the start of the disk, it takes a moment to get going
+            self.delay = SPIN_UP_CYCLES;
+            self.end_of_head = false;
+            self.position = 0;
+            self.gap_ended = false;
+            return;
+        }
+        if self.delay > 0 {
+            self.delay -= 1;
+            return;
+        }
+
+        self.scanning = true;
+        let mut irq = self.disk_irq_enabled;
+        if self.read_mode {
+            let data = self.image.disks[side][self.position];
+            if !self.previous_crc_control {
+                self.update_crc(data);
+            }
+            if !self.disk_ready {
+                self.gap_ended = false;
+                self.crc = 0;
+            } else if data != 0 && !self.gap_ended {
+                // the start mark comes through, but without an IRQ
+                self.gap_ended = true;
+                irq = false;
+            }
+            if self.gap_ended {
+                self.transfer_complete = true;
+                self.read_data = data;
+                if irq {
+                    self.disk_irq = true;
+                }
+            }
+        } else {
+            let mut data = 0;
+            if !self.crc_control {
+                self.transfer_complete = true;
+                data = self.write_data;
+                if irq {
+                    self.disk_irq = true;
+                }
+            }
+            if !self.disk_ready {
+                data = 0;
+            }
+            if !self.crc_control {
+                self.update_crc(data);
+            } else {
+                if !self.previous_crc_control {
+                    self.update_crc(0);
+                    self.update_crc(0);
+                }
+                data = self.crc as u8;
+                self.crc >>= 8;
+            }
+            self.image.disks[side][self.position] = data;
+            self.dirty = true;
+            self.gap_ended = false;
+        }
+        self.previous_crc_control = self.crc_control;
+
+        self.position += 1;
+        if self.position >= self.image.disks[side].len() {
+            self.motor_on = false;
+            self.end_of_head = true;
+        } else {
+            self.delay = BYTE_CYCLES;
+        }
+    }
+
+    // writes the disk's changes as a patch against the image as loaded
+    pub fn save(&mut self, path: &Path, original: &[u8]) -> io::Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+        battery::write_atomic(path, &patch::create_ips(original, &self.image.to_bytes()))?;
+        self.dirty = false;
+        Ok(())
+    }
+}
+
+impl Snapshot for FdsBoard {
+    fn snapshot(&self, w: &mut StateWriter) {
+        // the disks too, a state from before the game saved has to put the
+        // old files back
+        for disk in &self.image.disks {
+            w.write_bytes(disk);
+        }
+        w.write_bytes(&self.ram);
+        w.write_u8((self.mirroring == Mirroring::Vertical) as u8);
+        w.write_u8(self.side.is_some() as u8);
+        w.write_u8(self.side.unwrap_or(0) as u8);
+        w.write_u8(self.last_side as u8);
+        let (swap_side, swap_cycles) = self.swap.unwrap_or((0, 0));
+        w.write_u8(self.swap.is_some() as u8);
+        w.write_u8(swap_side as u8);
+        w.write_u32(swap_cycles);
+
+        w.write_u8(self.disk_registers_enabled as u8);
+        w.write_u16(self.timer_reload);
+        w.write_u16(self.timer_counter);
+        w.write_u8(self.timer_repeat as u8);
+        w.write_u8(self.timer_enabled as u8);
+        w.write_u8(self.timer_irq as u8);
+        w.write_u8(self.disk_irq as u8);
+        w.write_u8(self.transfer_complete as u8);
+
+        w.write_u8(self.motor_on as u8);
+        w.write_u8(self.reset_transfer as u8);
+        w.write_u8(self.read_mode as u8);
+        w.write_u8(self.crc_control as u8);
+        w.write_u8(self.previous_crc_control as u8);
+        w.write_u8(self.disk_ready as u8);
+        w.write_u8(self.disk_irq_enabled as u8);
+        w.write_u8(self.write_data);
+        w.write_u8(self.read_data);
+
+        w.write_u32(self.position as u32);
+        w.write_u32(self.delay);
+        w.write_u8(self.end_of_head as u8);
+        w.write_u8(self.scanning as u8);
+        w.write_u8(self.gap_ended as u8);
+        w.write_u16(self.crc);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        for disk in &mut self.image.disks {
+            let data = r.read_bytes()?;
+            if data.len() != disk.len() {
+                return Err(StateError::SizeMismatch {
+                    what: "disk side",
+                    expected: disk.len(),
+                    actual: data.len(),
+                });
+            }
+            // the save file has to catch up with whatever changed
+            if disk[..] != *data {
+                disk.copy_from_slice(data);
+                self.dirty = true;
+            }
+        }
+        r.read_into("RAM adapter RAM", &mut self.ram)?;
+        self.mirroring = match r.read_u8()? {
+            0 => Mirroring::Horizontal,
+            _ => Mirroring::Vertical,
+        };
+        let sides = self.image.side_count();
+        let inserted = r.read_u8()? != 0;
+        let side = r.read_u8()? as usize % sides;
+        self.side = inserted.then_some(side);
+        self.last_side = r.read_u8()? as usize % sides;
+        let swapping = r.read_u8()? != 0;
+        let swap_side = r.read_u8()? as usize % sides;
+        let swap_cycles = r.read_u32()?;
+        self.swap = swapping.then_some((swap_side, swap_cycles));
+
+        self.disk_registers_enabled = r.read_u8()? != 0;
+        self.timer_reload = r.read_u16()?;
+        self.timer_counter = r.read_u16()?;
+        self.timer_repeat = r.read_u8()? != 0;
+        self.timer_enabled = r.read_u8()? != 0;
+        self.timer_irq = r.read_u8()? != 0;
+        self.disk_irq = r.read_u8()? != 0;
+        self.transfer_complete = r.read_u8()? != 0;
+
+        self.motor_on = r.read_u8()? != 0;
+        self.reset_transfer = r.read_u8()? != 0;
+        self.read_mode = r.read_u8()? != 0;
+        self.crc_control = r.read_u8()? != 0;
+        self.previous_crc_control = r.read_u8()? != 0;
+        self.disk_ready = r.read_u8()? != 0;
+        self.disk_irq_enabled = r.read_u8()? != 0;
+        self.write_data = r.read_u8()?;
+        self.read_data = r.read_u8()?;
+
+        // every side is at least DISK_CAPACITY long
+        self.position = (r.read_u32()? as usize).min(DISK_CAPACITY - 1);
+        self.delay = r.read_u32()?;
+        self.end_of_head = r.read_u8()? != 0;
+        self.scanning = r.read_u8()? != 0;
+        self.gap_ended = r.read_u8()? != 0;
+        self.crc = r.read_u16()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::{Board, Bus};
+    use crate::cpu::Mem;
+
+    fn test_side() -> Vec<u8> {
+        let mut side = vec![1];
+        side.extend(&DISK_TAG[1..]);
+        side.resize(56, 0);
+        side.extend([2, 1]);
+        let mut header = vec![3; 16];
+        header[13..15].copy_from_slice(&4u16.to_le_bytes());
+        side.extend(header);
+        side.extend([4, 0xDE, 0xAD, 0xBE, 0xEF]);
+        side.resize(SIDE_SIZE, 0);
+        side
+    }
+
+    fn test_image() -> Vec<u8> {
+        let mut raw = FDS_TAG.to_vec();
+        raw.push(2);
+        raw.resize(HEADER_SIZE, 0);
+        raw.extend(test_side());
+        raw.extend(test_side());
+        raw
+    }
+
+    #[test]
+    fn test_image_round_trip() {
+        let raw = test_image();
+        let image = FdsImage::new(&raw).unwrap();
+        assert_eq!(image.side_count(), 2);
+        assert_eq!(image.to_bytes(), raw);
+
+        // headerless dumps work too
+        let image = FdsImage::new(&raw[HEADER_SIZE..]).unwrap();
+        assert_eq!(image.to_bytes(), raw[HEADER_SIZE..]);
+    }
+
+    #[test]
+    fn test_gaps() {
+        let side = test_side();
+        let disk = add_gaps(&side);
+        assert!(disk[..LEAD_IN_GAP].iter().all(|&b| b == 0));
+        assert_eq!(disk[LEAD_IN_GAP], BLOCK_START);
+        assert_eq!(disk[LEAD_IN_GAP + 1..LEAD_IN_GAP + 57], side[..56]);
+        assert_eq!(disk.len(), DISK_CAPACITY);
+    }
+
+    #[test]
+    fn test_truncated_surface() {
+        let side = test_side();
+        let disk = add_gaps(&side);
+        // cut off right after the last block's data, losing its CRC
+        let data = disk.windows(4).position(|w| w == [0xDE, 0xAD, 0xBE, 0xEF]);
+        let disk = &disk[..data.unwrap() + 4];
+        assert_eq!(strip_gaps(disk, &side), side);
+    }
+
+    #[test]
+    fn test_bad_images() {
+        assert_eq!(FdsImage::new(b"NES\x1a").err(), Some(FdsError::BadMagic));
+        assert_eq!(FdsImage::new(FDS_TAG).err(), Some(FdsError::NoSides));
+        let mut raw = test_image();
+        raw.extend([0; 100]);
+        assert_eq!(FdsImage::new(&raw).err(), Some(FdsError::BadSide(2)));
+        assert_eq!(
+            bios_rom(vec![0; 100]).err(),
+            Some(FdsError::BadBios { len: 100 })
+        );
+    }
+
+    #[test]
+    fn test_timer_irq() {
+        let mut board = FdsBoard::new(FdsImage::new(&test_image()).unwrap());
+        board.write_register(0x4020, 10);
+        board.write_register(0x4021, 0);
+        board.write_register(0x4022, 0b11);
+        board.tick(10);
+        assert!(!board.irq());
+        board.tick(1);
+        assert!(board.irq());
+        assert_eq!(board.read_register(0x4030) & 1, 1);
+        assert!(!board.irq());
+        // repeating, so it fires again
+        board.tick(11);
+        assert!(board.irq());
+    }
+
+    #[test]
+    fn test_reads_disk() {
+        let mut board = FdsBoard::new(FdsImage::new(&test_image()).unwrap());
+        // motor on, read mode, disk ready
+        board.write_register(0x4025, 0b0100_0101);
+        let mut bytes = vec![];
+        for _ in 0..1_000_000 {
+            board.tick(1);
+            if board.read_register(0x4030) & 0b10 != 0 {
+                bytes.push(board.read_register(0x4031));
+            }
+            if bytes.len() == 16 {
+                break;
+            }
+        }
+        assert_eq!(bytes, [&[BLOCK_START], DISK_TAG].concat());
+    }
+
+    #[test]
+    fn test_flip() {
+        let mut board = FdsBoard::new(FdsImage::new(&test_image()).unwrap());
+        assert_eq!(board.flip(), 1);
+        assert_eq!(board.side(), None);
+        assert_eq!(board.read_register(0x4032) & 1, 1);
+        board.tick(SWAP_CYCLES as usize + 1);
+        assert_eq!(board.side(), Some(1));
+        assert_eq!(board.flip(), 0);
+        board.eject();
+        board.reinsert();
+        assert_eq!(board.side(), Some(1));
+    }
+
+    #[test]
+    fn test_state_round_trip() {
+        let fds_bus = || {
+            let mut bus = Bus::new(bios_rom(vec![0; BIOS_SIZE]).unwrap());
+            bus.board = Board::Fds(FdsBoard::new(FdsImage::new(&test_image()).unwrap()));
+            bus
+        };
+        let mut bus = fds_bus();
+        bus.mem_write(0x6000, 0x12);
+        bus.mem_write(0xDFFF, 0x34);
+        bus.mem_write(0x4020, 100);
+        bus.mem_write(0x4022, 0b10);
+        // vertical mirroring, motor on
+        bus.mem_write(0x4025, 0b0000_0001);
+        let Board::Fds(board) = &mut bus.board else {
+            unreachable!()
+        };
+        board.flip();
+        board.image.disks[1][LEAD_IN_GAP] = 0x42;
+        let mut w = StateWriter::new();
+        bus.snapshot(&mut w);
+        let state = w.finish();
+
+        let mut restored = fds_bus();
+        restored
+            .restore(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        assert_eq!(restored.mem_read(0x6000), 0x12);
+        assert_eq!(restored.mem_read(0xDFFF), 0x34);
+        assert_eq!(restored.rom.screen_mirroring, Mirroring::Vertical);
+        let Board::Fds(board) = &mut restored.board else {
+            unreachable!()
+        };
+        assert_eq!(board.side(), None);
+        assert!(board.motor_on);
+        assert_eq!(board.image.disks[1][LEAD_IN_GAP], 0x42);
+        // the timer carries on where it was
+        board.tick(100);
+        assert!(!board.irq());
+        board.tick(1);
+        assert!(board.irq());
+        board.tick(SWAP_CYCLES as usize);
+        assert_eq!(board.side(), Some(1));
+    }
+
+    #[test]
+    fn test_save_is_a_patch() {
+        let raw = test_image();
+        let mut board = FdsBoard::new(FdsImage::new(&raw).unwrap());
+        let path = std::env::temp_dir().join(format!("nes_emu_fds_{}.sav", std::process::id()));
+
+        board.save(&path, &raw).unwrap();
+        assert!(!path.exists());
+
+        // a game rewriting the file data in place
+        let disk = &mut board.image.disks[0];
+        let data = disk.windows(4).position(|w| w == [0xDE, 0xAD, 0xBE, 0xEF]);
+        disk[data.unwrap()] = 0x42;
+        board.dirty = true;
+        board.save(&path, &raw).unwrap();
+        let patched = patch::apply(&std::fs::read(&path).unwrap(), &raw).unwrap();
+        assert_eq!(patched[HEADER_SIZE + 56 + 2 + 16 + 1], 0x42);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
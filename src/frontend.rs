@@ -0,0 +1,561 @@
+use crate::audio::{AudioQueue, MAX_RATE_DELTA, VOLUME_STEP};
+use crate::battery::BatterySave;
+use crate::bus::Board;
+use crate::config::{Config, WINDOW_SCALE};
+use crate::fds::{self, FdsBoard, FdsImage};
+use crate::nsf::{Nsf, NsfPlayer};
+use crate::render::{self, Frame, Image, NtscFilter, Palette, Scale};
+use crate::rom::Rom;
+use crate::wav::WavWriter;
+use crate::{archive, patch, Console};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+// Everything the windowed frontend does that isn't SDL: loading, saving,
+// what the hotkeys do, pacing and music playback. The binary turns SDL events
+// into calls here and puts the pictures and sound on screen and speaker.
+
+// looked for next to the disk image when --bios isn't given
+const FDS_BIOS_NAME: &str = "disksys.rom";
+// samples per sound card callback, and how much the ring buffer holds
+pub const AUDIO_DEVICE_SAMPLES: u16 = 1024;
+const AUDIO_BUFFER_MS: usize = 120;
+// how far the emulator may drift from the clock while locked to vsync
+// before it's put right
+const LOCK_SLACK_FRAMES: u32 = 5;
+
+pub struct GameFile {
+    // the name inside an archive, or the file name
+    pub name: String,
+    // with any patch applied
+    pub data: Vec<u8>,
+}
+
+// opens the file, out of an archive if need be, and applies --patch or a
+// patch found next to it
+pub fn load_file(config: &Config) -> Result<GameFile, String> {
+    let path = Path::new(&config.rom_path);
+    let file = archive::load(path, config.entry.as_deref())
+        .map_err(|err| format!("failed to open {}: {}", path.display(), err))?;
+    let patch_path = match &config.patch {
+        Some(patch_path) => Some(PathBuf::from(patch_path)),
+        None => patch::find_patch(path),
+    };
+    let data = match patch_path {
+        Some(patch_path) => {
+            println!("applying patch {}", patch_path.display());
+            std::fs::read(&patch_path)
+                .map_err(|err| err.to_string())
+                .and_then(|data| patch::apply(&data, &file.data).map_err(|err| err.to_string()))
+                .map_err(|err| format!("failed to apply {}: {}", patch_path.display(), err))?
+        }
+        None => file.data,
+    };
+    Ok(GameFile {
+        name: file.name,
+        data,
+    })
+}
+
+// turns frames into RGB24 pictures the way the command line asked
+pub struct Video {
+    palette: Palette,
+    ntsc: Option<NtscFilter>,
+    scale: Option<Scale>,
+}
+
+impl Video {
+    pub fn new(config: &Config) -> Result<Video, String> {
+        let palette = Palette::load(&config.palette)
+            .map_err(|err| format!("failed to load palette {}: {}", config.palette, err))?;
+        Ok(Video {
+            palette,
+            ntsc: config.ntsc.map(NtscFilter::new),
+            scale: config.scale,
+        })
+    }
+
+    fn source_width(&self) -> usize {
+        match self.ntsc {
+            Some(_) => render::NTSC_WIDTH,
+            None => render::WIDTH,
+        }
+    }
+
+    // what render() hands over; a software scaler makes it the window's size
+    pub fn picture_size(&self) -> (usize, usize) {
+        match &self.scale {
+            Some(scale) => scale.output_size(self.source_width(), render::HEIGHT),
+            None => (self.source_width(), render::HEIGHT),
+        }
+    }
+
+    pub fn window_size(&self) -> (u32, u32) {
+        match self.scale {
+            Some(_) => {
+                let (width, height) = self.picture_size();
+                (width as u32, height as u32)
+            }
+            None => (
+                render::WIDTH as u32 * WINDOW_SCALE,
+                render::HEIGHT as u32 * WINDOW_SCALE,
+            ),
+        }
+    }
+
+    // when the picture is smaller than the window, it's for SDL to scale by
+    // this much
+    pub fn hardware_scale(&self) -> Option<u32> {
+        self.scale.is_none().then_some(WINDOW_SCALE)
+    }
+
+    pub fn render(&self, frame: &Frame) -> Vec<u8> {
+        let rgb = match &self.ntsc {
+            Some(filter) => filter.apply(frame),
+            None => frame.to_rgb(&self.palette),
+        };
+        match &self.scale {
+            Some(scale) => {
+                scale
+                    .apply(&Image::new(self.source_width(), render::HEIGHT, rgb))
+                    .rgb
+            }
+            None => rgb,
+        }
+    }
+}
+
+// a cartridge or a disk, set up as the command line says
+pub fn load_console(config: &Config, file: &GameFile) -> Result<Console, String> {
+    let (mut rom, board) = if fds::is_fds(&file.data) {
+        load_fds(config, &file.data)?
+    } else {
+        let rom = Rom::new(&file.data).map_err(|err| format!("failed to load rom: {}", err))?;
+        (rom, Board::Cartridge)
+    };
+    for warning in &rom.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    println!("{}: prg+chr {}", config.rom_path, rom.hashes().prg_chr);
+    for correction in &rom.corrections {
+        println!("rom database: corrected {}", correction);
+    }
+    if let Some(region) = config.region {
+        rom.region = region;
+    }
+    let timing = rom.region.timing();
+    println!(
+        "{} timing: {} scanlines ({} in vblank), {:.4} Hz",
+        rom.region,
+        timing.scanlines,
+        timing.vblank_scanlines,
+        timing.frame_rate()
+    );
+    let mut console = Console::with_board(rom, board);
+    console.set_sprite_limit(config.sprite_limit);
+    // hack for nestest since it doesn't start at the addr in 0xFFFC
+    if file.name.ends_with("nestest.nes") {
+        console.cpu_mut().program_counter = 0xC000;
+    }
+    Ok(console)
+}
+
+// the disk goes in the RAM adapter, the BIOS is what the CPU boots
+fn load_fds(config: &Config, image: &[u8]) -> Result<(Rom, Board), String> {
+    let image_path = Path::new(&config.rom_path);
+    let bios_path = match &config.bios {
+        Some(path) => PathBuf::from(path),
+        None => image_path.with_file_name(FDS_BIOS_NAME),
+    };
+    let bios = std::fs::read(&bios_path)
+        .map_err(|err| err.to_string())
+        .and_then(|data| fds::bios_rom(data).map_err(|err| err.to_string()))
+        .map_err(|err| {
+            format!(
+                "failed to load FDS BIOS {}: {}\npoint --bios at a dump of the Disk System BIOS",
+                bios_path.display(),
+                err
+            )
+        })?;
+
+    let save_path = fds::save_path(image_path);
+    let saved = fds::load_save(image_path, image)
+        .map_err(|err| format!("failed to read {}: {}", save_path.display(), err))?;
+    if saved.is_some() {
+        println!("loaded {}", save_path.display());
+    }
+    let disk = FdsImage::new(saved.as_deref().unwrap_or(image))
+        .map_err(|err| format!("failed to load disk: {}", err))?;
+    println!(
+        "{} disk sides, F6 ejects/inserts, F7 flips to the next side",
+        disk.side_count()
+    );
+    Ok((bios, Board::Fds(FdsBoard::new(disk))))
+}
+
+// Battery RAM and disk writes, written every --save-interval and on exit.
+// Failures are reported and the game carries on.
+pub struct Saves {
+    battery: Option<BatterySave>,
+    interval: Option<Duration>,
+    // disk saves are a patch against the image as loaded
+    image: Vec<u8>,
+    disk_path: PathBuf,
+    last_disk_save: Instant,
+}
+
+impl Saves {
+    // loads the battery save into the console, if there is one
+    pub fn new(config: &Config, console: &mut Console, image: Vec<u8>) -> Saves {
+        let rom_path = Path::new(&config.rom_path);
+        let battery = console.bus().rom.battery.then(|| {
+            let mut battery = BatterySave::new(rom_path, config.save_interval);
+            match battery.load(&mut console.bus_mut().prg_ram) {
+                Ok(true) => println!("loaded {}", battery.path().display()),
+                Ok(false) => {}
+                Err(err) => eprintln!("failed to read {}: {}", battery.path().display(), err),
+            }
+            battery
+        });
+        Saves {
+            battery,
+            interval: config.save_interval,
+            image,
+            disk_path: fds::save_path(rom_path),
+            last_disk_save: Instant::now(),
+        }
+    }
+
+    // every frame; `quit` writes whatever hasn't been
+    pub fn tick(&mut self, console: &mut Console, quit: bool) {
+        if let Some(battery) = &mut self.battery {
+            let prg_ram = &console.bus().prg_ram;
+            let result = if quit {
+                battery.flush(prg_ram)
+            } else {
+                battery.tick(prg_ram)
+            };
+            if let Err(err) = result {
+                eprintln!("failed to write {}: {}", battery.path().display(), err);
+            }
+        }
+        if let Board::Fds(board) = &mut console.bus_mut().board {
+            let due = self
+                .interval
+                .is_some_and(|interval| self.last_disk_save.elapsed() >= interval);
+            if quit || due {
+                self.last_disk_save = Instant::now();
+                if let Err(err) = board.save(&self.disk_path, &self.image) {
+                    eprintln!("failed to write {}: {}", self.disk_path.display(), err);
+                }
+            }
+        }
+    }
+}
+
+// the save state slot, next to the ROM
+pub fn state_path(rom_path: &str) -> PathBuf {
+    Path::new(rom_path).with_extension("state")
+}
+
+// what the hotkeys do
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Action {
+    SaveState,
+    LoadState,
+    // ejects the disk, or puts it back
+    ToggleDisk,
+    FlipDisk,
+    ToggleMute,
+    VolumeDown,
+    VolumeUp,
+}
+
+// returns what to tell the user, if anything; actions that don't apply, like
+// flipping a cartridge, do nothing
+pub fn perform(
+    action: Action,
+    console: &mut Console,
+    state_path: &Path,
+    audio: Option<&mut AudioQueue>,
+) -> Result<Option<String>, String> {
+    match action {
+        Action::SaveState => std::fs::write(state_path, console.save_state())
+            .map(|_| Some(format!("saved state to {}", state_path.display())))
+            .map_err(|err| format!("failed to save state: {}", err)),
+        Action::LoadState => std::fs::read(state_path)
+            .map_err(|err| err.to_string())
+            .and_then(|data| console.load_state(&data).map_err(|err| err.to_string()))
+            .map(|_| Some(format!("loaded state from {}", state_path.display())))
+            .map_err(|err| format!("failed to load state: {}", err)),
+        Action::ToggleDisk => Ok(match &mut console.bus_mut().board {
+            Board::Fds(board) if board.side().is_some() => {
+                board.eject();
+                Some("disk ejected".to_string())
+            }
+            Board::Fds(board) => {
+                board.reinsert();
+                Some("disk inserted".to_string())
+            }
+            _ => None,
+        }),
+        Action::FlipDisk => Ok(match &mut console.bus_mut().board {
+            Board::Fds(board) => {
+                let side = board.flip();
+                Some(format!(
+                    "flipping to disk {} side {}",
+                    side / 2 + 1,
+                    ['A', 'B'][side % 2]
+                ))
+            }
+            _ => None,
+        }),
+        Action::ToggleMute => Ok(audio.map(|audio| {
+            audio.set_muted(!audio.is_muted());
+            format!("sound {}", if audio.is_muted() { "off" } else { "on" })
+        })),
+        Action::VolumeDown | Action::VolumeUp => Ok(audio.map(|audio| {
+            let step = match action {
+                Action::VolumeDown => -VOLUME_STEP,
+                _ => VOLUME_STEP,
+            };
+            audio.set_volume(audio.volume() + step);
+            format!("volume {:.0}%", audio.volume() * 100.0)
+        })),
+    }
+}
+
+// the queue to feed a sound card running at `sample_rate`
+pub fn audio_queue(sample_rate: u32, volume: f32) -> AudioQueue {
+    let capacity = sample_rate as usize * AUDIO_BUFFER_MS / 1000;
+    let mut queue = AudioQueue::new(capacity.max(AUDIO_DEVICE_SAMPLES as usize * 2));
+    queue.set_volume(volume);
+    queue
+}
+
+// Decides how many frames to run between presents. When the display
+// refreshes close enough to the console's frame rate, vsync sets the pace
+// at one frame a refresh, and the audio rate control soaks up the
+// difference. Otherwise, PAL on a 60 Hz screen say, frames follow the clock.
+pub struct Pacer {
+    period: Duration,
+    locked: bool,
+    // how far the emulator has got, in wall clock time
+    next_frame: Instant,
+}
+
+impl Pacer {
+    pub fn new(frame_rate: f64, refresh_rate: Option<f64>) -> Self {
+        let locked =
+            refresh_rate.is_some_and(|rate| (rate / frame_rate - 1.0).abs() <= MAX_RATE_DELTA);
+        Pacer {
+            period: Duration::from_secs_f64(1.0 / frame_rate),
+            locked,
+            next_frame: Instant::now(),
+        }
+    }
+
+    pub fn is_locked(&self) -> bool {
+        self.locked
+    }
+
+    pub fn frames_due(&mut self) -> usize {
+        let now = Instant::now();
+        let slack = self.period * LOCK_SLACK_FRAMES;
+        if self.locked {
+            self.next_frame += self.period;
+            if self.next_frame <= now + slack {
+                // a little behind is the rates not quite matching, a long
+                // way is a stall; either way carry on from here
+                if self.next_frame + slack < now {
+                    self.next_frame = now;
+                }
+                return 1;
+            }
+            // far ahead, so presenting isn't waiting for vsync after all
+            self.locked = false;
+        }
+
+        if let Some(wait) = self.next_frame.checked_duration_since(now) {
+            std::thread::sleep(wait);
+        }
+        let now = Instant::now();
+        let mut due = 0;
+        while self.next_frame <= now {
+            self.next_frame += self.period;
+            due += 1;
+        }
+        // running behind, don't try to catch up with a burst of frames
+        if due > 2 {
+            self.next_frame = now + self.period;
+            due = 1;
+        }
+        due
+    }
+}
+
+// Plays an NSF rip from --track to the last song, in real time into
+// `audio` if there is one, or writes the first track to --wav.
+// `sample_rate` is what the sound card ended up running at.
+pub fn play_nsf(
+    config: &Config,
+    data: &[u8],
+    sample_rate: u32,
+    mut audio: Option<&mut AudioQueue>,
+) -> Result<(), String> {
+    let mut nsf = Nsf::new(data).map_err(|err| format!("failed to load nsf: {}", err))?;
+    println!("title:     {}", nsf.title);
+    println!("artist:    {}", nsf.artist);
+    println!("copyright: {}", nsf.copyright);
+    let chips = nsf.expansion_chips();
+    if !chips.is_empty() {
+        println!("expansion: {} (not emulated)", chips.join(", "));
+    }
+
+    if let Some(region) = config.region {
+        nsf.region = region;
+    }
+    let mut player = NsfPlayer::new(nsf, sample_rate);
+    let songs = player.nsf.songs;
+    println!(
+        "{} songs, NSF v{}, {} timing",
+        songs,
+        player.nsf.version,
+        player.region()
+    );
+    let first = config.track.unwrap_or(player.nsf.starting_song);
+    if first == 0 || first > songs {
+        return Err(format!("--track must be between 1 and {}", songs));
+    }
+    let frames = (config.track_length.as_secs_f64() / player.play_period().as_secs_f64()) as u64;
+
+    if let Some(path) = &config.wav {
+        start_song(&mut player, first);
+        WavWriter::create(Path::new(path), sample_rate)
+            .and_then(|mut wav| {
+                for _ in 0..frames {
+                    player.run_frame();
+                    wav.write_samples(&player.take_samples())?;
+                }
+                wav.finish().map(|_| ())
+            })
+            .map_err(|err| format!("failed to write {}: {}", path, err))?;
+        println!("wrote track {} to {}", first, path);
+        return Ok(());
+    }
+
+    let period = player.play_period();
+    for song in first..=songs {
+        start_song(&mut player, song);
+        let mut next_frame = Instant::now();
+        for frame in 0..frames {
+            player.run_frame();
+            let samples = player.take_samples();
+            if let Some(audio) = audio.as_deref_mut() {
+                audio.push(&samples);
+            }
+            if frame % 30 == 0 {
+                let elapsed = period.mul_f64(frame as f64).as_secs();
+                print!(
+                    "\rtrack {}/{}  {}:{:02} / {}:{:02}",
+                    song,
+                    songs,
+                    elapsed / 60,
+                    elapsed % 60,
+                    config.track_length.as_secs() / 60,
+                    config.track_length.as_secs() % 60
+                );
+                std::io::stdout().flush().unwrap();
+            }
+            next_frame += period;
+            if let Some(wait) = next_frame.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        }
+        println!();
+    }
+    Ok(())
+}
+
+fn start_song(player: &mut NsfPlayer, song: u8) {
+    if !player.start_song(song) {
+        eprintln!("warning: INIT for track {} didn't return", song);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::config;
+
+    fn test_config(rom_path: &str) -> Config {
+        config::parse([rom_path.to_string()].into_iter()).unwrap()
+    }
+
+    #[test]
+    fn test_state_slot() {
+        let mut console = Console::new(crate::rom::test::test_rom());
+        let path = std::env::temp_dir().join(format!("nes_emu_slot_{}.state", std::process::id()));
+        console.bus_mut().cpu_vram[0] = 0x12;
+        let saved = perform(Action::SaveState, &mut console, &path, None).unwrap();
+        assert_eq!(saved, Some(format!("saved state to {}", path.display())));
+        console.bus_mut().cpu_vram[0] = 0x34;
+        perform(Action::LoadState, &mut console, &path, None).unwrap();
+        assert_eq!(console.bus().cpu_vram[0], 0x12);
+        std::fs::remove_file(&path).unwrap();
+
+        let err = perform(Action::LoadState, &mut console, &path, None).unwrap_err();
+        assert!(err.starts_with("failed to load state"));
+    }
+
+    #[test]
+    fn test_audio_and_disk_actions() {
+        let mut console = Console::new(crate::rom::test::test_rom());
+        let path = state_path("game.nes");
+        assert_eq!(path, PathBuf::from("game.state"));
+        // a cartridge has no disk to flip, and there may be no sound card
+        assert_eq!(
+            perform(Action::FlipDisk, &mut console, &path, None),
+            Ok(None)
+        );
+        assert_eq!(
+            perform(Action::ToggleMute, &mut console, &path, None),
+            Ok(None)
+        );
+
+        let mut queue = audio_queue(48000, 0.5);
+        let message = perform(Action::VolumeUp, &mut console, &path, Some(&mut queue));
+        assert_eq!(message, Ok(Some("volume 60%".to_string())));
+        let message = perform(Action::ToggleMute, &mut console, &path, Some(&mut queue));
+        assert_eq!(message, Ok(Some("sound off".to_string())));
+        assert!(queue.is_muted());
+    }
+
+    #[test]
+    fn test_video_sizes() {
+        let mut config = test_config("game.nes");
+        let video = Video::new(&config).unwrap();
+        assert_eq!(video.picture_size(), (render::WIDTH, render::HEIGHT));
+        assert_eq!(
+            video.window_size(),
+            (256 * WINDOW_SCALE, 240 * WINDOW_SCALE)
+        );
+        assert_eq!(video.hardware_scale(), Some(WINDOW_SCALE));
+
+        config = config::parse(["--scale", "2", "game.nes"].map(String::from).into_iter()).unwrap();
+        let video = Video::new(&config).unwrap();
+        assert_eq!(video.window_size(), (512, 480));
+        assert_eq!(video.hardware_scale(), None);
+        let frame = Frame::new();
+        assert_eq!(video.render(&frame).len(), 512 * 480 * 3);
+    }
+
+    #[test]
+    fn test_pacer_locks_to_close_refresh_rates() {
+        assert!(Pacer::new(60.0988, Some(60.0)).is_locked());
+        assert!(!Pacer::new(50.007, Some(60.0)).is_locked());
+        assert!(!Pacer::new(60.0988, None).is_locked());
+        assert_eq!(Pacer::new(60.0988, Some(60.0)).frames_due(), 1);
+    }
+}
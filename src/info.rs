@@ -0,0 +1,269 @@
+use crate::archive;
+use crate::fds::{self, FdsImage};
+use crate::nsf::{self, Nsf};
+use crate::rom::{Hash, Rom};
+use serde_json::{json, Map, Value};
+use std::path::{Path, PathBuf};
+
+// one line of the report, printed as "key: text" or as a JSON member
+struct Field {
+    key: &'static str,
+    text: String,
+    json: Value,
+}
+
+impl Field {
+    fn new(key: &'static str, text: impl ToString, json: impl Into<Value>) -> Field {
+        Field {
+            key,
+            text: text.to_string(),
+            json: json.into(),
+        }
+    }
+
+    fn size(key: &'static str, bytes: usize) -> Field {
+        Field::new(key, format_size(bytes), bytes)
+    }
+
+    fn hash(key: &'static str, hash: &Hash) -> Field {
+        let json = json!({
+            "crc32": format!("{:08X}", hash.crc32),
+            "sha1": hash.sha1_hex(),
+        });
+        Field::new(key, hash, json)
+    }
+}
+
+fn format_size(bytes: usize) -> String {
+    if bytes >= 1024 && bytes.is_multiple_of(1024) {
+        format!("{} KiB", bytes / 1024)
+    } else {
+        format!("{} bytes", bytes)
+    }
+}
+
+fn yes_no(flag: bool) -> &'static str {
+    if flag {
+        "yes"
+    } else {
+        "no"
+    }
+}
+
+fn rom_fields(rom: &Rom) -> Vec<Field> {
+    let hashes = rom.hashes();
+    let (chr_key, chr_text) = match rom.chr.is_ram() {
+        true => ("chr_ram", "CHR RAM"),
+        false => ("chr_rom", "CHR ROM"),
+    };
+    let chr_size = rom.chr.as_slice().len();
+    let trainer_size = rom.trainer.as_ref().map_or(0, |trainer| trainer.len());
+    let warnings: Vec<String> = rom.warnings.iter().map(|w| w.to_string()).collect();
+    let corrections: Vec<String> = rom.corrections.iter().map(|c| c.to_string()).collect();
+    vec![
+        Field::new("format", rom.format, rom.format.to_string()),
+        Field::new("mapper", rom.mapper, rom.mapper),
+        Field::new("submapper", rom.submapper, rom.submapper),
+        Field::size("prg_rom", rom.prg_rom.len()),
+        Field::new(
+            chr_key,
+            format!("{} ({})", format_size(chr_size), chr_text),
+            chr_size,
+        ),
+        Field::new(
+            "mirroring",
+            rom.screen_mirroring,
+            rom.screen_mirroring.to_string(),
+        ),
+        Field::new("battery", yes_no(rom.battery), rom.battery),
+        Field::new("trainer", yes_no(trainer_size > 0), trainer_size > 0),
+        Field::new("region", rom.region, rom.region.to_string()),
+        Field::new("warnings", warnings.join("; "), warnings),
+        Field::new("corrections", corrections.join("; "), corrections),
+        Field::hash("file_hash", &hashes.full),
+        Field::hash("prg_hash", &hashes.prg),
+        Field::hash("chr_hash", &hashes.chr),
+        Field::hash("prg_chr_hash", &hashes.prg_chr),
+    ]
+}
+
+fn nsf_fields(nsf: &Nsf, raw: &[u8]) -> Vec<Field> {
+    let chips = nsf.expansion_chips();
+    vec![
+        Field::new("format", "NSF", "NSF"),
+        Field::new("version", nsf.version, nsf.version),
+        Field::new("title", &nsf.title, nsf.title.clone()),
+        Field::new("artist", &nsf.artist, nsf.artist.clone()),
+        Field::new("copyright", &nsf.copyright, nsf.copyright.clone()),
+        Field::new("songs", nsf.songs, nsf.songs),
+        Field::new("starting_song", nsf.starting_song, nsf.starting_song),
+        Field::new(
+            "load_address",
+            format!("{:04X}", nsf.load_address),
+            nsf.load_address,
+        ),
+        Field::new(
+            "bankswitched",
+            yes_no(nsf.bankswitch.is_some()),
+            nsf.bankswitch.is_some(),
+        ),
+        Field::new("expansion", chips.join(", "), chips),
+        Field::new("region", nsf.region, nsf.region.to_string()),
+        Field::size("data", nsf.data.len()),
+        Field::hash("file_hash", &Hash::of(&[raw])),
+    ]
+}
+
+fn fds_fields(image: &FdsImage, raw: &[u8]) -> Vec<Field> {
+    vec![
+        Field::new("format", "FDS", "FDS"),
+        Field::new("sides", image.side_count(), image.side_count()),
+        Field::hash("file_hash", &Hash::of(&[raw])),
+    ]
+}
+
+// parses whatever the file turns out to be, the error is ready to print
+fn inspect(path: &Path) -> Result<Vec<Field>, String> {
+    let file = archive::load(path, None).map_err(|err| err.to_string())?;
+    let raw = &file.data;
+    if nsf::is_nsf(raw) {
+        let nsf = Nsf::new(raw).map_err(|err| err.to_string())?;
+        Ok(nsf_fields(&nsf, raw))
+    } else if fds::is_fds(raw) {
+        let image = FdsImage::new(raw).map_err(|err| err.to_string())?;
+        Ok(fds_fields(&image, raw))
+    } else {
+        let rom = Rom::new(raw).map_err(|err| err.to_string())?;
+        Ok(rom_fields(&rom))
+    }
+}
+
+fn to_json(file: &Path, result: &Result<Vec<Field>, String>) -> Value {
+    let mut object = Map::new();
+    object.insert("file".to_string(), file.display().to_string().into());
+    match result {
+        Ok(fields) => {
+            for field in fields {
+                object.insert(field.key.to_string(), field.json.clone());
+            }
+        }
+        Err(err) => {
+            object.insert("error".to_string(), err.clone().into());
+        }
+    }
+    Value::Object(object)
+}
+
+fn print_text(file: &Path, fields: &[Field]) {
+    println!("{}", file.display());
+    for field in fields {
+        if field.text.is_empty() {
+            continue;
+        }
+        println!(
+            "  {:<14} {}",
+            format!("{}:", field.key.replace('_', " ")),
+            field.text
+        );
+    }
+}
+
+// directories are expanded one level, so `info --json roms/` covers a collection
+fn expand(paths: Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut files = vec![];
+    for path in paths {
+        let entries = match std::fs::read_dir(&path) {
+            Ok(entries) => entries,
+            Err(_) => {
+                files.push(path);
+                continue;
+            }
+        };
+        let mut found: Vec<PathBuf> = entries
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .filter(|path| path.is_file())
+            .collect();
+        found.sort();
+        files.extend(found);
+    }
+    files
+}
+
+fn usage(error: &str) -> i32 {
+    eprintln!("error: {}", error);
+    eprintln!("usage: nes_emu info [--json] <file or directory>...");
+    2
+}
+
+// `nes_emu info`: returns the exit code, 1 if any file couldn't be parsed
+pub fn run(args: impl Iterator<Item = String>) -> i32 {
+    let mut as_json = false;
+    let mut paths = vec![];
+    for arg in args {
+        match arg.as_str() {
+            "--json" => as_json = true,
+            _ if arg.starts_with("--") => return usage(&format!("unknown option {}", arg)),
+            _ => paths.push(PathBuf::from(arg)),
+        }
+    }
+    if paths.is_empty() {
+        return usage("missing file");
+    }
+
+    let mut failed = false;
+    for (i, file) in expand(paths).iter().enumerate() {
+        let result = inspect(file);
+        if as_json {
+            // one object per line, easy to feed through jq or grep
+            println!("{}", to_json(file, &result));
+        } else {
+            if i > 0 {
+                println!();
+            }
+            match &result {
+                Ok(fields) => print_text(file, fields),
+                Err(err) => eprintln!("{}: {}", file.display(), err),
+            }
+        }
+        failed |= result.is_err();
+    }
+    if failed {
+        1
+    } else {
+        0
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::test_rom;
+
+    #[test]
+    fn test_rom_json() {
+        let rom = test_rom();
+        let value = to_json(Path::new("test.nes"), &Ok(rom_fields(&rom)));
+        assert_eq!(value["file"], "test.nes");
+        assert_eq!(value["format"], rom.format.to_string());
+        assert_eq!(value["mapper"], rom.mapper);
+        assert_eq!(value["prg_rom"], rom.prg_rom.len());
+        assert_eq!(value["battery"], false);
+        assert_eq!(
+            value["prg_chr_hash"]["crc32"],
+            format!("{:08X}", rom.hashes().prg_chr.crc32)
+        );
+        assert!(value.get("error").is_none());
+    }
+
+    #[test]
+    fn test_not_a_rom() {
+        let path = std::env::temp_dir().join("nes_emu_info_test.txt");
+        std::fs::write(&path, b"just some text, nothing to see here").unwrap();
+        let result = inspect(&path);
+        std::fs::remove_file(&path).unwrap();
+        let err = result.err().unwrap();
+        let value = to_json(&path, &Err(err.clone()));
+        assert_eq!(value["error"], err);
+        assert!(value.get("format").is_none());
+    }
+}
@@ -0,0 +1,100 @@
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+
+// button bits, in the order the controller shifts them out
+pub const BUTTON_A: u8 = 0b0000_0001;
+pub const BUTTON_B: u8 = 0b0000_0010;
+pub const SELECT: u8 = 0b0000_0100;
+pub const START: u8 = 0b0000_1000;
+pub const UP: u8 = 0b0001_0000;
+pub const DOWN: u8 = 0b0010_0000;
+pub const LEFT: u8 = 0b0100_0000;
+pub const RIGHT: u8 = 0b1000_0000;
+
+// a standard controller on $4016: writing 1 then 0 latches the buttons,
+// then each read returns the next one
+#[derive(Default)]
+pub struct Joypad {
+    strobe: bool,
+    index: u8,
+    buttons: u8,
+}
+
+impl Joypad {
+    pub fn new() -> Self {
+        Joypad::default()
+    }
+
+    pub fn set_button(&mut self, button: u8, pressed: bool) {
+        if pressed {
+            self.buttons |= button;
+        } else {
+            self.buttons &= !button;
+        }
+    }
+
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {
+            self.index = 0;
+        }
+    }
+
+    pub fn read(&mut self) -> u8 {
+        let pressed = self.peek();
+        if !self.strobe && self.index <= 7 {
+            self.index += 1;
+        }
+        pressed
+    }
+
+    // the button the next read returns, without moving on
+    pub fn peek(&self) -> u8 {
+        // after all eight buttons an official controller keeps returning 1
+        if self.index > 7 {
+            return 1;
+        }
+        (self.buttons >> self.index) & 1
+    }
+}
+
+impl Snapshot for Joypad {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_u8(self.strobe as u8);
+        w.write_u8(self.index);
+    }
+
+    // the buttons come from whoever is holding the controller, not the state
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        self.strobe = r.read_u8()? != 0;
+        self.index = r.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_read_sequence() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(BUTTON_A, true);
+        joypad.set_button(START, true);
+        joypad.set_button(RIGHT, true);
+        joypad.write(1);
+        joypad.write(0);
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+    }
+
+    #[test]
+    fn test_strobe_held() {
+        let mut joypad = Joypad::new();
+        joypad.set_button(BUTTON_A, true);
+        joypad.write(1);
+        assert_eq!(joypad.read(), 1);
+        assert_eq!(joypad.read(), 1);
+        joypad.set_button(BUTTON_A, false);
+        assert_eq!(joypad.read(), 0);
+    }
+}
@@ -0,0 +1,29 @@
+// The emulator core: everything here builds without SDL or any other native
+// library. `Console` is the entry point for running games; the modules stay
+// public for tools that need the CPU, bus or ROM parsing on their own.
+
+pub mod apu;
+pub mod archive;
+pub mod audio;
+pub mod battery;
+pub mod bus;
+pub mod config;
+pub mod console;
+pub mod cpu;
+pub mod fds;
+pub mod frontend;
+pub mod info;
+pub mod joypad;
+pub mod nsf;
+pub mod opcodes;
+pub mod patch;
+pub mod ppu;
+pub mod region;
+pub mod render;
+pub mod rom;
+pub mod romdb;
+pub mod state;
+pub mod trace;
+pub mod wav;
+
+pub use console::Console;
@@ -1,231 +1,263 @@
-mod bus;
-mod cpu;
-mod opcodes;
-mod rom;
-// mod snake_game;
-
-use bus::Bus;
-use cpu::Mem;
-use cpu::CPU;
-use rand::Rng;
-use rom::Rom;
+use nes_emu::audio::{AudioQueue, RingBuffer};
+use nes_emu::config::{self, Config};
+use nes_emu::frontend::{self, Action, Pacer, Saves, Video, AUDIO_DEVICE_SAMPLES};
+use nes_emu::trace::trace;
+use nes_emu::{info, joypad, nsf, Console};
+use sdl2::audio::{AudioCallback, AudioDevice, AudioSpecDesired};
 use sdl2::event::Event;
 use sdl2::keyboard::Keycode;
-use sdl2::pixels::Color;
 use sdl2::pixels::PixelFormatEnum;
 use sdl2::EventPump;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
 
 fn main() {
+    if std::env::args().nth(1).as_deref() == Some("info") {
+        std::process::exit(info::run(std::env::args().skip(2)));
+    }
+    let config = config::parse(std::env::args().skip(1)).unwrap_or_else(|err| usage(&err));
+    let file = frontend::load_file(&config).unwrap_or_else(|err| fail(&err));
+    if nsf::is_nsf(&file.data) {
+        // a wav is written as fast as it can be, there's nothing to hear
+        let mut audio = match config.wav {
+            Some(_) => None,
+            None => sdl2::init()
+                .map_err(|err| eprintln!("warning: no audio: {}", err))
+                .ok()
+                .and_then(|sdl_context| open_audio(&sdl_context, &config)),
+        };
+        let sample_rate = audio
+            .as_ref()
+            .map_or(config.sample_rate, |audio| audio.sample_rate);
+        let queue = audio.as_mut().map(|audio| &mut audio.queue);
+        if let Err(err) = frontend::play_nsf(&config, &file.data, sample_rate, queue) {
+            fail(&err);
+        }
+        return;
+    }
+
+    let video = Video::new(&config).unwrap_or_else(|err| fail(&err));
+    let (window_width, window_height) = video.window_size();
+    let (picture_width, picture_height) = video.picture_size();
+
     // init sdl2
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
     let window = video_subsystem
-        .window("Snake game", (32.0 * 10.0) as u32, (32.0 * 10.0) as u32)
+        .window(
+            &format!("nes_emu - {}", file.name),
+            window_width,
+            window_height,
+        )
         .position_centered()
         .build()
         .unwrap();
 
     let mut canvas = window.into_canvas().present_vsync().build().unwrap();
     let mut event_pump = sdl_context.event_pump().unwrap();
-    canvas.set_scale(10.0, 10.0).unwrap();
+    let mut audio = open_audio(&sdl_context, &config);
+    if let Some(scale) = video.hardware_scale() {
+        canvas.set_scale(scale as f32, scale as f32).unwrap();
+    }
 
     let creator = canvas.texture_creator();
     let mut texture = creator
-        .create_texture_target(PixelFormatEnum::RGB24, 32, 32)
+        .create_texture_target(
+            PixelFormatEnum::RGB24,
+            picture_width as u32,
+            picture_height as u32,
+        )
         .unwrap();
 
-    let args: Vec<String> = std::env::args().collect();
-    let filename = &args[1];
-    let file = std::fs::read(filename).expect("failed to open file");
-    let game_rom = Rom::new(&file).expect("failed to load rom");
-    let bus = Bus::new(game_rom);
-    let mut cpu = CPU::new(bus);
-    cpu.reset();
-    // hack for nestest since it doesn't start at the addr in 0xFFFC
-    if filename.ends_with("nestest.nes") {
-        cpu.program_counter = 0xC000;
+    let mut console = frontend::load_console(&config, &file).unwrap_or_else(|err| fail(&err));
+    console.set_sample_rate(
+        audio
+            .as_ref()
+            .map_or(config.sample_rate, |audio| audio.sample_rate),
+    );
+    let frame_rate = console.bus().rom.region.timing().frame_rate();
+    let mut saves = Saves::new(&config, &mut console, file.data);
+    let state_path = frontend::state_path(&config.rom_path);
+
+    let refresh_rate = canvas
+        .window()
+        .display_index()
+        .and_then(|index| video_subsystem.current_display_mode(index))
+        .ok()
+        .map(|mode| mode.refresh_rate as f64)
+        .filter(|&rate| rate > 0.0);
+    let mut pacer = Pacer::new(frame_rate, refresh_rate);
+    loop {
+        let quit = console.is_halted()
+            || handle_user_input(
+                &mut console,
+                &mut event_pump,
+                &state_path,
+                audio.as_mut().map(|audio| &mut audio.queue),
+            );
+        saves.tick(&mut console, quit);
+        if quit {
+            std::process::exit(0);
+        }
+
+        for _ in 0..pacer.frames_due() {
+            if config.trace {
+                console.run_frame_with_callback(|cpu| println!("{}", trace(cpu)));
+            } else {
+                console.run_frame();
+            }
+            let samples = console.take_samples();
+            if let Some(audio) = &mut audio {
+                audio.queue.push(&samples);
+            }
+            if console.is_halted() {
+                println!("BRK at {:04X}, stopping", console.cpu().program_counter);
+                break;
+            }
+        }
+
+        let rgb = video.render(console.frame());
+        texture.update(None, &rgb, picture_width * 3).unwrap();
+        canvas.copy(&texture, None, None).unwrap();
+        // waits for vsync
+        canvas.present();
     }
+}
+
+fn usage(error: &str) -> ! {
+    eprintln!("error: {}", error);
+    eprintln!("{}", config::usage());
+    std::process::exit(2);
+}
 
-    let mut screen_state = [0 as u8; 32 * 3 * 32];
-    let mut rng = rand::thread_rng();
+fn fail(error: &str) -> ! {
+    eprintln!("{}", error);
+    std::process::exit(1);
+}
 
-    // run the game cycle
-    cpu.run_with_callback(move |cpu| {
-        println!("{}", trace(cpu));
-        handle_user_input(cpu, &mut event_pump);
-        cpu.mem_write(0xfe, rng.gen_range(1..16));
+// runs on SDL's audio thread, playing what AudioQueue has buffered
+struct Speaker {
+    buffer: Arc<Mutex<RingBuffer>>,
+}
 
-        if read_screen_state(cpu, &mut screen_state) {
-            texture.update(None, &screen_state, 32 * 3).unwrap();
-            canvas.copy(&texture, None, None).unwrap();
-            canvas.present();
-        }
+impl AudioCallback for Speaker {
+    type Channel = f32;
 
-        ::std::thread::sleep(std::time::Duration::new(0, 70_000));
-    });
+    fn callback(&mut self, out: &mut [f32]) {
+        self.buffer.lock().unwrap().pop_into(out);
+    }
+}
+
+struct Audio {
+    // playback stops when this is dropped
+    _device: AudioDevice<Speaker>,
+    queue: AudioQueue,
+    // what the sound card gave us, which may not be what was asked for
+    sample_rate: u32,
 }
 
-fn read_screen_state(cpu: &CPU, frame: &mut [u8; 32 * 3 * 32]) -> bool {
-    let mut frame_idx = 0;
-    let mut update = false;
-    for i in 0x0200..0x600 {
-        let color_idx = cpu.mem_read(i as u16);
-        let (b1, b2, b3) = color(color_idx).rgb();
-        if frame[frame_idx] != b1 || frame[frame_idx + 1] != b2 || frame[frame_idx + 2] != b3 {
-            frame[frame_idx] = b1;
-            frame[frame_idx + 1] = b2;
-            frame[frame_idx + 2] = b3;
-            update = true;
+// None with --no-audio, or when there's no sound card to play on
+fn open_audio(sdl_context: &sdl2::Sdl, config: &Config) -> Option<Audio> {
+    if !config.audio {
+        return None;
+    }
+    let desired = AudioSpecDesired {
+        freq: Some(config.sample_rate as i32),
+        channels: Some(1),
+        samples: Some(AUDIO_DEVICE_SAMPLES),
+    };
+    let mut queue = None;
+    let result = sdl_context.audio().and_then(|subsystem| {
+        subsystem.open_playback(None, &desired, |spec| {
+            let audio_queue = frontend::audio_queue(spec.freq as u32, config.volume);
+            let speaker = Speaker {
+                buffer: audio_queue.buffer(),
+            };
+            queue = Some(audio_queue);
+            speaker
+        })
+    });
+    match (result, queue) {
+        (Ok(device), Some(queue)) => {
+            device.resume();
+            let sample_rate = device.spec().freq as u32;
+            Some(Audio {
+                _device: device,
+                queue,
+                sample_rate,
+            })
+        }
+        (Err(err), _) => {
+            eprintln!("warning: no audio: {}", err);
+            None
         }
-        frame_idx += 3;
+        (Ok(_), None) => None,
     }
-    update
 }
 
-fn handle_user_input(cpu: &mut CPU, event_pump: &mut EventPump) {
+// returns true when the user asked to quit
+fn handle_user_input(
+    console: &mut Console,
+    event_pump: &mut EventPump,
+    state_path: &Path,
+    mut audio: Option<&mut AudioQueue>,
+) -> bool {
     for event in event_pump.poll_iter() {
         match event {
             Event::Quit { .. }
             | Event::KeyDown {
                 keycode: Some(Keycode::Escape),
                 ..
-            } => std::process::exit(0),
+            } => return true,
             Event::KeyDown {
-                keycode: Some(Keycode::W),
-                ..
+                keycode: Some(key), ..
             } => {
-                cpu.mem_write(0xff, 0x77);
+                if let Some(action) = key_action(key) {
+                    match frontend::perform(action, console, state_path, audio.as_deref_mut()) {
+                        Ok(Some(message)) => println!("{}", message),
+                        Ok(None) => {}
+                        Err(err) => eprintln!("{}", err),
+                    }
+                } else if let Some(button) = key_button(key) {
+                    console.set_button(button, true);
+                }
             }
-            Event::KeyDown {
-                keycode: Some(Keycode::S),
-                ..
+            Event::KeyUp {
+                keycode: Some(key), ..
             } => {
-                cpu.mem_write(0xff, 0x73);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::A),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x61);
-            }
-            Event::KeyDown {
-                keycode: Some(Keycode::D),
-                ..
-            } => {
-                cpu.mem_write(0xff, 0x64);
+                if let Some(button) = key_button(key) {
+                    console.set_button(button, false);
+                }
             }
             _ => { /* do nothing */ }
         }
     }
+    false
 }
 
-fn color(byte: u8) -> Color {
-    match byte {
-        0 => sdl2::pixels::Color::BLACK,
-        1 => sdl2::pixels::Color::WHITE,
-        2 | 9 => sdl2::pixels::Color::GREY,
-        3 | 10 => sdl2::pixels::Color::RED,
-        4 | 11 => sdl2::pixels::Color::GREEN,
-        5 | 12 => sdl2::pixels::Color::BLUE,
-        6 | 13 => sdl2::pixels::Color::MAGENTA,
-        7 | 14 => sdl2::pixels::Color::YELLOW,
-        _ => sdl2::pixels::Color::CYAN,
+fn key_action(key: Keycode) -> Option<Action> {
+    match key {
+        Keycode::F5 => Some(Action::SaveState),
+        Keycode::F9 => Some(Action::LoadState),
+        Keycode::F6 => Some(Action::ToggleDisk),
+        Keycode::F7 => Some(Action::FlipDisk),
+        Keycode::M => Some(Action::ToggleMute),
+        Keycode::Minus => Some(Action::VolumeDown),
+        Keycode::Equals => Some(Action::VolumeUp),
+        _ => None,
     }
 }
 
-fn trace(cpu: &CPU) -> String {
-    format!(
-        "{:04X}  {}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
-        cpu.program_counter,
-        opcodes::format_instruction(cpu),
-        cpu.register_a,
-        cpu.register_x,
-        cpu.register_y,
-        cpu.status,
-        cpu.stack_pointer
-    )
-}
-
-#[cfg(test)]
-mod test {
-    use super::*;
-    use crate::bus::Bus;
-    use crate::rom::test::test_rom;
-
-    #[test]
-    fn test_format_trace() {
-        let mut bus = Bus::new(test_rom());
-        bus.mem_write(100, 0xa2);
-        bus.mem_write(101, 0x01);
-        bus.mem_write(102, 0xca);
-        bus.mem_write(103, 0x88);
-        bus.mem_write(104, 0xCD);
-        bus.mem_write(105, 0xF5);
-        bus.mem_write(106, 0xC5);
-        bus.mem_write(107, 0xB0);
-        bus.mem_write(108, 0x04);
-        bus.mem_write(109, 0x00);
-        bus.mem_write(110, 0x00);
-        bus.mem_write(111, 0x00);
-        bus.mem_write(112, 0x00);
-
-        let mut cpu = CPU::new(bus);
-        cpu.program_counter = 0x64;
-        cpu.register_a = 1;
-        cpu.register_x = 2;
-        cpu.register_y = 3;
-        let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
-            result.push(trace(cpu));
-        });
-        assert_eq!(
-            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
-            result[0]
-        );
-        assert_eq!(
-            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
-            result[1]
-        );
-        assert_eq!(
-            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
-            result[2]
-        );
-        assert_eq!(
-            "0068  CD F5 C5  CMP $C5F5                       A:01 X:00 Y:02 P:24 SP:FD",
-            result[3]
-        );
-        assert_eq!(
-            "006B  B0 04     BCS $0071                       A:01 X:00 Y:02 P:27 SP:FD",
-            result[4]
-        );
-        assert_eq!(cpu.program_counter, 0x71);
-    }
-
-    #[test]
-    fn test_format_mem_access() {
-        let mut bus = Bus::new(test_rom());
-        // ORA ($33), Y
-        bus.mem_write(100, 0x11);
-        bus.mem_write(101, 0x33);
-
-        //data
-        bus.mem_write(0x33, 00);
-        bus.mem_write(0x34, 04);
-
-        //target cell
-        bus.mem_write(0x400, 0xAA);
-
-        let mut cpu = CPU::new(bus);
-        cpu.reset();
-        cpu.program_counter = 0x64;
-        cpu.register_y = 0;
-        let mut result: Vec<String> = vec![];
-        cpu.run_with_callback(|cpu| {
-            result.push(trace(cpu));
-        });
-        assert_eq!(
-            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
-            result[0]
-        );
+fn key_button(key: Keycode) -> Option<u8> {
+    match key {
+        Keycode::Up => Some(joypad::UP),
+        Keycode::Down => Some(joypad::DOWN),
+        Keycode::Left => Some(joypad::LEFT),
+        Keycode::Right => Some(joypad::RIGHT),
+        Keycode::X => Some(joypad::BUTTON_A),
+        Keycode::Z => Some(joypad::BUTTON_B),
+        Keycode::Return => Some(joypad::START),
+        Keycode::RShift | Keycode::Space => Some(joypad::SELECT),
+        _ => None,
     }
 }
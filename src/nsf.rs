@@ -0,0 +1,473 @@
+use crate::apu::Apu;
+use crate::bus::{Board, Bus};
+use crate::cpu::{Mem, CPU};
+use crate::region::Region;
+use crate::rom::{Rom, RomFormat};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use std::fmt;
+use std::time::Duration;
+
+pub const NSF_TAG: &[u8] = b"NESM\x1a";
+const HEADER_SIZE: usize = 0x80;
+const BANK_SIZE: usize = 0x1000;
+
+// PLAY rates the spec says to assume when the header gives 0
+const DEFAULT_NTSC_SPEED: u16 = 16639;
+const DEFAULT_PAL_SPEED: u16 = 19997;
+
+const EXPANSION_CHIPS: [&str; 6] = ["VRC6", "VRC7", "FDS", "MMC5", "Namco 163", "Sunsoft 5B"];
+
+#[derive(Debug, PartialEq)]
+pub enum NsfError {
+    TooShort { len: usize },
+    BadMagic,
+    NoSongs,
+    NoData,
+    BadLoadAddress(u16),
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NsfError::TooShort { len } => write!(
+                f,
+                "file is too short to be an NSF ({} bytes, header needs {})",
+                len, HEADER_SIZE
+            ),
+            NsfError::BadMagic => write!(f, "Invalid NSF file"),
+            NsfError::NoSongs => write!(f, "NSF header declares no songs"),
+            NsfError::NoData => write!(f, "NSF has no program data"),
+            NsfError::BadLoadAddress(addr) => {
+                write!(f, "NSF load address {:04X} is outside $8000-$FFFF", addr)
+            }
+        }
+    }
+}
+
+impl std::error::Error for NsfError {}
+
+pub struct Nsf {
+    pub version: u8,
+    pub songs: u8,
+    // 1-based, like the header
+    pub starting_song: u8,
+    pub load_address: u16,
+    pub init_address: u16,
+    pub play_address: u16,
+    pub title: String,
+    pub artist: String,
+    pub copyright: String,
+    // microseconds between PLAY calls
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    // initial $5FF8-$5FFF values, None if the rip doesn't bankswitch
+    pub bankswitch: Option<[u8; 8]>,
+    pub region: Region,
+    pub expansion: u8,
+    pub data: Vec<u8>,
+}
+
+pub fn is_nsf(raw: &[u8]) -> bool {
+    raw.starts_with(NSF_TAG)
+}
+
+fn read_u16(raw: &[u8], pos: usize) -> u16 {
+    u16::from_le_bytes([raw[pos], raw[pos + 1]])
+}
+
+// header strings are NUL padded and usually ASCII, "<?>" means unknown
+fn read_string(raw: &[u8]) -> String {
+    let text = raw.split(|&b| b == 0).next().unwrap_or_default();
+    String::from_utf8_lossy(text).trim().to_string()
+}
+
+impl Nsf {
+    pub fn new(raw: &[u8]) -> Result<Nsf, NsfError> {
+        if raw.len() < HEADER_SIZE {
+            return Err(NsfError::TooShort { len: raw.len() });
+        }
+        if !is_nsf(raw) {
+            return Err(NsfError::BadMagic);
+        }
+
+        let version = raw[5];
+        let songs = raw[6];
+        if songs == 0 {
+            return Err(NsfError::NoSongs);
+        }
+        let load_address = read_u16(raw, 0x08);
+        if load_address < 0x8000 {
+            return Err(NsfError::BadLoadAddress(load_address));
+        }
+
+        let bankswitch: [u8; 8] = raw[0x70..0x78].try_into().unwrap();
+        let region = match raw[0x7A] & 0b11 {
+            0b00 => Region::Ntsc,
+            0b01 => Region::Pal,
+            _ => Region::Multi,
+        };
+
+        // NSF2 may follow the program with metadata, the header says where it ends
+        let mut data = &raw[HEADER_SIZE..];
+        let data_len = u32::from_le_bytes([raw[0x7D], raw[0x7E], raw[0x7F], 0]) as usize;
+        if version >= 2 && data_len != 0 && data_len < data.len() {
+            data = &data[..data_len];
+        }
+        if data.is_empty() {
+            return Err(NsfError::NoData);
+        }
+
+        let speed = |pos, default| match read_u16(raw, pos) {
+            0 => default,
+            speed => speed,
+        };
+
+        Ok(Nsf {
+            version,
+            songs,
+            starting_song: raw[7].clamp(1, songs),
+            load_address,
+            init_address: read_u16(raw, 0x0A),
+            play_address: read_u16(raw, 0x0C),
+            title: read_string(&raw[0x0E..0x2E]),
+            artist: read_string(&raw[0x2E..0x4E]),
+            copyright: read_string(&raw[0x4E..0x6E]),
+            ntsc_speed: speed(0x6E, DEFAULT_NTSC_SPEED),
+            pal_speed: speed(0x78, DEFAULT_PAL_SPEED),
+            bankswitch: bankswitch.iter().any(|&b| b != 0).then_some(bankswitch),
+            region,
+            expansion: raw[0x7B],
+            data: data.to_vec(),
+        })
+    }
+
+    pub fn expansion_chips(&self) -> Vec<&'static str> {
+        EXPANSION_CHIPS
+            .iter()
+            .enumerate()
+            .filter(|(bit, _)| self.expansion & (1 << bit) != 0)
+            .map(|(_, &name)| name)
+            .collect()
+    }
+
+    // the program laid out in 4K banks, so bank n starts at n * $1000
+    pub fn prg_image(&self) -> Vec<u8> {
+        let padding = match self.bankswitch {
+            Some(_) => self.load_address as usize & (BANK_SIZE - 1),
+            None => self.load_address as usize - 0x8000,
+        };
+        let mut image = vec![0; padding];
+        image.extend(&self.data);
+        image.resize(image.len().div_ceil(BANK_SIZE) * BANK_SIZE, 0);
+        image
+    }
+
+    pub fn to_rom(&self) -> Rom {
+        Rom::from_prg(RomFormat::Nsf, self.prg_image(), self.region)
+    }
+}
+
+// The driver lives in the unused $4100 area: JSR INIT or JSR PLAY, then spin
+// in place until the player starts the next call.
+const DRIVER: u16 = 0x4100;
+const DRIVER_SIZE: usize = 12;
+const IDLE: u16 = DRIVER + 3;
+const PLAY: u16 = DRIVER + 6;
+const BANK_REGISTERS: u16 = 0x5FF8;
+
+pub struct NsfBoard {
+    banks: [u8; 8],
+    initial_banks: [u8; 8],
+    driver: [u8; DRIVER_SIZE],
+}
+
+impl NsfBoard {
+    pub fn new(nsf: &Nsf) -> Self {
+        let [init_lo, init_hi] = nsf.init_address.to_le_bytes();
+        let [play_lo, play_hi] = nsf.play_address.to_le_bytes();
+        let [idle_lo, idle_hi] = IDLE.to_le_bytes();
+        let initial_banks = nsf.bankswitch.unwrap_or([0, 1, 2, 3, 4, 5, 6, 7]);
+        NsfBoard {
+            banks: initial_banks,
+            initial_banks,
+            driver: [
+                0x20, init_lo, init_hi, // JSR init
+                0x4C, idle_lo, idle_hi, // JMP idle
+                0x20, play_lo, play_hi, // JSR play
+                0x4C, idle_lo, idle_hi, // JMP idle
+            ],
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.banks = self.initial_banks;
+    }
+
+    pub fn read_driver(&self, addr: u16) -> Option<u8> {
+        let offset = addr.checked_sub(DRIVER)? as usize;
+        self.driver.get(offset).copied()
+    }
+
+    // anything outside $5FF8-$5FFF is ignored
+    pub fn write_bank(&mut self, addr: u16, data: u8) {
+        if let Some(bank @ 0..=7) = addr.checked_sub(BANK_REGISTERS) {
+            self.banks[bank as usize] = data;
+        }
+    }
+
+    pub fn read_prg(&self, prg: &[u8], addr: u16) -> u8 {
+        let addr = (addr - 0x8000) as usize;
+        let bank = self.banks[addr / BANK_SIZE] as usize;
+        // banks past the end of the rip read as open bus, call it 0
+        prg.get(bank * BANK_SIZE + addr % BANK_SIZE)
+            .copied()
+            .unwrap_or(0)
+    }
+}
+
+impl Snapshot for NsfBoard {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.banks);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_into("NSF banks", &mut self.banks)
+    }
+}
+
+pub struct NsfPlayer {
+    pub cpu: CPU,
+    pub nsf: Nsf,
+    song: u8,
+    // the rate the rip is played at, never Multi
+    region: Region,
+    // cycles between PLAY calls, and what's left of the current period
+    play_cycles: f64,
+    budget: f64,
+}
+
+impl NsfPlayer {
+    pub fn new(nsf: Nsf, sample_rate: u32) -> Self {
+        // dual region rips get NTSC, it's what they're nearly always written for
+        let region = match nsf.region {
+            Region::Multi => Region::Ntsc,
+            region => region,
+        };
+        let speed = match region {
+            Region::Pal | Region::Dendy => nsf.pal_speed,
+            _ => nsf.ntsc_speed,
+        };
+
+        let mut rom = nsf.to_rom();
+        rom.region = region;
+        let mut bus = Bus::new(rom);
+        bus.board = Board::Nsf(NsfBoard::new(&nsf));
+        bus.apu = Apu::new(&bus.timing, sample_rate);
+
+        NsfPlayer {
+            cpu: CPU::new(bus),
+            song: nsf.starting_song,
+            nsf,
+            region,
+            play_cycles: region.cpu_clock() * speed as f64 / 1_000_000.0,
+            budget: 0.0,
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn play_period(&self) -> Duration {
+        Duration::from_secs_f64(self.play_cycles / self.region.cpu_clock())
+    }
+
+    // resets the machine and runs INIT for a 1-based song number, returns
+    // false if INIT didn't come back within a second
+    pub fn start_song(&mut self, song: u8) -> bool {
+        self.song = song.clamp(1, self.nsf.songs);
+        let bus = &mut self.cpu.bus;
+        bus.cpu_vram = [0; 2048];
+        bus.prg_ram = [0; 0x2000];
+        if let Board::Nsf(board) = &mut bus.board {
+            board.reset();
+        }
+        for addr in 0x4000..=0x4013 {
+            bus.mem_write(addr, 0);
+        }
+        bus.mem_write(0x4015, 0x0F);
+        bus.mem_write(0x4017, 0x40);
+
+        self.cpu.register_a = self.song - 1;
+        self.cpu.register_x = (self.region == Region::Pal) as u8;
+        self.cpu.register_y = 0;
+        self.cpu.status = 0b0010_0100;
+        self.cpu.stack_pointer = 0xFD;
+        self.cpu.program_counter = DRIVER;
+        self.budget = 0.0;
+
+        let mut cycles = 0;
+        while !self.is_idle() && cycles < self.region.cpu_clock() as usize {
+            cycles += self.step();
+        }
+        self.cpu.bus.apu.take_samples();
+        self.is_idle()
+    }
+
+    // runs one PLAY period; if the last PLAY hasn't returned it keeps going
+    pub fn run_frame(&mut self) {
+        if self.is_idle() {
+            self.cpu.program_counter = PLAY;
+        }
+        self.budget += self.play_cycles;
+        while self.budget > 0.0 {
+            if self.is_idle() {
+                // nothing left to run this period, just let the clock catch up
+                let cycles = self.budget.ceil() as usize;
+                self.cpu.bus.tick(cycles);
+                self.budget -= cycles as f64;
+                break;
+            }
+            self.budget -= self.step() as f64;
+        }
+    }
+
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        self.cpu.bus.apu.take_samples()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.cpu.program_counter == IDLE
+    }
+
+    fn step(&mut self) -> usize {
+        match self.cpu.step() {
+            Some(cycles) => cycles,
+            None => {
+                // a BRK can't go anywhere useful here, treat it as a return
+                self.cpu.program_counter = IDLE;
+                self.cpu.bus.tick(7);
+                7
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub mod test {
+    use super::*;
+
+    pub fn nsf_file(load: u16, init: u16, play: u16, banks: [u8; 8], data: &[u8]) -> Vec<u8> {
+        let mut raw = NSF_TAG.to_vec();
+        raw.extend([1, 3, 2]);
+        raw.extend(load.to_le_bytes());
+        raw.extend(init.to_le_bytes());
+        raw.extend(play.to_le_bytes());
+        let mut text = [0u8; 96];
+        text[..4].copy_from_slice(b"Song");
+        text[32..38].copy_from_slice(b"Artist");
+        text[64..68].copy_from_slice(b"2024");
+        raw.extend(text);
+        raw.extend(0u16.to_le_bytes());
+        raw.extend(banks);
+        raw.extend(0u16.to_le_bytes());
+        raw.extend([0, 0b0000_0101, 0, 0, 0, 0]);
+        raw.extend(data);
+        raw
+    }
+
+    #[test]
+    fn test_header() {
+        let nsf = Nsf::new(&nsf_file(0x8000, 0x8000, 0x8003, [0; 8], &[0x60])).unwrap();
+        assert_eq!(nsf.version, 1);
+        assert_eq!(nsf.songs, 3);
+        assert_eq!(nsf.starting_song, 2);
+        assert_eq!(nsf.init_address, 0x8000);
+        assert_eq!(nsf.play_address, 0x8003);
+        assert_eq!(nsf.title, "Song");
+        assert_eq!(nsf.artist, "Artist");
+        assert_eq!(nsf.copyright, "2024");
+        assert_eq!(nsf.ntsc_speed, DEFAULT_NTSC_SPEED);
+        assert_eq!(nsf.bankswitch, None);
+        assert_eq!(nsf.region, Region::Ntsc);
+        assert_eq!(nsf.expansion_chips(), vec!["VRC6", "FDS"]);
+        assert_eq!(nsf.data, vec![0x60]);
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            Nsf::new(&[0; 16]).err(),
+            Some(NsfError::TooShort { len: 16 })
+        );
+        let mut raw = nsf_file(0x8000, 0x8000, 0x8000, [0; 8], &[0x60]);
+        raw[0] = b'X';
+        assert_eq!(Nsf::new(&raw).err(), Some(NsfError::BadMagic));
+        let raw = nsf_file(0x6000, 0x8000, 0x8000, [0; 8], &[0x60]);
+        assert_eq!(Nsf::new(&raw).err(), Some(NsfError::BadLoadAddress(0x6000)));
+        let raw = nsf_file(0x8000, 0x8000, 0x8000, [0; 8], &[]);
+        assert_eq!(Nsf::new(&raw).err(), Some(NsfError::NoData));
+    }
+
+    #[test]
+    fn test_prg_image() {
+        let nsf = Nsf::new(&nsf_file(0x8010, 0x8010, 0x8010, [0; 8], &[1, 2])).unwrap();
+        let image = nsf.prg_image();
+        assert_eq!(image.len(), BANK_SIZE);
+        assert_eq!(image[0x10..0x12], [1, 2]);
+
+        // bankswitched rips only pad within the first bank
+        let banks = [0, 0, 0, 0, 0, 0, 0, 1];
+        let nsf = Nsf::new(&nsf_file(0xF010, 0x8010, 0x8010, banks, &[1, 2])).unwrap();
+        assert_eq!(nsf.prg_image()[0x10..0x12], [1, 2]);
+    }
+
+    #[test]
+    fn test_bankswitching() {
+        let mut data = vec![0xAA; BANK_SIZE];
+        data.extend(vec![0xBB; BANK_SIZE]);
+        let nsf = Nsf::new(&nsf_file(
+            0x8000,
+            0x8000,
+            0x8000,
+            [0, 1, 0, 0, 0, 0, 0, 0],
+            &data,
+        ))
+        .unwrap();
+        let prg = nsf.prg_image();
+        let mut board = NsfBoard::new(&nsf);
+        assert_eq!(board.read_prg(&prg, 0x8000), 0xAA);
+        assert_eq!(board.read_prg(&prg, 0x9000), 0xBB);
+        board.write_bank(0x5FF8, 1);
+        board.write_bank(0x5FF7, 1);
+        assert_eq!(board.read_prg(&prg, 0x8000), 0xBB);
+        assert_eq!(board.read_prg(&prg, 0xF000), 0xAA);
+        board.write_bank(0x5FFF, 9);
+        assert_eq!(board.read_prg(&prg, 0xF000), 0);
+    }
+
+    #[test]
+    fn test_player_calls_init_and_play() {
+        let code = [
+            0x85, 0x10, // init: STA $10
+            0x86, 0x11, // STX $11
+            0x60, // RTS
+            0xE6, 0x12, // play: INC $12
+            0x60, // RTS
+        ];
+        let nsf = Nsf::new(&nsf_file(0x8000, 0x8000, 0x8005, [0; 8], &code)).unwrap();
+        let mut player = NsfPlayer::new(nsf, 44100);
+        assert_eq!(player.song, 2);
+
+        assert!(player.start_song(3));
+        assert_eq!(player.cpu.mem_read(0x10), 2);
+        assert_eq!(player.cpu.mem_read(0x11), 0);
+
+        for _ in 0..5 {
+            player.run_frame();
+        }
+        assert_eq!(player.cpu.mem_read(0x12), 5);
+        // five NTSC frames at 44.1kHz is 3668.9 samples
+        assert!((3668..=3669).contains(&player.take_samples().len()));
+    }
+}
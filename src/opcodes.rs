@@ -1,4 +1,3 @@
-use crate::cpu::Mem;
 use crate::cpu::CPU;
 use lazy_static::lazy_static;
 use std::collections::HashMap;
@@ -46,75 +45,88 @@ impl OpCode {
     }
 }
 
+// only peeks at memory, so tracing doesn't disturb registers that change
+// when read ($2002, $2007, $4015...) or move time along
 pub fn format_instruction(cpu: &CPU) -> String {
-    let opcode = CPU_OPCODES.get(&cpu.mem_read(cpu.program_counter)).unwrap();
+    let peek = |addr: u16| cpu.bus.peek(addr);
+    let peek_u16 = |addr: u16| u16::from_le_bytes([peek(addr), peek(addr.wrapping_add(1))]);
+    // zero page pointers wrap round within the zero page
+    let peek_pointer =
+        |ptr: u8| u16::from_le_bytes([peek(ptr as u16), peek(ptr.wrapping_add(1) as u16)]);
+
+    let opcode = CPU_OPCODES.get(&peek(cpu.program_counter)).unwrap();
     let name = opcode.name;
-    let address = cpu.mem_read(cpu.program_counter + 1);
+    let address = peek(cpu.program_counter + 1);
     let operand = match opcode.mode {
         AddressingMode::Immediate => format!("#${:02X}", address),
         AddressingMode::ZeroPage => {
-            format!("${:02X} = {:02X}", address, cpu.mem_read(address as u16))
+            format!("${:02X} = {:02X}", address, peek(address as u16))
         }
         AddressingMode::ZeroPage_X => {
-            format!("${:02X},X = {:02X}", address, cpu.mem_read(address as u16))
+            format!("${:02X},X = {:02X}", address, peek(address as u16))
         }
         AddressingMode::ZeroPage_Y => {
-            format!("${:02X},Y = {:02X}", address, cpu.mem_read(address as u16))
+            format!("${:02X},Y = {:02X}", address, peek(address as u16))
         }
         AddressingMode::Absolute => {
-            let addr = cpu.get_operand_address(&opcode.mode);
-            // format!("${:04X} = {:02X}", addr, cpu.mem_read(addr))
+            let addr = peek_u16(cpu.program_counter + 1);
+            // format!("${:04X} = {:02X}", addr, peek(addr))
             format!("${:04X}", addr)
         }
         AddressingMode::Absolute_X => format!(
             "${:02X}{:02X},X",
-            cpu.mem_read(cpu.program_counter + 2),
-            cpu.mem_read(cpu.program_counter + 1)
+            peek(cpu.program_counter + 2),
+            peek(cpu.program_counter + 1)
         ),
         AddressingMode::Absolute_Y => format!(
             "${:02X}{:02X},Y",
-            cpu.mem_read(cpu.program_counter + 2),
-            cpu.mem_read(cpu.program_counter + 1)
+            peek(cpu.program_counter + 2),
+            peek(cpu.program_counter + 1)
         ),
         AddressingMode::Indirect => {
-            let op_addr = cpu.get_operand_address(&opcode.mode);
+            let ptr = peek_u16(cpu.program_counter + 1);
+            let op_addr = u16::from_le_bytes([peek(ptr), peek(ptr.wrapping_add(1))]);
             format!(
                 "(${:02X}) = {:04X} @ {:04X} = {:02X}",
-                cpu.mem_read(cpu.program_counter + 1),
+                address,
                 op_addr,
                 op_addr,
-                cpu.mem_read(op_addr)
+                peek(op_addr)
             )
         }
         AddressingMode::Indirect_X => {
-            let op_addr = cpu.get_operand_address(&opcode.mode);
+            let op_addr = peek_pointer(address.wrapping_add(cpu.register_x));
             format!(
                 "(${:02X}),X = {:04X} @ {:04X} = {:02X}",
-                cpu.mem_read(cpu.program_counter + 1),
+                address,
                 op_addr,
                 op_addr,
-                cpu.mem_read(op_addr)
+                peek(op_addr)
             )
         }
         AddressingMode::Indirect_Y => {
-            let op_addr = cpu.get_operand_address(&opcode.mode);
+            let base = peek_pointer(address);
+            let op_addr = base.wrapping_add(cpu.register_y as u16);
             format!(
                 "(${:02X}),Y = {:04X} @ {:04X} = {:02X}",
-                cpu.mem_read(cpu.program_counter + 1),
-                op_addr,
+                address,
+                base,
                 op_addr,
-                cpu.mem_read(op_addr)
+                peek(op_addr)
             )
         }
         AddressingMode::Relative => {
             // +2 for opcode length
-            let op_addr = cpu.get_operand_address(&opcode.mode) + 2;
+            let op_addr = cpu
+                .program_counter
+                .wrapping_add(address as i8 as u16)
+                .wrapping_add(2);
             format!("${:04X}", op_addr)
         }
         AddressingMode::NoneAddressing => "".to_string(),
     };
     let full_opcode = (0..opcode.length)
-        .map(|i| format!("{:02X}", cpu.mem_read(cpu.program_counter + i)))
+        .map(|i| format!("{:02X}", peek(cpu.program_counter + i)))
         .collect::<Vec<String>>()
         .join(" ");
     format!("{:<8} {:>4} {:<26}", full_opcode, name, operand)
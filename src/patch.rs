@@ -0,0 +1,506 @@
+use std::fmt;
+use std::path::{Path, PathBuf};
+
+// soft-patching: patches are applied to the raw file bytes before they reach
+// Rom::new, so the files on disk stay untouched
+
+const IPS_MAGIC: &[u8] = b"PATCH";
+const IPS_EOF: &[u8] = b"EOF";
+const UPS_MAGIC: &[u8] = b"UPS1";
+const BPS_MAGIC: &[u8] = b"BPS1";
+// source, target and patch CRC32s at the end of UPS and BPS files
+const FOOTER_SIZE: usize = 12;
+
+// refuse to allocate absurd outputs for corrupt size fields
+const MAX_TARGET_SIZE: usize = 64 * 1024 * 1024;
+
+const BPS_OUT_OF_BOUNDS: PatchError = PatchError::OutOfBounds("BPS");
+
+// checked in this order when looking for a patch next to the ROM
+const PATCH_EXTENSIONS: [&str; 3] = ["bps", "ups", "ips"];
+
+#[derive(Debug, PartialEq)]
+pub enum PatchError {
+    UnknownFormat,
+    Truncated(&'static str),
+    ChecksumMismatch {
+        what: &'static str,
+        expected: u32,
+        actual: u32,
+    },
+    SourceSize {
+        expected: usize,
+        actual: usize,
+    },
+    OutOfBounds(&'static str),
+}
+
+impl fmt::Display for PatchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PatchError::UnknownFormat => write!(f, "not an IPS, UPS or BPS patch"),
+            PatchError::Truncated(format) => write!(f, "{} patch is truncated", format),
+            PatchError::ChecksumMismatch {
+                what,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "{} checksum mismatch: patch expects {:08X}, got {:08X}",
+                what, expected, actual
+            ),
+            PatchError::SourceSize { expected, actual } => write!(
+                f,
+                "patch is for a {} byte file, this one is {} bytes",
+                expected, actual
+            ),
+            PatchError::OutOfBounds(format) => {
+                write!(f, "{} patch reads or writes out of bounds", format)
+            }
+        }
+    }
+}
+
+impl std::error::Error for PatchError {}
+
+// picks the format from the patch header
+pub fn apply(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    if patch.starts_with(IPS_MAGIC) {
+        apply_ips(patch, source)
+    } else if patch.starts_with(UPS_MAGIC) {
+        apply_ups(patch, source)
+    } else if patch.starts_with(BPS_MAGIC) {
+        apply_bps(patch, source)
+    } else {
+        Err(PatchError::UnknownFormat)
+    }
+}
+
+// looks for game.bps / game.ups / game.ips next to game.nes
+pub fn find_patch(rom_path: &Path) -> Option<PathBuf> {
+    PATCH_EXTENSIONS
+        .iter()
+        .map(|ext| rom_path.with_extension(ext))
+        .find(|path| path.is_file())
+}
+
+struct PatchReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    format: &'static str,
+}
+
+impl<'a> PatchReader<'a> {
+    fn new(data: &'a [u8], pos: usize, format: &'static str) -> Self {
+        PatchReader { data, pos, format }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], PatchError> {
+        let slice = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.data.get(self.pos..end))
+            .ok_or(PatchError::Truncated(self.format))?;
+        self.pos += len;
+        Ok(slice)
+    }
+
+    fn byte(&mut self) -> Result<u8, PatchError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn be(&mut self, len: usize) -> Result<usize, PatchError> {
+        Ok(self
+            .bytes(len)?
+            .iter()
+            .fold(0, |acc, &b| acc << 8 | b as usize))
+    }
+
+    // the variable length number encoding shared by UPS and BPS
+    fn varint(&mut self) -> Result<usize, PatchError> {
+        let mut value: usize = 0;
+        let mut shift: usize = 1;
+        loop {
+            let x = self.byte()?;
+            value = (x as usize & 0x7F)
+                .checked_mul(shift)
+                .and_then(|v| v.checked_add(value))
+                .ok_or(PatchError::OutOfBounds(self.format))?;
+            if x & 0x80 != 0 {
+                return Ok(value);
+            }
+            shift = shift
+                .checked_shl(7)
+                .filter(|&s| s != 0)
+                .ok_or(PatchError::OutOfBounds(self.format))?;
+            value = value
+                .checked_add(shift)
+                .ok_or(PatchError::OutOfBounds(self.format))?;
+        }
+    }
+}
+
+fn apply_ips(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let mut out = source.to_vec();
+    let mut r = PatchReader::new(patch, IPS_MAGIC.len(), "IPS");
+    loop {
+        if r.data[r.pos..].starts_with(IPS_EOF) {
+            r.pos += IPS_EOF.len();
+            break;
+        }
+        let offset = r.be(3)?;
+        let size = r.be(2)?;
+        // a zero size marks an RLE record: run length and a single byte
+        let (len, run) = if size == 0 {
+            (r.be(2)?, Some(r.byte()?))
+        } else {
+            (size, None)
+        };
+        if out.len() < offset + len {
+            out.resize(offset + len, 0);
+        }
+        match run {
+            Some(value) => out[offset..offset + len].fill(value),
+            None => out[offset..offset + len].copy_from_slice(r.bytes(len)?),
+        }
+    }
+    // the truncation extension: three more bytes after EOF give the final size
+    if r.data.len() - r.pos >= 3 {
+        let size = r.be(3)?;
+        out.truncate(size);
+    }
+    Ok(out)
+}
+
+// builds an IPS patch turning source into target, used to keep disk writes
+// out of the original image
+pub fn create_ips(source: &[u8], target: &[u8]) -> Vec<u8> {
+    const MAX_RECORD: usize = 0xFFFF;
+    // a record starting here would read as the EOF marker
+    const EOF_OFFSET: usize = 0x454F46;
+
+    let mut patch = IPS_MAGIC.to_vec();
+    let mut pos = 0;
+    while pos < target.len() {
+        if source.get(pos) == Some(&target[pos]) {
+            pos += 1;
+            continue;
+        }
+        let start = if pos == EOF_OFFSET { pos - 1 } else { pos };
+        let mut end = pos;
+        while end < target.len()
+            && end - start < MAX_RECORD
+            && source.get(end) != Some(&target[end])
+        {
+            end += 1;
+        }
+        patch.extend(&(start as u32).to_be_bytes()[1..]);
+        patch.extend(((end - start) as u16).to_be_bytes());
+        patch.extend(&target[start..end]);
+        pos = end;
+    }
+    patch.extend(IPS_EOF);
+    if target.len() < source.len() {
+        patch.extend(&(target.len() as u32).to_be_bytes()[1..]);
+    }
+    patch
+}
+
+// checks the patch's own CRC and returns the expected source and target CRCs
+fn read_footer(patch: &[u8], format: &'static str) -> Result<(u32, u32), PatchError> {
+    if patch.len() < 4 + FOOTER_SIZE {
+        return Err(PatchError::Truncated(format));
+    }
+    let footer = &patch[patch.len() - FOOTER_SIZE..];
+    let crc = |i: usize| u32::from_le_bytes(footer[i..i + 4].try_into().unwrap());
+    let patch_crc = crc32fast::hash(&patch[..patch.len() - 4]);
+    if patch_crc != crc(8) {
+        return Err(PatchError::ChecksumMismatch {
+            what: "patch",
+            expected: crc(8),
+            actual: patch_crc,
+        });
+    }
+    Ok((crc(0), crc(4)))
+}
+
+fn check_crc(what: &'static str, data: &[u8], expected: u32) -> Result<(), PatchError> {
+    let actual = crc32fast::hash(data);
+    if actual != expected {
+        return Err(PatchError::ChecksumMismatch {
+            what,
+            expected,
+            actual,
+        });
+    }
+    Ok(())
+}
+
+fn apply_ups(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = read_footer(patch, "UPS")?;
+    let body = &patch[..patch.len() - FOOTER_SIZE];
+    let mut r = PatchReader::new(body, UPS_MAGIC.len(), "UPS");
+
+    let source_size = r.varint()?;
+    let target_size = r.varint()?;
+    if source.len() != source_size {
+        return Err(PatchError::SourceSize {
+            expected: source_size,
+            actual: source.len(),
+        });
+    }
+    check_crc("source", source, source_crc)?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(PatchError::OutOfBounds("UPS"));
+    }
+
+    let mut out = source.to_vec();
+    out.resize(target_size, 0);
+    let mut pos: usize = 0;
+    while r.pos < body.len() {
+        pos = pos
+            .checked_add(r.varint()?)
+            .ok_or(PatchError::OutOfBounds("UPS"))?;
+        // XOR bytes up to and including a zero terminator
+        loop {
+            let x = r.byte()?;
+            if x != 0 {
+                let byte = out.get_mut(pos).ok_or(PatchError::OutOfBounds("UPS"))?;
+                *byte ^= x;
+            }
+            pos += 1;
+            if x == 0 {
+                break;
+            }
+        }
+    }
+
+    check_crc("target", &out, target_crc)?;
+    Ok(out)
+}
+
+fn apply_bps(patch: &[u8], source: &[u8]) -> Result<Vec<u8>, PatchError> {
+    let (source_crc, target_crc) = read_footer(patch, "BPS")?;
+    let body = &patch[..patch.len() - FOOTER_SIZE];
+    let mut r = PatchReader::new(body, BPS_MAGIC.len(), "BPS");
+
+    let source_size = r.varint()?;
+    let target_size = r.varint()?;
+    let metadata_size = r.varint()?;
+    r.bytes(metadata_size)?;
+    if source.len() != source_size {
+        return Err(PatchError::SourceSize {
+            expected: source_size,
+            actual: source.len(),
+        });
+    }
+    check_crc("source", source, source_crc)?;
+    if target_size > MAX_TARGET_SIZE {
+        return Err(BPS_OUT_OF_BOUNDS);
+    }
+
+    let mut out = vec![0; target_size];
+    let mut out_pos: usize = 0;
+    let mut source_rel: usize = 0;
+    let mut target_rel: usize = 0;
+    // signed relative offsets, sign in the low bit
+    let seek = |base: usize, data: usize| -> Option<usize> {
+        if data & 1 != 0 {
+            base.checked_sub(data >> 1)
+        } else {
+            base.checked_add(data >> 1)
+        }
+    };
+
+    while r.pos < body.len() {
+        let data = r.varint()?;
+        let len = (data >> 2) + 1;
+        let end = out_pos
+            .checked_add(len)
+            .filter(|&e| e <= target_size)
+            .ok_or(BPS_OUT_OF_BOUNDS)?;
+        match data & 0b11 {
+            // SourceRead
+            0 => {
+                let src = source.get(out_pos..end).ok_or(BPS_OUT_OF_BOUNDS)?;
+                out[out_pos..end].copy_from_slice(src);
+            }
+            // TargetRead
+            1 => out[out_pos..end].copy_from_slice(r.bytes(len)?),
+            // SourceCopy
+            2 => {
+                source_rel = seek(source_rel, r.varint()?).ok_or(BPS_OUT_OF_BOUNDS)?;
+                let src_end = source_rel.checked_add(len).ok_or(BPS_OUT_OF_BOUNDS)?;
+                let src = source.get(source_rel..src_end).ok_or(BPS_OUT_OF_BOUNDS)?;
+                out[out_pos..end].copy_from_slice(src);
+                source_rel = src_end;
+            }
+            // TargetCopy, byte by byte since the ranges may overlap
+            _ => {
+                target_rel = seek(target_rel, r.varint()?).ok_or(BPS_OUT_OF_BOUNDS)?;
+                for i in out_pos..end {
+                    if target_rel >= i {
+                        return Err(BPS_OUT_OF_BOUNDS);
+                    }
+                    out[i] = out[target_rel];
+                    target_rel += 1;
+                }
+            }
+        }
+        out_pos = end;
+    }
+
+    check_crc("target", &out, target_crc)?;
+    Ok(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn varint(mut value: usize) -> Vec<u8> {
+        let mut out = vec![];
+        loop {
+            let x = (value & 0x7F) as u8;
+            value >>= 7;
+            if value == 0 {
+                out.push(0x80 | x);
+                return out;
+            }
+            out.push(x);
+            value -= 1;
+        }
+    }
+
+    fn with_footer(mut body: Vec<u8>, source: &[u8], target: &[u8]) -> Vec<u8> {
+        body.extend(crc32fast::hash(source).to_le_bytes());
+        body.extend(crc32fast::hash(target).to_le_bytes());
+        let patch_crc = crc32fast::hash(&body);
+        body.extend(patch_crc.to_le_bytes());
+        body
+    }
+
+    #[test]
+    fn test_varint_round_trip() {
+        for value in [0, 1, 127, 128, 255, 16511, 16512, 1 << 20] {
+            let encoded = varint(value);
+            assert_eq!(PatchReader::new(&encoded, 0, "test").varint(), Ok(value));
+        }
+    }
+
+    #[test]
+    fn test_ips() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend([0x00, 0x00, 0x01, 0x00, 0x02, 0xAA, 0xBB]);
+        // RLE record writing 3 x $CC past the end of the file
+        patch.extend([0x00, 0x00, 0x06, 0x00, 0x00, 0x00, 0x03, 0xCC]);
+        patch.extend(IPS_EOF);
+
+        let out = apply(&patch, &[0; 4]).unwrap();
+        assert_eq!(out, vec![0, 0xAA, 0xBB, 0, 0, 0, 0xCC, 0xCC, 0xCC]);
+    }
+
+    #[test]
+    fn test_create_ips() {
+        let source = [0u8; 16];
+        let mut target = source.to_vec();
+        target[2] = 1;
+        target[3] = 2;
+        target[10] = 3;
+        target.extend([4, 5]);
+
+        let patch = create_ips(&source, &target);
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+        assert_eq!(
+            apply(&create_ips(&target, &source), &target).unwrap(),
+            source
+        );
+        assert_eq!(create_ips(&source, &source), [IPS_MAGIC, IPS_EOF].concat());
+    }
+
+    #[test]
+    fn test_ips_truncation() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend(IPS_EOF);
+        patch.extend([0x00, 0x00, 0x02]);
+
+        assert_eq!(apply(&patch, &[1, 2, 3, 4]).unwrap(), vec![1, 2]);
+    }
+
+    #[test]
+    fn test_ips_truncated_record() {
+        let mut patch = IPS_MAGIC.to_vec();
+        patch.extend([0x00, 0x00, 0x01, 0x00, 0x05, 0xAA]);
+        assert_eq!(apply(&patch, &[0; 4]), Err(PatchError::Truncated("IPS")));
+    }
+
+    #[test]
+    fn test_ups() {
+        let source = [1, 2, 3, 4];
+        let target = [1, 7, 3, 4, 5];
+        let mut body = UPS_MAGIC.to_vec();
+        body.extend(varint(4));
+        body.extend(varint(5));
+        body.extend(varint(1));
+        body.extend([2 ^ 7, 0x00]);
+        // the terminator consumed byte 2, skip byte 3
+        body.extend(varint(1));
+        body.extend([5, 0x00]);
+        let patch = with_footer(body, &source, &target);
+
+        assert_eq!(apply(&patch, &source).unwrap(), target);
+        assert_eq!(
+            apply(&patch, &[1, 2, 3, 9]),
+            Err(PatchError::ChecksumMismatch {
+                what: "source",
+                expected: crc32fast::hash(&source),
+                actual: crc32fast::hash(&[1, 2, 3, 9]),
+            })
+        );
+    }
+
+    #[test]
+    fn test_bps() {
+        let source = b"abcdef";
+        let target = b"abcXYXYXcd";
+        let mut body = BPS_MAGIC.to_vec();
+        body.extend(varint(source.len()));
+        body.extend(varint(target.len()));
+        body.extend(varint(0));
+        // SourceRead 3: "abc"
+        body.extend(varint((3 - 1) << 2));
+        // TargetRead 2: "XY"
+        body.extend(varint((2 - 1) << 2 | 1));
+        body.extend(b"XY");
+        // TargetCopy 3 from offset 3: "XYX"
+        body.extend(varint((3 - 1) << 2 | 3));
+        body.extend(varint(3 << 1));
+        // SourceCopy 2 from offset 2: "cd"
+        body.extend(varint((2 - 1) << 2 | 2));
+        body.extend(varint(2 << 1));
+        let patch = with_footer(body, source, target);
+
+        assert_eq!(apply(&patch, source).unwrap(), target);
+    }
+
+    #[test]
+    fn test_bps_corrupt_patch() {
+        let mut body = BPS_MAGIC.to_vec();
+        body.extend(varint(1));
+        body.extend(varint(1));
+        body.extend(varint(0));
+        body.extend(varint(0));
+        let mut patch = with_footer(body, b"a", b"a");
+        patch[7] ^= 0xFF;
+
+        assert!(matches!(
+            apply(&patch, b"a"),
+            Err(PatchError::ChecksumMismatch { what: "patch", .. })
+        ));
+    }
+
+    #[test]
+    fn test_unknown_format() {
+        assert_eq!(apply(b"nope", b""), Err(PatchError::UnknownFormat));
+    }
+}
@@ -0,0 +1,932 @@
+use crate::region::{Timing, DOTS_PER_SCANLINE};
+use crate::render::palette::EMPHASIS_SHIFT;
+use crate::render::Frame;
+use crate::rom::{Chr, Mirroring};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use background::Background;
+use sprites::LineSprite;
+
+mod background;
+mod sprites;
+
+const VRAM_SIZE: usize = 0x1000;
+const NAMETABLE_SIZE: u16 = 0x400;
+const ATTRIBUTE_TABLE: u16 = 0x23C0;
+
+// $2000
+const CTRL_NAMETABLE: u8 = 0b0000_0011;
+const CTRL_INCREMENT_32: u8 = 0b0000_0100;
+const CTRL_SPRITE_TABLE: u8 = 0b0000_1000;
+const CTRL_BACKGROUND_TABLE: u8 = 0b0001_0000;
+const CTRL_SPRITE_8X16: u8 = 0b0010_0000;
+const CTRL_NMI: u8 = 0b1000_0000;
+// $2001
+const MASK_GREYSCALE: u8 = 0b0000_0001;
+const MASK_BACKGROUND_LEFT: u8 = 0b0000_0010;
+const MASK_SPRITES_LEFT: u8 = 0b0000_0100;
+const MASK_BACKGROUND: u8 = 0b0000_1000;
+const MASK_SPRITES: u8 = 0b0001_0000;
+const MASK_EMPHASIS_RED: u8 = 0b0010_0000;
+const MASK_EMPHASIS_GREEN: u8 = 0b0100_0000;
+const MASK_EMPHASIS: u8 = 0b1110_0000;
+// $2002
+const STATUS_SPRITE_OVERFLOW: u8 = 0b0010_0000;
+const STATUS_SPRITE_0_HIT: u8 = 0b0100_0000;
+const STATUS_VBLANK: u8 = 0b1000_0000;
+
+// the loopy v and t registers: yyy NN YYYYY XXXXX
+// fine y, nametable, coarse y, coarse x
+const COARSE_X: u16 = 0x001F;
+const COARSE_Y: u16 = 0x03E0;
+const NAMETABLE_X: u16 = 0x0400;
+const NAMETABLE_Y: u16 = 0x0800;
+const FINE_Y: u16 = 0x7000;
+const HORIZONTAL_BITS: u16 = NAMETABLE_X | COARSE_X;
+
+// the visible picture is drawn on lines 0-239, line 240 is idle and vblank
+// starts on the line after
+const POST_RENDER_LINE: u16 = 240;
+
+// Runs a dot at a time, 341 dots a line, with the background fetched through
+// the same pipeline the hardware uses, so register writes land on the pixel
+// they were made at. Sprites for a line are picked at dot 257 of the line
+// before and drawn from the latched rows.
+pub struct Ppu {
+    // 2K inside the console, the other 2K is only there for four-screen carts
+    pub vram: [u8; VRAM_SIZE],
+    pub palette: [u8; 32],
+    pub oam: [u8; 256],
+    pub frame: Frame,
+
+    ctrl: u8,
+    mask: u8,
+    status: u8,
+    oam_addr: u8,
+    // current VRAM address, also the scroll position while rendering
+    v: u16,
+    // what v gets reloaded from: the scroll set through $2000/$2005/$2006
+    t: u16,
+    fine_x: u8,
+    // second write to $2005/$2006
+    w: bool,
+    // $2007 reads come out one access late
+    read_buffer: u8,
+
+    background: Background,
+    // what secondary OAM would hold for the current line
+    line_sprites: Vec<LineSprite>,
+    // off draws every sprite on a line, the overflow flag still acts as usual
+    sprite_limit: bool,
+
+    scanline: u16,
+    dot: u16,
+    odd_frame: bool,
+    // color subcarrier phase in twelfths of a cycle, a dot is 8 of them
+    phase: u8,
+    // master clock ticks not yet turned into dots, PAL runs 3.2 dots a cycle
+    clock: u32,
+    cpu_divider: u32,
+    ppu_divider: u32,
+    odd_frame_skip: bool,
+    swap_emphasis: bool,
+    scanlines: u16,
+    vblank_line: u16,
+
+    // $2002 read just before vblank starts, which keeps the flag and NMI off
+    suppress_vblank: bool,
+    nmi: bool,
+    frame_complete: bool,
+}
+
+impl Ppu {
+    pub fn new(timing: &Timing) -> Self {
+        Ppu {
+            vram: [0; VRAM_SIZE],
+            palette: [0; 32],
+            oam: [0; 256],
+            frame: Frame::new(),
+            ctrl: 0,
+            mask: 0,
+            status: 0,
+            oam_addr: 0,
+            v: 0,
+            t: 0,
+            fine_x: 0,
+            w: false,
+            read_buffer: 0,
+            background: Background::default(),
+            line_sprites: vec![],
+            sprite_limit: true,
+            scanline: 0,
+            dot: 0,
+            odd_frame: false,
+            phase: 0,
+            clock: 0,
+            cpu_divider: timing.cpu_divider,
+            ppu_divider: timing.ppu_divider,
+            odd_frame_skip: timing.odd_frame_skip,
+            swap_emphasis: timing.swap_emphasis,
+            scanlines: timing.scanlines,
+            // Dendy puts its extra lines before vblank, PAL after
+            vblank_line: timing.scanlines - 1 - timing.vblank_scanlines,
+            suppress_vblank: false,
+            nmi: false,
+            frame_complete: false,
+        }
+    }
+
+    fn background_table(&self) -> u16 {
+        match self.ctrl & CTRL_BACKGROUND_TABLE {
+            0 => 0x0000,
+            _ => 0x1000,
+        }
+    }
+
+    fn show_background(&self) -> bool {
+        self.mask & MASK_BACKGROUND != 0
+    }
+
+    // the hardware draws up to 8 sprites a line, more flicker or vanish
+    pub fn set_sprite_limit(&mut self, on: bool) {
+        self.sprite_limit = on;
+    }
+
+    fn sprite_height(&self) -> u16 {
+        match self.ctrl & CTRL_SPRITE_8X16 {
+            0 => 8,
+            _ => 16,
+        }
+    }
+
+    fn sprite_table(&self) -> u16 {
+        match self.ctrl & CTRL_SPRITE_TABLE {
+            0 => 0x0000,
+            _ => 0x1000,
+        }
+    }
+
+    fn show_background_left(&self) -> bool {
+        self.mask & MASK_BACKGROUND_LEFT != 0
+    }
+
+    // v only moves with the picture while something is being drawn
+    fn rendering(&self) -> bool {
+        self.mask & (MASK_BACKGROUND | MASK_SPRITES) != 0
+    }
+
+    // an NMI is raised once per vblank, the CPU takes it with this
+    pub fn poll_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi)
+    }
+
+    // true once per frame, when the picture is ready in `frame`
+    pub fn take_frame(&mut self) -> bool {
+        std::mem::take(&mut self.frame_complete)
+    }
+
+    pub fn tick(&mut self, cycles: usize, chr: &Chr, mirroring: Mirroring) {
+        self.clock += cycles as u32 * self.cpu_divider;
+        while self.clock >= self.ppu_divider {
+            self.clock -= self.ppu_divider;
+            self.advance();
+            self.run_dot(chr, mirroring);
+        }
+    }
+
+    fn advance(&mut self) {
+        self.dot += 1;
+        self.phase = (self.phase + 8) % 12;
+        // odd NTSC frames with rendering on leave out the pre-render line's last dot
+        let pre_render = self.scanline == self.scanlines - 1;
+        if pre_render
+            && self.dot == 340
+            && self.odd_frame
+            && self.odd_frame_skip
+            && self.rendering()
+        {
+            self.dot = DOTS_PER_SCANLINE as u16;
+        }
+        if self.dot == DOTS_PER_SCANLINE as u16 {
+            self.dot = 0;
+            self.scanline += 1;
+            if self.scanline == self.scanlines {
+                self.scanline = 0;
+                self.odd_frame = !self.odd_frame;
+            }
+        }
+    }
+
+    fn run_dot(&mut self, chr: &Chr, mirroring: Mirroring) {
+        let visible = self.scanline < POST_RENDER_LINE;
+        let pre_render = self.scanline == self.scanlines - 1;
+        if self.scanline == self.vblank_line && self.dot == 1 {
+            self.frame_complete = true;
+            if !std::mem::take(&mut self.suppress_vblank) {
+                self.status |= STATUS_VBLANK;
+                if self.ctrl & CTRL_NMI != 0 {
+                    self.nmi = true;
+                }
+            }
+        }
+        if pre_render && self.dot == 1 {
+            self.status &= !(STATUS_VBLANK | STATUS_SPRITE_0_HIT | STATUS_SPRITE_OVERFLOW);
+        }
+        if (visible || pre_render) && self.rendering() {
+            self.fetch(chr, mirroring);
+        }
+        if visible && (1..=256).contains(&self.dot) {
+            self.output_pixel();
+        }
+    }
+
+    // the background fetches and v updates of a rendering line
+    fn fetch(&mut self, chr: &Chr, mirroring: Mirroring) {
+        let dot = self.dot;
+        if (2..258).contains(&dot) || (321..338).contains(&dot) {
+            self.background.shift();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.background.reload();
+                    self.background.tile =
+                        self.read_nametable(0x2000 | (self.v & 0x0FFF), mirroring);
+                }
+                2 => {
+                    let v = self.v;
+                    let addr =
+                        ATTRIBUTE_TABLE | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                    // each attribute byte covers four 2x2 tile blocks
+                    let shift = ((v >> 4) & 4) | (v & 2);
+                    self.background.palette =
+                        (self.read_nametable(addr, mirroring) >> shift) & 0b11;
+                }
+                4 => self.background.lo = chr.read(self.background_row()),
+                6 => self.background.hi = chr.read(self.background_row() + 8),
+                7 => self.increment_x(),
+                _ => {}
+            }
+        }
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            // back to the left edge from t, and pick the next line's sprites
+            self.background.reload();
+            self.v = self.v & !HORIZONTAL_BITS | self.t & HORIZONTAL_BITS;
+            if self.scanline < POST_RENDER_LINE {
+                self.evaluate_sprites(chr);
+            } else {
+                self.line_sprites.clear();
+            }
+        }
+        // the pre-render line reloads the vertical scroll too
+        if self.scanline == self.scanlines - 1 && (280..=304).contains(&dot) {
+            self.v = self.v & HORIZONTAL_BITS | self.t & !HORIZONTAL_BITS;
+        }
+    }
+
+    fn background_row(&self) -> u16 {
+        self.background_table() + self.background.tile as u16 * 16 + (self.v >> 12)
+    }
+
+    fn output_pixel(&mut self) {
+        let x = self.dot as usize - 1;
+        let y = self.scanline as usize;
+        if x == 0 {
+            self.frame.set_phase(y, self.phase);
+        }
+        if !self.rendering() {
+            let pixel = self.color_out(self.backdrop());
+            self.frame.set_pixel(x, y, pixel);
+            return;
+        }
+        let background = match self.show_background() && (x >= 8 || self.show_background_left()) {
+            true => self.background.pixel(self.fine_x),
+            false => 0,
+        };
+        let mut index = background;
+        let show_sprites = self.mask & MASK_SPRITES != 0;
+        let show_sprites_left = self.mask & MASK_SPRITES_LEFT != 0;
+        // the first opaque sprite in OAM order wins, even if it's behind
+        // the background and one further down isn't
+        let sprite = self
+            .line_sprites
+            .iter()
+            .find(|sprite| sprite.color_at(x) != 0)
+            .copied()
+            .filter(|_| show_sprites && (x >= 8 || show_sprites_left));
+        if let Some(sprite) = sprite {
+            // never on the last column; a clipped background is transparent already
+            if sprite.zero && background != 0 && x != 255 {
+                self.status |= STATUS_SPRITE_0_HIT;
+            }
+            if background == 0 || !sprite.behind_background() {
+                index = 0x10 | sprite.palette() << 2 | sprite.color_at(x);
+            }
+        }
+        let pixel = self.color_out(self.palette[index as usize]);
+        self.frame.set_pixel(x, y, pixel);
+    }
+
+    // a palette entry as it leaves the PPU, through greyscale and emphasis
+    fn color_out(&self, color: u8) -> u16 {
+        let color = match self.mask & MASK_GREYSCALE {
+            0 => color,
+            _ => color & 0x30,
+        };
+        let mut emphasis = self.mask & MASK_EMPHASIS;
+        if self.swap_emphasis {
+            let red = emphasis & MASK_EMPHASIS_RED;
+            let green = emphasis & MASK_EMPHASIS_GREEN;
+            emphasis =
+                emphasis & !(MASK_EMPHASIS_RED | MASK_EMPHASIS_GREEN) | red << 1 | green >> 1;
+        }
+        color as u16 | ((emphasis >> 5) as u16) << EMPHASIS_SHIFT
+    }
+
+    fn evaluate_sprites(&mut self, chr: &Chr) {
+        let height = self.sprite_height();
+        let (found, overflow) =
+            sprites::evaluate(&self.oam, self.scanline, height, !self.sprite_limit);
+        if overflow {
+            self.status |= STATUS_SPRITE_OVERFLOW;
+        }
+        let table = self.sprite_table();
+        self.line_sprites = found
+            .into_iter()
+            .map(|index| sprites::fetch(&self.oam, index, self.scanline, height, table, chr))
+            .collect();
+    }
+
+    // moves v one tile right, into the next nametable at the edge
+    fn increment_x(&mut self) {
+        if self.v & COARSE_X == 31 {
+            self.v = (self.v & !COARSE_X) ^ NAMETABLE_X;
+        } else {
+            self.v += 1;
+        }
+    }
+
+    // moves v down a pixel, wrapping into the nametable below after row 29
+    fn increment_y(&mut self) {
+        if self.v & FINE_Y != FINE_Y {
+            self.v += 0x1000;
+            return;
+        }
+        self.v &= !FINE_Y;
+        let coarse_y = match (self.v & COARSE_Y) >> 5 {
+            29 => {
+                self.v ^= NAMETABLE_Y;
+                0
+            }
+            // rows 30 and 31 hold attributes, games pointing there wrap without switching
+            31 => 0,
+            y => y + 1,
+        };
+        self.v = self.v & !COARSE_Y | coarse_y << 5;
+    }
+
+    // nametable address to an index into vram
+    pub fn mirror_vram_addr(&self, addr: u16, mirroring: Mirroring) -> usize {
+        let addr = (addr - 0x2000) % (4 * NAMETABLE_SIZE);
+        let table = addr / NAMETABLE_SIZE;
+        let offset = addr % NAMETABLE_SIZE;
+        let bank = match mirroring {
+            Mirroring::Horizontal => table / 2,
+            Mirroring::Vertical => table % 2,
+            Mirroring::FourScreen => table,
+            Mirroring::SingleScreenLower => 0,
+            Mirroring::SingleScreenUpper => 1,
+        };
+        (bank * NAMETABLE_SIZE + offset) as usize
+    }
+
+    fn palette_index(addr: u16) -> usize {
+        let index = (addr & 0x1F) as usize;
+        // the backdrop entries of the sprite palettes mirror the background ones
+        match index {
+            0x10 | 0x14 | 0x18 | 0x1C => index - 0x10,
+            _ => index,
+        }
+    }
+
+    fn increment_addr(&mut self) {
+        let step = match self.ctrl & CTRL_INCREMENT_32 {
+            0 => 1,
+            _ => 32,
+        };
+        self.v = self.v.wrapping_add(step) & 0x7FFF;
+    }
+
+    pub fn write_ctrl(&mut self, data: u8) {
+        let was_enabled = self.ctrl & CTRL_NMI != 0;
+        self.ctrl = data;
+        self.t = self.t & !(NAMETABLE_X | NAMETABLE_Y) | ((data & CTRL_NAMETABLE) as u16) << 10;
+        // turning NMIs on during vblank fires one straight away
+        if !was_enabled && data & CTRL_NMI != 0 && self.status & STATUS_VBLANK != 0 {
+            self.nmi = true;
+        }
+    }
+
+    pub fn write_mask(&mut self, data: u8) {
+        self.mask = data;
+    }
+
+    // $2002 without clearing anything, for debuggers
+    pub fn peek_status(&self) -> u8 {
+        self.status
+    }
+
+    pub fn read_status(&mut self) -> u8 {
+        // racing the flag: a dot early it never gets set, on it or just after
+        // it reads set but the NMI is lost
+        if self.scanline == self.vblank_line {
+            match self.dot {
+                0 => self.suppress_vblank = true,
+                1 | 2 => self.nmi = false,
+                _ => {}
+            }
+        }
+        let status = self.status;
+        self.status &= !STATUS_VBLANK;
+        self.w = false;
+        status
+    }
+
+    pub fn write_oam_addr(&mut self, data: u8) {
+        self.oam_addr = data;
+    }
+
+    pub fn write_oam_data(&mut self, data: u8) {
+        self.oam[self.oam_addr as usize] = data;
+        self.oam_addr = self.oam_addr.wrapping_add(1);
+    }
+
+    pub fn read_oam_data(&self) -> u8 {
+        self.oam[self.oam_addr as usize]
+    }
+
+    pub fn write_scroll(&mut self, data: u8) {
+        let data = data as u16;
+        if self.w {
+            self.t = self.t & !(FINE_Y | COARSE_Y) | (data & 0b111) << 12 | (data >> 3) << 5;
+        } else {
+            self.t = self.t & !COARSE_X | data >> 3;
+            self.fine_x = data as u8 & 0b111;
+        }
+        self.w = !self.w;
+    }
+
+    // shares t with $2005, which is how games change the scroll mid-frame
+    pub fn write_addr(&mut self, data: u8) {
+        if self.w {
+            self.t = self.t & 0xFF00 | data as u16;
+            self.v = self.t;
+        } else {
+            self.t = (data as u16 & 0x3F) << 8 | self.t & 0x00FF;
+        }
+        self.w = !self.w;
+    }
+
+    pub fn read_data(&mut self, chr: &Chr, mirroring: Mirroring) -> u8 {
+        let addr = self.v & 0x3FFF;
+        self.increment_addr();
+        match addr {
+            0x0000..=0x1FFF => std::mem::replace(&mut self.read_buffer, chr.read(addr)),
+            0x2000..=0x3EFF => {
+                let data = self.vram[self.mirror_vram_addr(addr, mirroring)];
+                std::mem::replace(&mut self.read_buffer, data)
+            }
+            // palette reads skip the buffer, which gets the nametable byte underneath
+            _ => {
+                self.read_buffer = self.vram[self.mirror_vram_addr(addr - 0x1000, mirroring)];
+                self.palette[Ppu::palette_index(addr)]
+            }
+        }
+    }
+
+    pub fn write_data(&mut self, chr: &mut Chr, mirroring: Mirroring, data: u8) {
+        let addr = self.v & 0x3FFF;
+        self.increment_addr();
+        match addr {
+            0x0000..=0x1FFF => chr.write(addr, data),
+            0x2000..=0x3EFF => self.vram[self.mirror_vram_addr(addr, mirroring)] = data,
+            _ => self.palette[Ppu::palette_index(addr)] = data & 0x3F,
+        }
+    }
+
+    // the color the screen shows where nothing else is drawn
+    pub fn backdrop(&self) -> u8 {
+        self.palette[0]
+    }
+
+    pub fn read_nametable(&self, addr: u16, mirroring: Mirroring) -> u8 {
+        self.vram[self.mirror_vram_addr(addr, mirroring)]
+    }
+}
+
+impl Snapshot for Ppu {
+    fn snapshot(&self, w: &mut StateWriter) {
+        w.write_bytes(&self.vram);
+        w.write_bytes(&self.palette);
+        w.write_bytes(&self.oam);
+        w.write_u8(self.ctrl);
+        w.write_u8(self.mask);
+        w.write_u8(self.status);
+        w.write_u8(self.oam_addr);
+        w.write_u16(self.v);
+        w.write_u16(self.t);
+        w.write_u8(self.fine_x);
+        w.write_u8(self.w as u8);
+        w.write_u8(self.read_buffer);
+        w.write_u16(self.scanline);
+        w.write_u16(self.dot);
+        w.write_u8(self.odd_frame as u8);
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        r.read_into("VRAM", &mut self.vram)?;
+        r.read_into("palette", &mut self.palette)?;
+        r.read_into("OAM", &mut self.oam)?;
+        self.ctrl = r.read_u8()?;
+        self.mask = r.read_u8()?;
+        self.status = r.read_u8()?;
+        self.oam_addr = r.read_u8()?;
+        self.v = r.read_u16()?;
+        self.t = r.read_u16()?;
+        self.fine_x = r.read_u8()?;
+        self.w = r.read_u8()? != 0;
+        self.read_buffer = r.read_u8()?;
+        self.scanline = r.read_u16()?;
+        self.dot = r.read_u16()?;
+        self.odd_frame = r.read_u8()? != 0;
+        self.clock = 0;
+        self.nmi = false;
+        self.suppress_vblank = false;
+        self.line_sprites.clear();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::region::{DENDY, NTSC};
+    use crate::render::HEIGHT;
+
+    fn chr_ram() -> Chr {
+        Chr::Ram(vec![0; 0x2000])
+    }
+
+    fn set_addr(ppu: &mut Ppu, addr: u16) {
+        ppu.write_addr((addr >> 8) as u8);
+        ppu.write_addr(addr as u8);
+    }
+
+    #[test]
+    fn test_buffered_read() {
+        let mut ppu = Ppu::new(&NTSC);
+        let mut chr = chr_ram();
+        set_addr(&mut ppu, 0x2305);
+        ppu.write_data(&mut chr, Mirroring::Horizontal, 0x66);
+        ppu.write_data(&mut chr, Mirroring::Horizontal, 0x77);
+
+        set_addr(&mut ppu, 0x2305);
+        ppu.read_data(&chr, Mirroring::Horizontal);
+        assert_eq!(ppu.read_data(&chr, Mirroring::Horizontal), 0x66);
+        assert_eq!(ppu.read_data(&chr, Mirroring::Horizontal), 0x77);
+    }
+
+    #[test]
+    fn test_increment_32() {
+        let mut ppu = Ppu::new(&NTSC);
+        let mut chr = chr_ram();
+        ppu.write_ctrl(CTRL_INCREMENT_32);
+        set_addr(&mut ppu, 0x21FF);
+        ppu.write_data(&mut chr, Mirroring::Horizontal, 0x66);
+        ppu.write_data(&mut chr, Mirroring::Horizontal, 0x77);
+        assert_eq!(ppu.vram[0x01FF], 0x66);
+        assert_eq!(ppu.vram[0x021F], 0x77);
+    }
+
+    #[test]
+    fn test_mirroring() {
+        let ppu = Ppu::new(&NTSC);
+        assert_eq!(ppu.mirror_vram_addr(0x2405, Mirroring::Horizontal), 0x0005);
+        assert_eq!(ppu.mirror_vram_addr(0x2805, Mirroring::Horizontal), 0x0405);
+        assert_eq!(ppu.mirror_vram_addr(0x2405, Mirroring::Vertical), 0x0405);
+        assert_eq!(ppu.mirror_vram_addr(0x2805, Mirroring::Vertical), 0x0005);
+        assert_eq!(ppu.mirror_vram_addr(0x2C05, Mirroring::FourScreen), 0x0C05);
+        assert_eq!(
+            ppu.mirror_vram_addr(0x3405, Mirroring::SingleScreenUpper),
+            0x0405
+        );
+    }
+
+    #[test]
+    fn test_palette_mirrors() {
+        let mut ppu = Ppu::new(&NTSC);
+        let mut chr = chr_ram();
+        set_addr(&mut ppu, 0x3F10);
+        ppu.write_data(&mut chr, Mirroring::Horizontal, 0x2C);
+        assert_eq!(ppu.backdrop(), 0x2C);
+        set_addr(&mut ppu, 0x3F20);
+        assert_eq!(ppu.read_data(&chr, Mirroring::Horizontal), 0x2C);
+    }
+
+    #[test]
+    fn test_vblank_nmi() {
+        let mut ppu = Ppu::new(&NTSC);
+        let chr = chr_ram();
+        ppu.write_ctrl(CTRL_NMI);
+        // 241 lines of 341 dots, 3 dots per CPU cycle
+        ppu.tick(27393, &chr, Mirroring::Horizontal);
+        assert!(!ppu.poll_nmi());
+        ppu.tick(1, &chr, Mirroring::Horizontal);
+        assert!(ppu.poll_nmi());
+        assert!(!ppu.poll_nmi());
+        assert!(ppu.take_frame());
+
+        assert_eq!(ppu.read_status() & STATUS_VBLANK, STATUS_VBLANK);
+        assert_eq!(ppu.read_status() & STATUS_VBLANK, 0);
+    }
+
+    #[test]
+    fn test_dendy_vblank_starts_late() {
+        let mut ppu = Ppu::new(&DENDY);
+        let chr = chr_ram();
+        ppu.tick(27394, &chr, Mirroring::Horizontal);
+        assert!(!ppu.take_frame());
+        ppu.tick(50 * 341 / 3 + 1, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.scanline, 291);
+        assert!(ppu.take_frame());
+    }
+
+    #[test]
+    fn test_status_resets_latch() {
+        let mut ppu = Ppu::new(&NTSC);
+        ppu.write_addr(0x21);
+        ppu.read_status();
+        set_addr(&mut ppu, 0x2305);
+        assert_eq!(ppu.v, 0x2305);
+    }
+
+    #[test]
+    fn test_scroll_registers() {
+        let mut ppu = Ppu::new(&NTSC);
+        ppu.write_ctrl(0b10);
+        ppu.write_scroll(0x7D);
+        assert_eq!(ppu.fine_x, 0b101);
+        ppu.write_scroll(0x5E);
+        assert_eq!(ppu.t, 0b110 << 12 | NAMETABLE_Y | 0b01011 << 5 | 0b01111);
+        // $2006 overwrites t, and copies it to v on the second write
+        ppu.write_addr(0x3D);
+        ppu.write_addr(0xF0);
+        assert_eq!(ppu.t, 0x3DF0);
+        assert_eq!(ppu.v, 0x3DF0);
+    }
+
+    // one dot at a time, so tests can stop exactly where they need to
+    fn run_dot(ppu: &mut Ppu, chr: &Chr) {
+        ppu.advance();
+        ppu.run_dot(chr, Mirroring::Horizontal);
+    }
+
+    fn run_to(ppu: &mut Ppu, chr: &Chr, scanline: u16, dot: u16) {
+        while (ppu.scanline, ppu.dot) != (scanline, dot) {
+            run_dot(ppu, chr);
+        }
+    }
+
+    // two whole frames, the first one starts without the pre-render line
+    fn render_frame(ppu: &mut Ppu, chr: &Chr, mirroring: Mirroring) {
+        for _ in 0..2 {
+            while !ppu.take_frame() {
+                ppu.tick(1, chr, mirroring);
+            }
+        }
+    }
+
+    // tile 1 is solid color 3
+    fn solid_tile() -> Chr {
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x20].fill(0xFF);
+        Chr::Ram(chr)
+    }
+
+    #[test]
+    fn test_scroll_split() {
+        let mut ppu = Ppu::new(&NTSC);
+        let chr = chr_ram();
+        ppu.write_mask(MASK_BACKGROUND);
+        // partway through a line, 2 tiles prefetched and 12 drawn, a new x
+        // scroll waits for dot 257 to reach v
+        run_to(&mut ppu, &chr, 120, 100);
+        ppu.write_scroll(0x18);
+        ppu.write_scroll(0);
+        assert_eq!(ppu.v & COARSE_X, 14);
+        run_to(&mut ppu, &chr, 120, 257);
+        assert_eq!(ppu.v & COARSE_X, 3);
+        // then the next line's first two tiles are fetched ahead
+        run_to(&mut ppu, &chr, 121, 0);
+        assert_eq!(ppu.v & COARSE_X, 5);
+    }
+
+    #[test]
+    fn test_background() {
+        let mut ppu = Ppu::new(&NTSC);
+        let chr = solid_tile();
+        // tile 1 at the top left with palette 1
+        ppu.vram[0] = 1;
+        ppu.vram[0x3C0] = 0b01;
+        ppu.palette[0] = 0x0F;
+        ppu.palette[7] = 0x30;
+        ppu.write_mask(MASK_BACKGROUND | MASK_BACKGROUND_LEFT);
+
+        render_frame(&mut ppu, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.frame.pixel(7, 7), 0x30);
+        assert_eq!(ppu.frame.pixel(8, 0), 0x0F);
+        assert_eq!(ppu.frame.pixel(0, 8), 0x0F);
+    }
+
+    #[test]
+    fn test_fine_scroll_and_wrap() {
+        let mut ppu = Ppu::new(&NTSC);
+        let chr = solid_tile();
+        // the last tile of the first nametable, then the first of the second
+        ppu.vram[31] = 1;
+        ppu.vram[0x400] = 1;
+        ppu.palette[3] = 0x30;
+        ppu.write_mask(MASK_BACKGROUND | MASK_BACKGROUND_LEFT);
+        ppu.write_scroll(31 * 8 + 3);
+        ppu.write_scroll(0);
+
+        render_frame(&mut ppu, &chr, Mirroring::Vertical);
+        let row: Vec<u16> = (0..14).map(|x| ppu.frame.pixel(x, 0)).collect();
+        assert_eq!(row[..13], [0x30; 13]);
+        assert_eq!(row[13], 0);
+    }
+
+    #[test]
+    fn test_left_clipping() {
+        let mut ppu = Ppu::new(&NTSC);
+        let chr = solid_tile();
+        ppu.vram[0] = 1;
+        ppu.vram[1] = 1;
+        ppu.palette[3] = 0x30;
+        ppu.write_mask(MASK_BACKGROUND);
+
+        render_frame(&mut ppu, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.frame.pixel(7, 0), 0);
+        assert_eq!(ppu.frame.pixel(8, 0), 0x30);
+    }
+
+    #[test]
+    fn test_greyscale_and_emphasis() {
+        let mut ppu = Ppu::new(&NTSC);
+        ppu.write_mask(MASK_GREYSCALE | MASK_EMPHASIS_RED);
+        assert_eq!(ppu.color_out(0x16), 0x10 | 0b001 << EMPHASIS_SHIFT);
+        // PAL's PPU has red and green the other way around
+        let mut ppu = Ppu::new(&crate::region::PAL);
+        ppu.write_mask(MASK_EMPHASIS_RED);
+        assert_eq!(ppu.color_out(0x16), 0x16 | 0b010 << EMPHASIS_SHIFT);
+    }
+
+    #[test]
+    fn test_odd_frame_skip() {
+        let mut ppu = Ppu::new(&NTSC);
+        let chr = chr_ram();
+        ppu.write_mask(MASK_BACKGROUND);
+        ppu.scanline = 261;
+        ppu.dot = 338;
+        ppu.odd_frame = true;
+        ppu.tick(1, &chr, Mirroring::Horizontal);
+        assert_eq!((ppu.scanline, ppu.dot), (0, 1));
+
+        ppu.scanline = 261;
+        ppu.dot = 338;
+        ppu.odd_frame = false;
+        ppu.tick(1, &chr, Mirroring::Horizontal);
+        assert_eq!((ppu.scanline, ppu.dot), (0, 0));
+    }
+
+    #[test]
+    fn test_subcarrier_phase() {
+        let mut ppu = Ppu::new(&NTSC);
+        let chr = chr_ram();
+        render_frame(&mut ppu, &chr, Mirroring::Horizontal);
+        // 341 dots of 8 twelfths put every line 4 further on
+        let phases = &ppu.frame.phases;
+        assert!((1..HEIGHT).all(|y| phases[y] == (phases[y - 1] + 4) % 12));
+
+        // a frame moves it on by 4 too, 8 on odd frames with rendering on,
+        // so the dot crawl goes back and forth between two patterns
+        ppu.write_mask(MASK_BACKGROUND);
+        let mut starts = vec![];
+        for _ in 0..4 {
+            while !ppu.take_frame() {
+                ppu.tick(1, &chr, Mirroring::Horizontal);
+            }
+            starts.push(ppu.frame.phases[0]);
+        }
+        assert_eq!(starts[0], starts[2]);
+        assert_eq!(starts[1], starts[3]);
+        assert_eq!((starts[1] + 12 - starts[0]) % 4, 0);
+        assert_ne!(starts[0], starts[1]);
+    }
+
+    #[test]
+    fn test_status_read_race() {
+        let chr = chr_ram();
+        // a dot early: the flag reads clear and then never comes up
+        let mut ppu = Ppu::new(&NTSC);
+        ppu.write_ctrl(CTRL_NMI);
+        run_to(&mut ppu, &chr, 241, 0);
+        assert_eq!(ppu.read_status() & STATUS_VBLANK, 0);
+        run_dot(&mut ppu, &chr);
+        assert_eq!(ppu.status & STATUS_VBLANK, 0);
+        assert!(!ppu.poll_nmi());
+        assert!(ppu.take_frame());
+
+        // right on it: the flag reads set, but the NMI is gone
+        let mut ppu = Ppu::new(&NTSC);
+        ppu.write_ctrl(CTRL_NMI);
+        run_to(&mut ppu, &chr, 241, 1);
+        assert_eq!(ppu.read_status() & STATUS_VBLANK, STATUS_VBLANK);
+        assert!(!ppu.poll_nmi());
+
+        let mut ppu = Ppu::new(&NTSC);
+        ppu.write_ctrl(CTRL_NMI);
+        run_to(&mut ppu, &chr, 241, 3);
+        assert_eq!(ppu.read_status() & STATUS_VBLANK, STATUS_VBLANK);
+        assert!(ppu.poll_nmi());
+    }
+
+    // an opaque background of tile 1, with the given sprites over it
+    fn sprite_scene(sprites: &[[u8; 4]]) -> (Ppu, Chr) {
+        let mut ppu = Ppu::new(&NTSC);
+        let mut chr = vec![0; 0x2000];
+        chr[0x10..0x20].fill(0xFF);
+        ppu.vram[..0x3C0].fill(1);
+        ppu.oam.fill(0xFF);
+        for (i, sprite) in sprites.iter().enumerate() {
+            ppu.oam[i * 4..i * 4 + 4].copy_from_slice(sprite);
+        }
+        ppu.palette[0x03] = 0x21;
+        ppu.palette[0x13] = 0x16;
+        ppu.write_mask(MASK_BACKGROUND | MASK_SPRITES | MASK_BACKGROUND_LEFT | MASK_SPRITES_LEFT);
+        (ppu, Chr::Ram(chr))
+    }
+
+    #[test]
+    fn test_sprite_0_hit_and_priority() {
+        // sprite 0 in front at x 100, sprite 1 behind the background at x 120
+        let (mut ppu, chr) = sprite_scene(&[[9, 1, 0, 100], [9, 1, 0x20, 120]]);
+        // to dot 1 of line 10, the sprites' first line
+        ppu.tick(1137, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.scanline, 10);
+        ppu.tick(33, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.read_status() & STATUS_SPRITE_0_HIT, 0);
+        ppu.tick(1, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.read_status() & STATUS_SPRITE_0_HIT, STATUS_SPRITE_0_HIT);
+
+        ppu.tick(100, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.frame.pixel(100, 10), 0x16);
+        assert_eq!(ppu.frame.pixel(120, 10), 0x21);
+        assert_eq!(ppu.frame.pixel(100, 9), 0x21);
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let sprites: Vec<[u8; 4]> = (0..9).map(|i| [9, 1, 0, i * 8]).collect();
+        let (mut ppu, chr) = sprite_scene(&sprites);
+        ppu.palette[0x03] = 0x0F;
+        ppu.tick(1200, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.frame.pixel(7 * 8, 10), 0x16);
+        assert_eq!(ppu.frame.pixel(8 * 8, 10), 0x0F);
+        assert_eq!(
+            ppu.read_status() & STATUS_SPRITE_OVERFLOW,
+            STATUS_SPRITE_OVERFLOW
+        );
+
+        let (mut ppu, chr) = sprite_scene(&sprites);
+        ppu.set_sprite_limit(false);
+        ppu.tick(1200, &chr, Mirroring::Horizontal);
+        assert_eq!(ppu.frame.pixel(8 * 8, 10), 0x16);
+        // the flag still reports what the hardware would
+        assert_eq!(
+            ppu.read_status() & STATUS_SPRITE_OVERFLOW,
+            STATUS_SPRITE_OVERFLOW
+        );
+    }
+
+    #[test]
+    fn test_increment_y_wraps_nametable() {
+        let mut ppu = Ppu::new(&NTSC);
+        ppu.v = FINE_Y | 29 << 5;
+        ppu.increment_y();
+        assert_eq!(ppu.v, NAMETABLE_Y);
+        ppu.v = FINE_Y | 31 << 5 | NAMETABLE_Y;
+        ppu.increment_y();
+        assert_eq!(ppu.v, NAMETABLE_Y);
+    }
+}
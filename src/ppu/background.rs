@@ -0,0 +1,71 @@
+// The background pipeline. Every 8 dots the PPU fetches a tile's nametable
+// byte, attribute and pattern bytes into latches, then loads them into the
+// low half of 16-bit shift registers that move out a pixel each dot. The
+// high half holds the tile being drawn, so fine x can pick any of 8 bits.
+#[derive(Default)]
+pub struct Background {
+    pub tile: u8,
+    // the tile's 2-bit palette, already picked out of the attribute byte
+    pub palette: u8,
+    pub lo: u8,
+    pub hi: u8,
+    pattern_lo: u16,
+    pattern_hi: u16,
+    palette_lo: u16,
+    palette_hi: u16,
+}
+
+impl Background {
+    pub fn reload(&mut self) {
+        self.pattern_lo = self.pattern_lo & 0xFF00 | self.lo as u16;
+        self.pattern_hi = self.pattern_hi & 0xFF00 | self.hi as u16;
+        let spread = |bit: u8| if self.palette & bit != 0 { 0xFF } else { 0x00 };
+        self.palette_lo = self.palette_lo & 0xFF00 | spread(0b01);
+        self.palette_hi = self.palette_hi & 0xFF00 | spread(0b10);
+    }
+
+    pub fn shift(&mut self) {
+        self.pattern_lo <<= 1;
+        self.pattern_hi <<= 1;
+        self.palette_lo <<= 1;
+        self.palette_hi <<= 1;
+    }
+
+    // palette RAM index of the current pixel, 0 where it's transparent
+    pub fn pixel(&self, fine_x: u8) -> u8 {
+        let bit = 15 - fine_x;
+        let value = (self.pattern_hi >> bit & 1) << 1 | (self.pattern_lo >> bit & 1);
+        if value == 0 {
+            return 0;
+        }
+        let palette = (self.palette_hi >> bit & 1) << 1 | (self.palette_lo >> bit & 1);
+        (palette << 2 | value) as u8
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_shift_out_two_tiles() {
+        // a tile with only its first pixel set, then a solid one in palette 2
+        let mut background = Background {
+            lo: 0x80,
+            ..Default::default()
+        };
+        background.reload();
+        for _ in 0..8 {
+            background.shift();
+        }
+        background.lo = 0xFF;
+        background.hi = 0xFF;
+        background.palette = 2;
+        background.reload();
+
+        assert_eq!(background.pixel(0), 0b01);
+        assert_eq!(background.pixel(1), 0);
+        background.shift();
+        assert_eq!(background.pixel(7), 0b1011);
+    }
+}
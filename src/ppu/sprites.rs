@@ -0,0 +1,177 @@
+use crate::rom::Chr;
+
+// what the hardware's secondary OAM holds
+pub const SPRITES_PER_LINE: usize = 8;
+
+// OAM byte 2
+const ATTR_PALETTE: u8 = 0b0000_0011;
+const ATTR_BEHIND_BACKGROUND: u8 = 0b0010_0000;
+const ATTR_FLIP_HORIZONTAL: u8 = 0b0100_0000;
+const ATTR_FLIP_VERTICAL: u8 = 0b1000_0000;
+
+// a sprite picked for a line, with its row of pattern already fetched
+#[derive(Clone, Copy)]
+pub struct LineSprite {
+    pub x: u8,
+    pub attributes: u8,
+    pub lo: u8,
+    pub hi: u8,
+    // OAM entry 0, the one that sets the hit flag
+    pub zero: bool,
+}
+
+impl LineSprite {
+    // the 2-bit color at screen column x, 0 where it's transparent or elsewhere
+    pub fn color_at(&self, x: usize) -> u8 {
+        let offset = x.wrapping_sub(self.x as usize);
+        if offset >= 8 {
+            return 0;
+        }
+        let bit = match self.attributes & ATTR_FLIP_HORIZONTAL {
+            0 => 7 - offset,
+            _ => offset,
+        };
+        (self.hi >> bit & 1) << 1 | (self.lo >> bit & 1)
+    }
+
+    pub fn palette(&self) -> u8 {
+        self.attributes & ATTR_PALETTE
+    }
+
+    pub fn behind_background(&self) -> bool {
+        self.attributes & ATTR_BEHIND_BACKGROUND != 0
+    }
+}
+
+fn in_range(y: u8, line: u16, height: u16) -> bool {
+    line >= y as u16 && line - (y as u16) < height
+}
+
+// OAM indexes of the sprites on the line after `line`, since sprites show up
+// one line below their Y. The hardware stops at eight, `unlimited` keeps going.
+// Also returns the overflow flag, worked out the way the 2C02 does it: once
+// eight are found it steps through the bytes of each entry as well as the
+// entries, so it misses some overflows and reports some that aren't there.
+pub fn evaluate(oam: &[u8; 256], line: u16, height: u16, unlimited: bool) -> (Vec<usize>, bool) {
+    let mut found = vec![];
+    let mut n = 0;
+    while n < 64 && found.len() < SPRITES_PER_LINE {
+        if in_range(oam[n * 4], line, height) {
+            found.push(n);
+        }
+        n += 1;
+    }
+
+    let mut overflow = false;
+    if found.len() == SPRITES_PER_LINE {
+        let rest = n;
+        let mut m = 0;
+        while n < 64 {
+            if in_range(oam[n * 4 + m], line, height) {
+                overflow = true;
+                break;
+            }
+            n += 1;
+            m = (m + 1) & 3;
+        }
+        if unlimited {
+            found.extend((rest..64).filter(|&n| in_range(oam[n * 4], line, height)));
+        }
+    }
+    (found, overflow)
+}
+
+// `table` is the 8x8 pattern table from PPUCTRL, 8x16 sprites pick their own
+pub fn fetch(
+    oam: &[u8; 256],
+    index: usize,
+    line: u16,
+    height: u16,
+    table: u16,
+    chr: &Chr,
+) -> LineSprite {
+    let entry = &oam[index * 4..index * 4 + 4];
+    let (y, tile, attributes, x) = (entry[0] as u16, entry[1] as u16, entry[2], entry[3]);
+    let mut row = line - y;
+    if attributes & ATTR_FLIP_VERTICAL != 0 {
+        row = height - 1 - row;
+    }
+    let addr = match height {
+        16 => (tile & 1) * 0x1000 + ((tile & 0xFE) + row / 8) * 16 + row % 8,
+        _ => table + tile * 16 + row,
+    };
+    LineSprite {
+        x,
+        attributes,
+        lo: chr.read(addr),
+        hi: chr.read(addr + 8),
+        zero: index == 0,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn oam_with(sprites: &[(u8, u8, u8, u8)]) -> [u8; 256] {
+        // Y of 0xFF keeps the rest off screen
+        let mut oam = [0xFF; 256];
+        for (i, &(y, tile, attributes, x)) in sprites.iter().enumerate() {
+            oam[i * 4..i * 4 + 4].copy_from_slice(&[y, tile, attributes, x]);
+        }
+        oam
+    }
+
+    #[test]
+    fn test_eight_sprite_limit() {
+        let oam = oam_with(&[(10, 0, 0, 0); 10]);
+        let (found, overflow) = evaluate(&oam, 12, 8, false);
+        assert_eq!(found, (0..8).collect::<Vec<_>>());
+        assert!(overflow);
+        let (found, _) = evaluate(&oam, 12, 8, true);
+        assert_eq!(found.len(), 10);
+        let (found, overflow) = evaluate(&oam, 18, 8, false);
+        assert!(found.is_empty());
+        assert!(!overflow);
+    }
+
+    #[test]
+    fn test_overflow_bug() {
+        // after eight hits the ninth entry is checked by its Y, the tenth by
+        // its tile number, the eleventh by its attributes...
+        let mut sprites = vec![(10, 0, 0, 0); 8];
+        sprites.push((100, 0, 0, 0));
+        sprites.push((100, 10, 0, 0));
+        let (_, overflow) = evaluate(&oam_with(&sprites), 12, 8, false);
+        assert!(overflow);
+
+        // a real ninth sprite on the line missed for the same reason
+        let mut sprites = vec![(10, 0, 0, 0); 8];
+        sprites.push((100, 0, 0, 0));
+        sprites.push((10, 100, 0, 0));
+        let (found, overflow) = evaluate(&oam_with(&sprites), 12, 8, true);
+        assert!(!overflow);
+        assert_eq!(found.len(), 9);
+    }
+
+    #[test]
+    fn test_fetch_flips_and_tall_sprites() {
+        let mut chr = vec![0; 0x2000];
+        // tile 3 in the right table, row 7 has only its leftmost pixel set
+        chr[0x1000 + 3 * 16 + 7] = 0x80;
+        let chr = Chr::Ram(chr);
+
+        // 8x16 with tile 2 | 1: rows 8-15 come from tile 3 in $1000
+        let oam = oam_with(&[(20, 0x03, 0, 50)]);
+        let sprite = fetch(&oam, 0, 20 + 15, 16, 0, &chr);
+        assert_eq!(sprite.color_at(50), 1);
+        assert_eq!(sprite.color_at(57), 0);
+        assert!(sprite.zero);
+
+        // flipped both ways, the same pixel is now the top right one
+        let oam = oam_with(&[(20, 0x03, ATTR_FLIP_VERTICAL | ATTR_FLIP_HORIZONTAL, 50)]);
+        let sprite = fetch(&oam, 0, 20, 16, 0, &chr);
+        assert_eq!(sprite.color_at(57), 1);
+        assert_eq!(sprite.color_at(50), 0);
+    }
+}
@@ -0,0 +1,173 @@
+use std::fmt;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum Region {
+    Ntsc,
+    Pal,
+    Dendy,
+    // runs on either, the header doesn't prefer one
+    Multi,
+}
+
+impl Region {
+    pub fn from_name(name: &str) -> Option<Region> {
+        match name.to_ascii_lowercase().as_str() {
+            "ntsc" => Some(Region::Ntsc),
+            "pal" => Some(Region::Pal),
+            "dendy" => Some(Region::Dendy),
+            "multi" => Some(Region::Multi),
+            _ => None,
+        }
+    }
+
+    // the clocks and frame layout the console runs with; multi-region
+    // carts get an NTSC console, it's what most of them were tested on
+    pub fn timing(&self) -> Timing {
+        match self {
+            Region::Pal => PAL,
+            Region::Dendy => DENDY,
+            Region::Ntsc | Region::Multi => NTSC,
+        }
+    }
+
+    // CPU cycles per second
+    pub fn cpu_clock(&self) -> f64 {
+        self.timing().cpu_clock()
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub struct Timing {
+    // crystal frequency, the CPU and PPU clocks are divided down from it
+    pub master_clock: f64,
+    pub cpu_divider: u32,
+    pub ppu_divider: u32,
+    pub scanlines: u16,
+    // scanlines after the post-render line before the pre-render line
+    pub vblank_scanlines: u16,
+    // NTSC skips a dot on odd frames when rendering is on
+    pub odd_frame_skip: bool,
+    // the 2C07 and Dendy clones swap PPUMASK's red and green emphasis bits
+    pub swap_emphasis: bool,
+    // CPU cycle of each APU frame counter step; the 4-step sequence ends
+    // on the fourth, the 5-step one on the fifth
+    pub frame_counter_steps: [usize; 5],
+    // the noise channel's timer periods in CPU cycles, picked by $400E
+    pub noise_periods: [u16; 16],
+    // the DMC's output periods in CPU cycles, picked by $4010
+    pub dmc_rates: [u16; 16],
+}
+
+const NTSC_NOISE_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+
+pub const NTSC: Timing = Timing {
+    master_clock: 236.25e6 / 11.0,
+    cpu_divider: 12,
+    ppu_divider: 4,
+    scanlines: 262,
+    vblank_scanlines: 20,
+    odd_frame_skip: true,
+    swap_emphasis: false,
+    frame_counter_steps: [7457, 14913, 22371, 29829, 37281],
+    noise_periods: NTSC_NOISE_PERIODS,
+    dmc_rates: NTSC_DMC_RATES,
+};
+
+pub const PAL: Timing = Timing {
+    master_clock: 26_601_712.5,
+    cpu_divider: 16,
+    ppu_divider: 5,
+    scanlines: 312,
+    vblank_scanlines: 70,
+    odd_frame_skip: false,
+    swap_emphasis: true,
+    frame_counter_steps: [8313, 16627, 24939, 33253, 41565],
+    noise_periods: [
+        4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+    ],
+    dmc_rates: [
+        398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+    ],
+};
+
+// a PAL-clocked famiclone, but with NTSC-length vblank (the extra lines go
+// before it) and the NTSC APU, so NTSC games play at close to full speed
+pub const DENDY: Timing = Timing {
+    master_clock: 26_601_712.5,
+    cpu_divider: 15,
+    ppu_divider: 5,
+    scanlines: 312,
+    vblank_scanlines: 20,
+    odd_frame_skip: false,
+    swap_emphasis: true,
+    frame_counter_steps: [7457, 14913, 22371, 29829, 37281],
+    noise_periods: NTSC_NOISE_PERIODS,
+    dmc_rates: NTSC_DMC_RATES,
+};
+
+pub const DOTS_PER_SCANLINE: u32 = 341;
+
+impl Timing {
+    pub fn cpu_clock(&self) -> f64 {
+        self.master_clock / self.cpu_divider as f64
+    }
+
+    // 3 on NTSC and Dendy, 3.2 on PAL
+    pub fn dots_per_cpu_cycle(&self) -> f64 {
+        self.cpu_divider as f64 / self.ppu_divider as f64
+    }
+
+    pub fn dots_per_frame(&self) -> f64 {
+        let dots = (DOTS_PER_SCANLINE * self.scanlines as u32) as f64;
+        // every other frame is a dot short
+        match self.odd_frame_skip {
+            true => dots - 0.5,
+            false => dots,
+        }
+    }
+
+    // 60.0988 Hz on NTSC, 50.007 Hz on PAL and Dendy
+    pub fn frame_rate(&self) -> f64 {
+        self.master_clock / self.ppu_divider as f64 / self.dots_per_frame()
+    }
+}
+
+impl fmt::Display for Region {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Region::Ntsc => "NTSC",
+            Region::Pal => "PAL",
+            Region::Dendy => "Dendy",
+            Region::Multi => "multi-region",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_clocks() {
+        assert_eq!(Region::Ntsc.cpu_clock().round(), 1_789_773.0);
+        assert_eq!(Region::Pal.cpu_clock().round(), 1_662_607.0);
+        assert_eq!(Region::Dendy.cpu_clock().round(), 1_773_448.0);
+        assert_eq!(Region::Multi.timing(), NTSC);
+        assert_eq!(PAL.dots_per_cpu_cycle(), 3.2);
+    }
+
+    #[test]
+    fn test_frame_rate() {
+        assert_eq!((NTSC.frame_rate() * 10000.0).round(), 600988.0);
+        assert_eq!((PAL.frame_rate() * 1000.0).round(), 50007.0);
+        assert_eq!((DENDY.frame_rate() * 1000.0).round(), 50007.0);
+        assert_eq!(NTSC.dots_per_frame(), 89341.5);
+    }
+}
@@ -0,0 +1,9 @@
+mod frame;
+pub mod ntsc;
+pub mod palette;
+pub mod scale;
+
+pub use frame::{ppm, Frame, HEIGHT, WIDTH};
+pub use ntsc::{NtscFilter, NtscPreset, NTSC_WIDTH};
+pub use palette::Palette;
+pub use scale::{Image, Scale, Scaler};
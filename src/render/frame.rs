@@ -0,0 +1,78 @@
+use super::palette::Palette;
+
+pub const WIDTH: usize = 256;
+pub const HEIGHT: usize = 240;
+
+// a finished picture, one NES color (0-63) per pixel with the emphasis
+// bits above it, see palette::EMPHASIS_SHIFT
+#[derive(Default)]
+pub struct Frame {
+    pub pixels: Vec<u16>,
+    // where the color subcarrier was (0-11) at each line's first pixel,
+    // which the NTSC filter needs to rebuild the signal
+    pub phases: Vec<u8>,
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Frame {
+            pixels: vec![0; WIDTH * HEIGHT],
+            phases: vec![0; HEIGHT],
+        }
+    }
+
+    pub fn pixel(&self, x: usize, y: usize) -> u16 {
+        self.pixels[y * WIDTH + x]
+    }
+
+    pub fn set_pixel(&mut self, x: usize, y: usize, color: u16) {
+        self.pixels[y * WIDTH + x] = color;
+    }
+
+    pub fn set_phase(&mut self, y: usize, phase: u8) {
+        self.phases[y] = phase;
+    }
+
+    pub fn fill(&mut self, color: u16) {
+        self.pixels.fill(color);
+    }
+
+    // packed RGB, ready for a texture
+    pub fn to_rgb(&self, palette: &Palette) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(self.pixels.len() * 3);
+        for &pixel in &self.pixels {
+            let (r, g, b) = palette.rgb(pixel);
+            rgb.extend([r, g, b]);
+        }
+        rgb
+    }
+
+    // binary PPM, which any image viewer or converter can open
+    pub fn to_ppm(&self, palette: &Palette) -> Vec<u8> {
+        ppm(WIDTH, HEIGHT, &self.to_rgb(palette))
+    }
+}
+
+// any packed RGB picture as a PPM
+pub fn ppm(width: usize, height: usize, rgb: &[u8]) -> Vec<u8> {
+    let mut ppm = format!("P6\n{} {}\n255\n", width, height).into_bytes();
+    ppm.extend(rgb);
+    ppm
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_ppm() {
+        let mut frame = Frame::new();
+        frame.set_pixel(1, 0, 0x30);
+        let palette = Palette::default();
+        let ppm = frame.to_ppm(&palette);
+        assert!(ppm.starts_with(b"P6\n256 240\n255\n"));
+        assert_eq!(ppm.len(), 15 + WIDTH * HEIGHT * 3);
+        let (r, g, b) = palette.rgb(0x30);
+        assert_eq!(&ppm[18..21], &[r, g, b]);
+    }
+}
@@ -0,0 +1,209 @@
+use super::frame::{Frame, HEIGHT, WIDTH};
+use super::palette::{composite_level, phase_angle, yiq_to_rgb, Palette, PALETTE_SIZE};
+
+// The PPU puts out 8 samples of the signal a dot, at 12 a color subcarrier
+// cycle; the filter decodes two output pixels a dot.
+pub const NTSC_WIDTH: usize = WIDTH * 2;
+const SAMPLES_PER_DOT: usize = 8;
+const SAMPLES_PER_PIXEL: usize = SAMPLES_PER_DOT * WIDTH / NTSC_WIDTH;
+const LINE_SAMPLES: usize = WIDTH * SAMPLES_PER_DOT;
+// room either side of a line for the widest window, read as black
+const PADDING: usize = 16;
+
+pub const NTSC_PRESETS: [&str; 3] = ["composite", "svideo", "rgb"];
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NtscPreset {
+    // one wire: luma and chroma get into each other, so fine detail turns
+    // into artifact colors and the color carrier crawls across edges
+    Composite,
+    // luma and chroma on their own wires, colors still bleed sideways
+    SVideo,
+    // what the PPU meant, with nothing lost on the way
+    Rgb,
+}
+
+impl NtscPreset {
+    pub fn from_name(name: &str) -> Option<NtscPreset> {
+        match name.to_ascii_lowercase().as_str() {
+            "composite" => Some(NtscPreset::Composite),
+            "svideo" | "s-video" => Some(NtscPreset::SVideo),
+            "rgb" => Some(NtscPreset::Rgb),
+            _ => None,
+        }
+    }
+
+    // samples averaged for luma and chroma, a subcarrier cycle is 12; a luma
+    // window that isn't a whole cycle leaves some of the carrier in
+    fn windows(&self) -> (usize, usize) {
+        match self {
+            NtscPreset::Composite => (8, 24),
+            NtscPreset::SVideo => (4, 24),
+            NtscPreset::Rgb => (1, 12),
+        }
+    }
+}
+
+// Rebuilds the signal a TV would get from a frame's pixels, emphasis and the
+// subcarrier phase each line started on, then decodes it back into RGB.
+pub struct NtscFilter {
+    preset: NtscPreset,
+    // each pixel value's signal at the 12 phases, and its average
+    levels: Vec<[f32; 12]>,
+    luma: Vec<f32>,
+    colors: Palette,
+    cos: [f32; 12],
+    sin: [f32; 12],
+}
+
+impl NtscFilter {
+    pub fn new(preset: NtscPreset) -> Self {
+        let levels: Vec<[f32; 12]> = (0..PALETTE_SIZE as u16)
+            .map(|pixel| std::array::from_fn(|phase| composite_level(pixel, phase)))
+            .collect();
+        let luma = levels
+            .iter()
+            .map(|level| level.iter().sum::<f32>() / 12.0)
+            .collect();
+        NtscFilter {
+            preset,
+            levels,
+            luma,
+            colors: Palette::builtin("ntsc").unwrap(),
+            cos: std::array::from_fn(|phase| phase_angle(phase as f32).cos()),
+            sin: std::array::from_fn(|phase| phase_angle(phase as f32).sin()),
+        }
+    }
+
+    pub fn preset(&self) -> NtscPreset {
+        self.preset
+    }
+
+    // packed RGB, NTSC_WIDTH by HEIGHT
+    pub fn apply(&self, frame: &Frame) -> Vec<u8> {
+        let mut rgb = Vec::with_capacity(NTSC_WIDTH * HEIGHT * 3);
+        for y in 0..HEIGHT {
+            let row = &frame.pixels[y * WIDTH..(y + 1) * WIDTH];
+            match self.preset {
+                NtscPreset::Rgb => {
+                    for &pixel in row {
+                        let (r, g, b) = self.colors.rgb(pixel);
+                        for _ in 0..NTSC_WIDTH / WIDTH {
+                            rgb.extend([r, g, b]);
+                        }
+                    }
+                }
+                _ => self.decode_line(row, frame.phases[y] as usize, &mut rgb),
+            }
+        }
+        rgb
+    }
+
+    fn decode_line(&self, row: &[u16], phase: usize, rgb: &mut Vec<u8>) {
+        // running sums of luma, and of chroma against the subcarrier, so
+        // any window is a subtraction
+        let mut luma = vec![0.0; LINE_SAMPLES + 2 * PADDING + 1];
+        let mut i_sum = luma.clone();
+        let mut q_sum = luma.clone();
+        for s in 0..LINE_SAMPLES + 2 * PADDING {
+            let (mut y, mut c) = (0.0, 0.0);
+            let sample_phase = (phase + s + 12 - PADDING % 12) % 12;
+            if (PADDING..PADDING + LINE_SAMPLES).contains(&s) {
+                let pixel = row[(s - PADDING) / SAMPLES_PER_DOT] as usize % PALETTE_SIZE;
+                let level = self.levels[pixel][sample_phase];
+                (y, c) = match self.preset {
+                    NtscPreset::Composite => (level, level),
+                    _ => (self.luma[pixel], level - self.luma[pixel]),
+                };
+            }
+            luma[s + 1] = luma[s] + y;
+            i_sum[s + 1] = i_sum[s] + c * self.cos[sample_phase];
+            q_sum[s + 1] = q_sum[s] + c * self.sin[sample_phase];
+        }
+
+        let (luma_window, chroma_window) = self.preset.windows();
+        let window = |sums: &[f32], center: usize, width: usize| {
+            sums[center + width - width / 2] - sums[center - width / 2]
+        };
+        for x in 0..NTSC_WIDTH {
+            let center = PADDING + x * SAMPLES_PER_PIXEL + SAMPLES_PER_PIXEL / 2;
+            let y = window(&luma, center, luma_window) / luma_window as f32;
+            let scale = 2.0 / chroma_window as f32;
+            let i = window(&i_sum, center, chroma_window) * scale;
+            let q = window(&q_sum, center, chroma_window) * scale;
+            let (r, g, b) = yiq_to_rgb(y, i, q);
+            rgb.extend([r, g, b]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn frame_of(pixel: impl Fn(usize) -> u16) -> Frame {
+        let mut frame = Frame::new();
+        for y in 0..HEIGHT {
+            for x in 0..WIDTH {
+                frame.set_pixel(x, y, pixel(x));
+            }
+        }
+        frame
+    }
+
+    fn rgb_at(rgb: &[u8], x: usize, y: usize) -> (u8, u8, u8) {
+        let i = (y * NTSC_WIDTH + x) * 3;
+        (rgb[i], rgb[i + 1], rgb[i + 2])
+    }
+
+    #[test]
+    fn test_flat_colors_match_palette() {
+        let palette = Palette::builtin("ntsc").unwrap();
+        for preset in [NtscPreset::Composite, NtscPreset::SVideo, NtscPreset::Rgb] {
+            let filter = NtscFilter::new(preset);
+            for pixel in [0x16, 0x21, 0x30, 0x0F] {
+                let rgb = filter.apply(&frame_of(|_| pixel));
+                let (r, g, b) = rgb_at(&rgb, NTSC_WIDTH / 2, 100);
+                let (pr, pg, pb) = palette.rgb(pixel);
+                let near = |a: u8, b: u8| a.abs_diff(b) <= 24;
+                assert!(
+                    near(r, pr) && near(g, pg) && near(b, pb),
+                    "{:?} {:02X}: {:?}",
+                    preset,
+                    pixel,
+                    (r, g, b)
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_artifact_colors() {
+        // alternating black and white columns, a common dithering pattern
+        let frame = frame_of(|x| if x % 2 == 0 { 0x30 } else { 0x0F });
+        let chroma = |preset| {
+            let rgb = NtscFilter::new(preset).apply(&frame);
+            let (r, g, b) = rgb_at(&rgb, NTSC_WIDTH / 2, 100);
+            r.max(g).max(b) - r.min(g).min(b)
+        };
+        assert!(chroma(NtscPreset::Composite) > 20);
+        assert!(chroma(NtscPreset::SVideo) < 8);
+        assert!(chroma(NtscPreset::Rgb) < 8);
+    }
+
+    #[test]
+    fn test_dot_crawl() {
+        let mut frame = frame_of(|x| if x < WIDTH / 2 { 0x16 } else { 0x30 });
+        let filter = NtscFilter::new(NtscPreset::Composite);
+        let first = filter.apply(&frame);
+        frame
+            .phases
+            .iter_mut()
+            .for_each(|phase| *phase = (*phase + 4) % 12);
+        let second = filter.apply(&frame);
+        assert_ne!(first, second);
+
+        let filter = NtscFilter::new(NtscPreset::Rgb);
+        assert_eq!(filter.apply(&frame).len(), NTSC_WIDTH * HEIGHT * 3);
+    }
+}
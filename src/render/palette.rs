@@ -0,0 +1,284 @@
+use std::f32::consts::PI;
+use std::fmt;
+use std::io;
+use std::path::Path;
+
+// Frame pixels are a 6-bit color with PPUMASK's emphasis bits above it,
+// red, green, blue from bit 6 up (already swapped back on PAL), so every
+// palette has 512 entries.
+pub const EMPHASIS_SHIFT: u16 = 6;
+pub const PALETTE_SIZE: usize = 512;
+
+pub const BUILTIN_PALETTES: [&str; 3] = ["default", "ntsc", "2c03"];
+
+// the 2C02's 64 colors as RGB
+#[rustfmt::skip]
+pub static SYSTEM_PALETTE: [(u8, u8, u8); 64] = [
+    (0x80, 0x80, 0x80), (0x00, 0x3D, 0xA6), (0x00, 0x12, 0xB0), (0x44, 0x00, 0x96),
+    (0xA1, 0x00, 0x5E), (0xC7, 0x00, 0x28), (0xBA, 0x06, 0x00), (0x8C, 0x17, 0x00),
+    (0x5C, 0x2F, 0x00), (0x10, 0x45, 0x00), (0x05, 0x4A, 0x00), (0x00, 0x47, 0x2E),
+    (0x00, 0x41, 0x66), (0x00, 0x00, 0x00), (0x05, 0x05, 0x05), (0x05, 0x05, 0x05),
+    (0xC7, 0xC7, 0xC7), (0x00, 0x77, 0xFF), (0x21, 0x55, 0xFF), (0x82, 0x37, 0xFA),
+    (0xEB, 0x2F, 0xB5), (0xFF, 0x29, 0x50), (0xFF, 0x22, 0x00), (0xD6, 0x32, 0x00),
+    (0xC4, 0x62, 0x00), (0x35, 0x80, 0x00), (0x05, 0x8F, 0x00), (0x00, 0x8A, 0x55),
+    (0x00, 0x99, 0xCC), (0x21, 0x21, 0x21), (0x09, 0x09, 0x09), (0x09, 0x09, 0x09),
+    (0xFF, 0xFF, 0xFF), (0x0F, 0xD7, 0xFF), (0x69, 0xA2, 0xFF), (0xD4, 0x80, 0xFF),
+    (0xFF, 0x45, 0xF3), (0xFF, 0x61, 0x8B), (0xFF, 0x88, 0x33), (0xFF, 0x9C, 0x12),
+    (0xFA, 0xBC, 0x20), (0x9F, 0xE3, 0x0E), (0x2B, 0xF0, 0x35), (0x0C, 0xF0, 0xA4),
+    (0x05, 0xFB, 0xFF), (0x5E, 0x5E, 0x5E), (0x0D, 0x0D, 0x0D), (0x0D, 0x0D, 0x0D),
+    (0xFF, 0xFF, 0xFF), (0xA6, 0xFC, 0xFF), (0xB3, 0xEC, 0xFF), (0xDA, 0xAB, 0xEB),
+    (0xFF, 0xA8, 0xF9), (0xFF, 0xAB, 0xB3), (0xFF, 0xD2, 0xB0), (0xFF, 0xEF, 0xA6),
+    (0xFF, 0xF7, 0x9C), (0xD7, 0xE8, 0x95), (0xA6, 0xED, 0xAF), (0xA2, 0xF2, 0xDA),
+    (0x99, 0xFF, 0xFC), (0xDD, 0xDD, 0xDD), (0x11, 0x11, 0x11), (0x11, 0x11, 0x11),
+];
+
+// the RGB PPU in Vs. System and PlayChoice-10 boards, 3 bits a channel
+#[rustfmt::skip]
+static RGB_PPU_PALETTE: [u16; 64] = [
+    0o333, 0o014, 0o006, 0o326, 0o403, 0o503, 0o510, 0o420,
+    0o320, 0o120, 0o031, 0o040, 0o022, 0o000, 0o000, 0o000,
+    0o555, 0o036, 0o027, 0o407, 0o507, 0o704, 0o700, 0o630,
+    0o430, 0o140, 0o040, 0o053, 0o044, 0o000, 0o000, 0o000,
+    0o777, 0o357, 0o447, 0o637, 0o707, 0o737, 0o740, 0o750,
+    0o660, 0o360, 0o070, 0o276, 0o077, 0o000, 0o000, 0o000,
+    0o777, 0o567, 0o657, 0o757, 0o747, 0o755, 0o764, 0o772,
+    0o773, 0o572, 0o473, 0o276, 0o467, 0o000, 0o000, 0o000,
+];
+
+// composite voltages for the four luma rows, with the color's wave at its
+// low and high points; black and white are what the rest is scaled between
+const SIGNAL_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const SIGNAL_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const SIGNAL_BLACK: f32 = 0.518;
+const SIGNAL_WHITE: f32 = 1.962;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+// lines the hue wheel up with what TVs of the day showed
+const HUE_OFFSET: f32 = 4.0;
+// how much 64-color palettes darken the channels emphasis leaves out
+const RGB_EMPHASIS_ATTENUATION: f32 = 0.816;
+
+// The composite signal the PPU puts out for a pixel, at one of the 12
+// phases of the color subcarrier, scaled to 0.0 at black and 1.0 at white.
+// Emphasis works by pulling the signal down for part of each cycle.
+pub fn composite_level(pixel: u16, phase: usize) -> f32 {
+    let hue = (pixel & 0x0F) as usize;
+    // $xE and $xF are black whatever the row
+    let row = match hue {
+        0x0E | 0x0F => 1,
+        _ => (pixel >> 4 & 0b11) as usize,
+    };
+    let mut low = SIGNAL_LOW[row];
+    let mut high = SIGNAL_HIGH[row];
+    // column 0 is grey at the high level, columns $D-$F at the low one
+    if hue == 0 {
+        low = high;
+    } else if hue >= 0x0D {
+        high = low;
+    }
+    let in_phase = |hue: usize| (hue + phase) % 12 < 6;
+    let mut level = if in_phase(hue) { high } else { low };
+    let emphasis = pixel >> EMPHASIS_SHIFT;
+    if (emphasis & 0b001 != 0 && in_phase(0))
+        || (emphasis & 0b010 != 0 && in_phase(4))
+        || (emphasis & 0b100 != 0 && in_phase(8))
+    {
+        level *= EMPHASIS_ATTENUATION;
+    }
+    (level - SIGNAL_BLACK) / (SIGNAL_WHITE - SIGNAL_BLACK)
+}
+
+// the subcarrier angle a decoder compares phase against
+pub fn phase_angle(phase: f32) -> f32 {
+    PI * (phase + HUE_OFFSET) / 6.0
+}
+
+// FCC YIQ to RGB, clamped
+pub fn yiq_to_rgb(y: f32, i: f32, q: f32) -> (u8, u8, u8) {
+    let to_byte = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+    (
+        to_byte(y + 0.956 * i + 0.621 * q),
+        to_byte(y - 0.272 * i - 0.647 * q),
+        to_byte(y - 1.106 * i + 1.703 * q),
+    )
+}
+
+// what a TV shows for a flat area of one color: the signal averaged over a
+// whole cycle for luma, and against the subcarrier for chroma
+fn decode_flat(pixel: u16) -> (u8, u8, u8) {
+    let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+    for phase in 0..12 {
+        let level = composite_level(pixel, phase);
+        let angle = phase_angle(phase as f32);
+        y += level;
+        i += level * angle.cos();
+        q += level * angle.sin();
+    }
+    yiq_to_rgb(y / 12.0, i / 6.0, q / 6.0)
+}
+
+#[derive(Debug)]
+pub enum PaletteError {
+    Io(io::Error),
+    // .pal files are 64 or 512 colors of 3 bytes
+    Size(usize),
+}
+
+impl fmt::Display for PaletteError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PaletteError::Io(err) => write!(f, "{}", err),
+            PaletteError::Size(size) => write!(
+                f,
+                "palette is {} bytes, expected 192 (64 colors) or 1536 (with emphasis)",
+                size
+            ),
+        }
+    }
+}
+
+impl std::error::Error for PaletteError {}
+
+impl From<io::Error> for PaletteError {
+    fn from(err: io::Error) -> Self {
+        PaletteError::Io(err)
+    }
+}
+
+// the RGB color of every pixel value, emphasis included
+#[derive(Clone)]
+pub struct Palette {
+    colors: Vec<(u8, u8, u8)>,
+}
+
+impl Palette {
+    pub fn builtin(name: &str) -> Option<Palette> {
+        match name.to_ascii_lowercase().as_str() {
+            "default" => Some(Palette::from_colors(&SYSTEM_PALETTE)),
+            "ntsc" => Some(Palette {
+                colors: (0..PALETTE_SIZE as u16).map(decode_flat).collect(),
+            }),
+            "2c03" => {
+                let level = |bits: u16| ((bits & 0b111) * 255 / 7) as u8;
+                let colors: Vec<_> = RGB_PPU_PALETTE
+                    .iter()
+                    .map(|&rgb| (level(rgb >> 6), level(rgb >> 3), level(rgb)))
+                    .collect();
+                Some(Palette::from_colors(&colors))
+            }
+            _ => None,
+        }
+    }
+
+    // a built-in palette by name, or else a .pal file
+    pub fn load(name: &str) -> Result<Palette, PaletteError> {
+        match Palette::builtin(name) {
+            Some(palette) => Ok(palette),
+            None => Palette::from_pal(&std::fs::read(Path::new(name))?),
+        }
+    }
+
+    // .pal files are plain RGB triples, 64 of them or all 512 with emphasis
+    pub fn from_pal(data: &[u8]) -> Result<Palette, PaletteError> {
+        if data.len() != 64 * 3 && data.len() != PALETTE_SIZE * 3 {
+            return Err(PaletteError::Size(data.len()));
+        }
+        let colors: Vec<_> = data
+            .chunks_exact(3)
+            .map(|rgb| (rgb[0], rgb[1], rgb[2]))
+            .collect();
+        match colors.len() {
+            64 => Ok(Palette::from_colors(&colors)),
+            _ => Ok(Palette { colors }),
+        }
+    }
+
+    // makes up the emphasized colors by darkening the other channels
+    fn from_colors(base: &[(u8, u8, u8)]) -> Palette {
+        let colors = (0..PALETTE_SIZE)
+            .map(|pixel| {
+                let (r, g, b) = base[pixel & 0x3F];
+                let emphasis = pixel >> EMPHASIS_SHIFT;
+                if emphasis == 0 {
+                    return (r, g, b);
+                }
+                let dim = |value: u8, bit: usize| match emphasis & bit {
+                    0 => (value as f32 * RGB_EMPHASIS_ATTENUATION) as u8,
+                    _ => value,
+                };
+                (dim(r, 0b001), dim(g, 0b010), dim(b, 0b100))
+            })
+            .collect();
+        Palette { colors }
+    }
+
+    pub fn rgb(&self, pixel: u16) -> (u8, u8, u8) {
+        self.colors[pixel as usize % PALETTE_SIZE]
+    }
+}
+
+impl Default for Palette {
+    fn default() -> Self {
+        Palette::from_colors(&SYSTEM_PALETTE)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn hue(pixel: u16) -> &'static str {
+        let (r, g, b) = Palette::builtin("ntsc").unwrap().rgb(pixel);
+        if r > g && r > b {
+            "red"
+        } else if g > r && g > b {
+            "green"
+        } else if b > r && b > g {
+            "blue"
+        } else {
+            "grey"
+        }
+    }
+
+    #[test]
+    fn test_ntsc_palette() {
+        assert_eq!(hue(0x16), "red");
+        assert_eq!(hue(0x1A), "green");
+        assert_eq!(hue(0x12), "blue");
+        assert_eq!(hue(0x00), "grey");
+        let palette = Palette::builtin("ntsc").unwrap();
+        assert_eq!(palette.rgb(0x0F), (0, 0, 0));
+        assert_eq!(palette.rgb(0x30), (255, 255, 255));
+        // blue emphasis darkens white's red and green
+        let (r, g, b) = palette.rgb(0x30 | 0b100 << EMPHASIS_SHIFT);
+        assert!(r < 255 && g < 255 && b > r);
+    }
+
+    #[test]
+    fn test_pal_file() {
+        let mut data = vec![0; 64 * 3];
+        data[0x21 * 3..0x21 * 3 + 3].copy_from_slice(&[10, 20, 200]);
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.rgb(0x21), (10, 20, 200));
+        // red emphasis leaves red alone
+        let (r, g, b) = palette.rgb(0x21 | 0b001 << EMPHASIS_SHIFT);
+        assert_eq!(r, 10);
+        assert!(g < 20 && b < 200);
+
+        let data: Vec<u8> = (0..PALETTE_SIZE * 3).map(|i| i as u8).collect();
+        let palette = Palette::from_pal(&data).unwrap();
+        assert_eq!(palette.rgb(0x1FF), (0xFD, 0xFE, 0xFF));
+
+        assert!(matches!(
+            Palette::from_pal(&[0; 100]),
+            Err(PaletteError::Size(100))
+        ));
+    }
+
+    #[test]
+    fn test_builtins() {
+        for name in BUILTIN_PALETTES {
+            assert!(Palette::builtin(name).is_some(), "{}", name);
+        }
+        assert_eq!(Palette::builtin("2c03").unwrap().rgb(0x20), (255, 255, 255));
+    }
+}
@@ -0,0 +1,269 @@
+use super::frame::{ppm, WIDTH};
+
+// NES pixels are a little wider than they are tall
+const PIXEL_ASPECT: (usize, usize) = (8, 7);
+// how much of a line's brightness the gap between scanlines keeps
+const SCANLINE_LEVEL: u16 = 160;
+// how much the two other phosphors let through in each grille stripe
+const GRILLE_LEVEL: u16 = 176;
+
+pub const SCALERS: [&str; 4] = ["integer", "scanlines", "aperture", "smooth"];
+
+// a packed RGB picture of any size, what the scalers work on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Image {
+    pub width: usize,
+    pub height: usize,
+    pub rgb: Vec<u8>,
+}
+
+impl Image {
+    pub fn new(width: usize, height: usize, rgb: Vec<u8>) -> Self {
+        assert_eq!(rgb.len(), width * height * 3);
+        Image { width, height, rgb }
+    }
+
+    fn pixel(&self, x: usize, y: usize) -> [u8; 3] {
+        let i = (y * self.width + x) * 3;
+        [self.rgb[i], self.rgb[i + 1], self.rgb[i + 2]]
+    }
+
+    pub fn to_ppm(&self) -> Vec<u8> {
+        ppm(self.width, self.height, &self.rgb)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Scaler {
+    // every pixel a block of the same size
+    Integer,
+    // darkens the bottom of every line, like the gaps a CRT's beam leaves
+    Scanlines,
+    // red, green and blue stripes like a Trinitron's mask
+    Aperture,
+    // doubles the picture, rounding off diagonal edges the way Scale2x and
+    // hq2x do, then scales up the rest of the way
+    Smooth,
+}
+
+impl Scaler {
+    pub fn from_name(name: &str) -> Option<Scaler> {
+        match name.to_ascii_lowercase().as_str() {
+            "integer" => Some(Scaler::Integer),
+            "scanlines" => Some(Scaler::Scanlines),
+            "aperture" => Some(Scaler::Aperture),
+            "smooth" => Some(Scaler::Smooth),
+            _ => None,
+        }
+    }
+}
+
+// Everything runs on the CPU, so it works the same in the window and in
+// headless screenshots. `aspect` stretches the width to the 8:7 pixels a
+// TV shows, the height is always a whole multiple.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Scale {
+    pub scaler: Scaler,
+    pub factor: usize,
+    pub aspect: bool,
+}
+
+impl Scale {
+    // the CRT effects need a line or column to darken, smoothing doubles
+    fn factor(&self) -> usize {
+        match self.scaler {
+            Scaler::Integer => self.factor.max(1),
+            Scaler::Scanlines | Scaler::Aperture => self.factor.max(2),
+            Scaler::Smooth => self.factor.max(2).next_multiple_of(2),
+        }
+    }
+
+    pub fn output_size(&self, width: usize, height: usize) -> (usize, usize) {
+        let factor = self.factor();
+        let width = match self.aspect {
+            // wider sources, like the NTSC filter's, already have narrower
+            // pixels, so it's always the NES picture's width that gets stretched
+            true => (WIDTH * factor * PIXEL_ASPECT.0).div_ceil(PIXEL_ASPECT.1),
+            false => width * factor,
+        };
+        (width, height * factor)
+    }
+
+    pub fn apply(&self, image: &Image) -> Image {
+        let (width, height) = self.output_size(image.width, image.height);
+        let factor = self.factor();
+        let mut out = match self.scaler {
+            Scaler::Smooth => resize(&scale2x(image), width, height),
+            _ => resize(image, width, height),
+        };
+        match self.scaler {
+            Scaler::Scanlines => {
+                for y in 0..height {
+                    // the lower half of each line's rows
+                    if y % factor >= factor.div_ceil(2) {
+                        let row = &mut out.rgb[y * width * 3..(y + 1) * width * 3];
+                        row.iter_mut().for_each(|c| *c = dim(*c, SCANLINE_LEVEL));
+                    }
+                }
+            }
+            Scaler::Aperture => {
+                for (i, c) in out.rgb.iter_mut().enumerate() {
+                    let (x, channel) = (i / 3 % width, i % 3);
+                    if x % 3 != channel {
+                        *c = dim(*c, GRILLE_LEVEL);
+                    }
+                }
+            }
+            _ => {}
+        }
+        out
+    }
+}
+
+fn dim(value: u8, level: u16) -> u8 {
+    (value as u16 * level / 255) as u8
+}
+
+// nearest neighbor, which with a whole multiple is just bigger blocks
+fn resize(image: &Image, width: usize, height: usize) -> Image {
+    let mut rgb = Vec::with_capacity(width * height * 3);
+    for y in 0..height {
+        let source_y = y * image.height / height;
+        for x in 0..width {
+            rgb.extend(image.pixel(x * image.width / width, source_y));
+        }
+    }
+    Image::new(width, height, rgb)
+}
+
+// Colors close enough to count as the same edge, compared in YUV with
+// hq2x's thresholds so noise and shading don't get smoothed into shapes.
+fn similar(a: [u8; 3], b: [u8; 3]) -> bool {
+    let yuv = |[r, g, b]: [u8; 3]| {
+        let (r, g, b) = (r as i32, g as i32, b as i32);
+        (
+            (r + g + b) / 3,
+            (r - b) / 4 + 128,
+            (2 * g - r - b) / 8 + 128,
+        )
+    };
+    let (y1, u1, v1) = yuv(a);
+    let (y2, u2, v2) = yuv(b);
+    (y1 - y2).abs() <= 48 && (u1 - u2).abs() <= 7 && (v1 - v2).abs() <= 6
+}
+
+// Scale2x: each pixel becomes four, and a corner takes its neighbors' color
+// where they meet across it, which turns staircases into diagonals
+fn scale2x(image: &Image) -> Image {
+    let (width, height) = (image.width, image.height);
+    let mut out = vec![0; width * height * 12];
+    for y in 0..height {
+        for x in 0..width {
+            let e = image.pixel(x, y);
+            let b = image.pixel(x, y.saturating_sub(1));
+            let d = image.pixel(x.saturating_sub(1), y);
+            let f = image.pixel((x + 1).min(width - 1), y);
+            let h = image.pixel(x, (y + 1).min(height - 1));
+            let mut corners = [e; 4];
+            if !similar(b, h) && !similar(d, f) {
+                if similar(d, b) {
+                    corners[0] = d;
+                }
+                if similar(b, f) {
+                    corners[1] = f;
+                }
+                if similar(d, h) {
+                    corners[2] = d;
+                }
+                if similar(h, f) {
+                    corners[3] = f;
+                }
+            }
+            for (corner, color) in corners.iter().enumerate() {
+                let (cx, cy) = (x * 2 + corner % 2, y * 2 + corner / 2);
+                let i = (cy * width * 2 + cx) * 3;
+                out[i..i + 3].copy_from_slice(color);
+            }
+        }
+    }
+    Image::new(width * 2, height * 2, out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const WHITE: [u8; 3] = [255, 255, 255];
+    const BLACK: [u8; 3] = [0, 0, 0];
+
+    fn image_of(width: usize, height: usize, pixel: impl Fn(usize, usize) -> [u8; 3]) -> Image {
+        let mut rgb = vec![];
+        for y in 0..height {
+            for x in 0..width {
+                rgb.extend(pixel(x, y));
+            }
+        }
+        Image::new(width, height, rgb)
+    }
+
+    fn scale(scaler: Scaler, factor: usize) -> Scale {
+        Scale {
+            scaler,
+            factor,
+            aspect: false,
+        }
+    }
+
+    #[test]
+    fn test_integer_and_aspect() {
+        let image = image_of(4, 2, |x, _| if x == 1 { WHITE } else { BLACK });
+        let out = scale(Scaler::Integer, 3).apply(&image);
+        assert_eq!((out.width, out.height), (12, 6));
+        assert_eq!(out.pixel(2, 5), BLACK);
+        assert_eq!(out.pixel(3, 5), WHITE);
+        assert_eq!(out.pixel(5, 0), WHITE);
+
+        let aspect = Scale {
+            aspect: true,
+            ..scale(Scaler::Integer, 2)
+        };
+        assert_eq!(aspect.output_size(256, 240), (586, 480));
+        // the NTSC filter's wider picture comes out the same size
+        assert_eq!(aspect.output_size(512, 240), (586, 480));
+    }
+
+    #[test]
+    fn test_scanlines() {
+        let image = image_of(2, 2, |_, _| WHITE);
+        let out = scale(Scaler::Scanlines, 1).apply(&image);
+        assert_eq!((out.width, out.height), (4, 4));
+        assert_eq!(out.pixel(0, 0), WHITE);
+        assert_eq!(out.pixel(0, 1), [160; 3]);
+        assert_eq!(out.pixel(3, 2), WHITE);
+    }
+
+    #[test]
+    fn test_aperture_grille() {
+        let image = image_of(2, 1, |_, _| WHITE);
+        let out = scale(Scaler::Aperture, 3).apply(&image);
+        assert_eq!(out.pixel(0, 0), [255, 176, 176]);
+        assert_eq!(out.pixel(1, 1), [176, 255, 176]);
+        assert_eq!(out.pixel(5, 2), [176, 176, 255]);
+    }
+
+    #[test]
+    fn test_smooth_rounds_off_staircases() {
+        // a white diagonal edge, one pixel step at a time
+        let image = image_of(4, 4, |x, y| if x <= y { WHITE } else { BLACK });
+        let out = scale(Scaler::Smooth, 2).apply(&image);
+        assert_eq!((out.width, out.height), (8, 8));
+        // the black pixel right of the diagonal gets its lower left corner filled in
+        assert_eq!(out.pixel(2, 1), WHITE);
+        assert_eq!(out.pixel(3, 0), BLACK);
+        // the white pixel on the diagonal gets its upper right corner cut off
+        assert_eq!(out.pixel(3, 2), BLACK);
+        assert_eq!(out.pixel(2, 3), WHITE);
+        // a flat area is left alone
+        assert_eq!(out.pixel(0, 7), WHITE);
+    }
+}
@@ -1,36 +1,330 @@
-#[derive(Debug, PartialEq)]
+use crate::region::Region;
+use crate::romdb::{self, Correction, RomDb};
+use crate::state::{Snapshot, StateError, StateReader, StateWriter};
+use std::fmt;
+
+mod unif;
+
+#[derive(Debug, PartialEq, Clone, Copy)]
 pub enum Mirroring {
     Horizontal,
     Vertical,
     FourScreen,
+    SingleScreenLower,
+    SingleScreenUpper,
+}
+
+#[derive(Debug, PartialEq, Clone, Copy)]
+pub enum RomFormat {
+    INes,
+    Nes2,
+    Unif,
+    // a music rip, played through the NSF driver rather than booted
+    Nsf,
+    // a BIOS image, the game itself is on the disk in the RAM adapter
+    Fds,
+}
+
+impl fmt::Display for Mirroring {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Mirroring::Horizontal => "horizontal",
+            Mirroring::Vertical => "vertical",
+            Mirroring::FourScreen => "four-screen",
+            Mirroring::SingleScreenLower => "single-screen lower",
+            Mirroring::SingleScreenUpper => "single-screen upper",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for RomFormat {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            RomFormat::INes => "iNES",
+            RomFormat::Nes2 => "NES 2.0",
+            RomFormat::Unif => "UNIF",
+            RomFormat::Nsf => "NSF",
+            RomFormat::Fds => "FDS",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+// pattern table storage, seen by the PPU at $0000-$1FFF
+#[derive(Debug, PartialEq)]
+pub enum Chr {
+    Rom(Vec<u8>),
+    Ram(Vec<u8>),
+}
+
+impl Chr {
+    pub fn read(&self, addr: u16) -> u8 {
+        let data = self.as_slice();
+        if data.is_empty() {
+            return 0;
+        }
+        data[addr as usize % data.len()]
+    }
+
+    pub fn write(&mut self, addr: u16, data: u8) {
+        // writes to CHR ROM are silently dropped, like on the real bus
+        if let Chr::Ram(ram) = self {
+            if !ram.is_empty() {
+                let len = ram.len();
+                ram[addr as usize % len] = data;
+            }
+        }
+    }
+
+    pub fn is_ram(&self) -> bool {
+        matches!(self, Chr::Ram(_))
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        match self {
+            Chr::Rom(data) | Chr::Ram(data) => data,
+        }
+    }
+}
+
+// only RAM contents belong in a save state, ROM comes from the cartridge
+impl Snapshot for Chr {
+    fn snapshot(&self, w: &mut StateWriter) {
+        if let Chr::Ram(ram) = self {
+            w.write_bytes(ram);
+        }
+    }
+
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError> {
+        if let Chr::Ram(ram) = self {
+            r.read_into("CHR RAM", ram)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct Hash {
+    pub crc32: u32,
+    pub sha1: [u8; 20],
+}
+
+impl Hash {
+    pub fn of(parts: &[&[u8]]) -> Hash {
+        let mut crc32 = crc32fast::Hasher::new();
+        let mut sha1 = sha1_smol::Sha1::new();
+        for part in parts {
+            crc32.update(part);
+            sha1.update(part);
+        }
+        Hash {
+            crc32: crc32.finalize(),
+            sha1: sha1.digest().bytes(),
+        }
+    }
+
+    pub fn sha1_hex(&self) -> String {
+        self.sha1.iter().map(|b| format!("{:02x}", b)).collect()
+    }
+}
+
+impl fmt::Display for Hash {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "crc32 {:08X} sha1 {}", self.crc32, self.sha1_hex())
+    }
+}
+
+#[derive(Debug, PartialEq, Clone, Copy, Default)]
+pub struct RomHashes {
+    // the whole file as loaded, header included
+    pub full: Hash,
+    pub prg: Hash,
+    pub chr: Hash,
+    // PRG followed by CHR, the key used by the ROM database
+    pub prg_chr: Hash,
 }
 
 pub struct Rom {
+    pub format: RomFormat,
     pub prg_rom: Vec<u8>,
-    pub chr_rom: Vec<u8>,
-    pub mapper: u8,
+    pub chr: Chr,
+    pub trainer: Option<Vec<u8>>,
+    pub mapper: u16,
+    pub submapper: u8,
     pub screen_mirroring: Mirroring,
+    pub battery: bool,
+    pub region: Region,
+    pub warnings: Vec<RomWarning>,
+    // header fields overridden by the ROM database
+    pub corrections: Vec<Correction>,
+    hashes: RomHashes,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RomError {
+    TooShort { len: usize },
+    BadMagic,
+    UnsupportedFormat(String),
+    NoPrgRom,
+    TruncatedTrainer { expected: usize, actual: usize },
+    TruncatedPrg { expected: usize, actual: usize },
+    TruncatedChr { expected: usize, actual: usize },
+    TruncatedChunk(String),
+    MissingChunk(&'static str),
+    UnknownBoard(String),
+}
+
+impl fmt::Display for RomError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomError::TooShort { len } => write!(
+                f,
+                "file is too short to be a ROM ({} bytes, header needs {})",
+                len, HEADER_SIZE
+            ),
+            RomError::BadMagic => write!(f, "Invalid iNES file"),
+            RomError::UnsupportedFormat(format) => write!(f, "{} is not supported", format),
+            RomError::NoPrgRom => write!(f, "header declares no PRG ROM"),
+            RomError::TruncatedTrainer { expected, actual } => write!(
+                f,
+                "trainer is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            RomError::TruncatedPrg { expected, actual } => write!(
+                f,
+                "PRG ROM is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            RomError::TruncatedChr { expected, actual } => write!(
+                f,
+                "CHR ROM is truncated: expected {} bytes, found {}",
+                expected, actual
+            ),
+            RomError::TruncatedChunk(id) => write!(f, "UNIF chunk {} is truncated", id),
+            RomError::MissingChunk(id) => write!(f, "UNIF file has no {} chunk", id),
+            RomError::UnknownBoard(board) => write!(f, "unknown UNIF board {:?}", board),
+        }
+    }
+}
+
+impl std::error::Error for RomError {}
+
+#[derive(Debug, PartialEq)]
+pub enum RomWarning {
+    // bytes 7-15 hold garbage, usually a ripper signature like "DiskDude!"
+    DirtyHeader,
+    TrailingData { len: usize },
+}
+
+impl fmt::Display for RomWarning {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RomWarning::DirtyHeader => write!(
+                f,
+                "header bytes 7-15 contain junk, ignoring the upper mapper nibble"
+            ),
+            RomWarning::TrailingData { len } => {
+                write!(f, "{} unused bytes after the end of CHR ROM", len)
+            }
+        }
+    }
 }
 
 const NES_TAG: [u8; 4] = [0x4e, 0x45, 0x53, 0x1a];
+const HEADER_SIZE: usize = 16;
+const TRAINER_SIZE: usize = 512;
 const PRG_ROM_PAGE_SIZE: usize = 16384; // 16KB
 const CHR_ROM_PAGE_SIZE: usize = 8192; // 8KB
+const DEFAULT_CHR_RAM_SIZE: usize = 8192; // 8KB
+
+// NES 2.0 ROM sizes: a plain page count, or 2^E * (MM*2+1) bytes when the
+// MSB nibble is $F and the LSB byte is EEEEEEMM
+fn nes2_rom_size(lsb: u8, msb: u8, page_size: usize) -> usize {
+    if msb == 0xF {
+        let exponent = (lsb >> 2) as u32;
+        let multiplier = (lsb & 0b11) as usize * 2 + 1;
+        (1usize << exponent).saturating_mul(multiplier)
+    } else {
+        ((msb as usize) << 8 | lsb as usize) * page_size
+    }
+}
+
+// NES 2.0 RAM sizes are stored as a shift count, 0 meaning none
+fn nes2_ram_size(shift: u8) -> usize {
+    if shift == 0 {
+        0
+    } else {
+        64 << shift
+    }
+}
+
+// returns the requested slice, or how many bytes were actually left
+fn take(raw: &[u8], start: usize, len: usize) -> Result<&[u8], usize> {
+    match start.checked_add(len).and_then(|end| raw.get(start..end)) {
+        Some(slice) => Ok(slice),
+        None => Err(raw.len().saturating_sub(start)),
+    }
+}
 
 impl Rom {
-    pub fn new(raw: &Vec<u8>) -> Result<Self, String> {
-        if &raw[0..4] != &NES_TAG {
-            return Err("Invalid iNES file".to_string());
+    pub fn new(raw: &[u8]) -> Result<Self, RomError> {
+        if raw.len() < HEADER_SIZE {
+            return Err(RomError::TooShort { len: raw.len() });
+        }
+        if raw.starts_with(unif::UNIF_TAG) {
+            return unif::parse(raw);
+        }
+        if raw[0..4] != NES_TAG {
+            return Err(match &raw[0..4] {
+                b"NESM" => RomError::UnsupportedFormat("NSF".to_string()),
+                b"FDS\x1a" | b"\x01*NI" => RomError::UnsupportedFormat("FDS".to_string()),
+                _ => RomError::BadMagic,
+            });
         }
 
-        let mapper = (raw[7] & 0b1111_0000) | (raw[6] >> 4);
-        // if mapper != 0 {
-        //     return Err(format!("Unsupported mapper: {}", mapper));
-        // }
+        let mut warnings = vec![];
 
-        let ines_ver = (raw[7] >> 2) & 0b11;
-        if ines_ver != 0 {
-            return Err("NES v2 is not supported".to_string());
-        }
+        let format = match (raw[7] >> 2) & 0b11 {
+            2 => RomFormat::Nes2,
+            _ => RomFormat::INes,
+        };
+
+        let mut clean_ines = false;
+        let (mapper, submapper) = match format {
+            RomFormat::Nes2 => (
+                (raw[8] as u16 & 0b1111) << 8
+                    | (raw[7] & 0b1111_0000) as u16
+                    | (raw[6] >> 4) as u16,
+                raw[8] >> 4,
+            ),
+            _ => {
+                // iNES 1.0 leaves bytes 11-15 zeroed; anything else (or the
+                // reserved version bits being set) means byte 7 can't be
+                // trusted either
+                let dirty = raw[7] & 0b1100 != 0 || raw[11..16].iter().any(|&b| b != 0);
+                let mapper_hi = if dirty {
+                    warnings.push(RomWarning::DirtyHeader);
+                    0
+                } else {
+                    clean_ines = true;
+                    raw[7] & 0b1111_0000
+                };
+                ((mapper_hi | (raw[6] >> 4)) as u16, 0)
+            }
+        };
+
+        let region = match format {
+            RomFormat::Nes2 => match raw[12] & 0b11 {
+                0 => Region::Ntsc,
+                1 => Region::Pal,
+                2 => Region::Multi,
+                _ => Region::Dendy,
+            },
+            // byte 9 is rarely set, but it's all iNES 1.0 has
+            _ if clean_ines && raw[9] & 0b1 != 0 => Region::Pal,
+            _ => Region::Ntsc,
+        };
 
         let four_screen = raw[6] & 0b1000 != 0;
         let vertical = raw[6] & 0b1 != 0;
@@ -40,20 +334,125 @@ impl Rom {
             (false, false) => Mirroring::Horizontal,
         };
 
-        let prg_rom_size = raw[4] as usize * PRG_ROM_PAGE_SIZE;
-        let chr_rom_size = raw[5] as usize * CHR_ROM_PAGE_SIZE;
+        let (prg_rom_size, chr_rom_size, chr_ram_size) = match format {
+            RomFormat::Nes2 => (
+                nes2_rom_size(raw[4], raw[9] & 0b1111, PRG_ROM_PAGE_SIZE),
+                nes2_rom_size(raw[5], raw[9] >> 4, CHR_ROM_PAGE_SIZE),
+                nes2_ram_size(raw[11] & 0b1111) + nes2_ram_size(raw[11] >> 4),
+            ),
+            _ => (
+                raw[4] as usize * PRG_ROM_PAGE_SIZE,
+                raw[5] as usize * CHR_ROM_PAGE_SIZE,
+                0,
+            ),
+        };
+        if prg_rom_size == 0 {
+            return Err(RomError::NoPrgRom);
+        }
 
+        let battery = raw[6] & 0b10 != 0;
         let skip_trainer = raw[6] & 0b100 != 0;
+        let trainer_size = if skip_trainer { TRAINER_SIZE } else { 0 };
+        let trainer =
+            take(raw, HEADER_SIZE, trainer_size).map_err(|actual| RomError::TruncatedTrainer {
+                expected: trainer_size,
+                actual,
+            })?;
+
+        let prg_rom_start = HEADER_SIZE + trainer_size;
+        let prg_rom =
+            take(raw, prg_rom_start, prg_rom_size).map_err(|actual| RomError::TruncatedPrg {
+                expected: prg_rom_size,
+                actual,
+            })?;
 
-        let prg_rom_start = 16 + if skip_trainer { 512 } else { 0 };
         let chr_rom_start = prg_rom_start + prg_rom_size;
+        let chr_rom =
+            take(raw, chr_rom_start, chr_rom_size).map_err(|actual| RomError::TruncatedChr {
+                expected: chr_rom_size,
+                actual,
+            })?;
+
+        let trailing = raw.len() - (chr_rom_start + chr_rom_size);
+        if trailing > 0 {
+            warnings.push(RomWarning::TrailingData { len: trailing });
+        }
 
-        Ok(Rom {
-            prg_rom: raw[prg_rom_start..(prg_rom_start + prg_rom_size)].to_vec(),
-            chr_rom: raw[chr_rom_start..(chr_rom_start + chr_rom_size)].to_vec(),
+        // no CHR ROM means the board has CHR RAM instead; old headers can't
+        // say how much, and the common case is a single 8KB bank
+        let chr = if chr_rom_size > 0 {
+            Chr::Rom(chr_rom.to_vec())
+        } else if chr_ram_size > 0 {
+            Chr::Ram(vec![0; chr_ram_size])
+        } else {
+            Chr::Ram(vec![0; DEFAULT_CHR_RAM_SIZE])
+        };
+
+        let rom = Rom {
+            format,
+            prg_rom: prg_rom.to_vec(),
+            chr,
+            trainer: if skip_trainer {
+                Some(trainer.to_vec())
+            } else {
+                None
+            },
             mapper,
+            submapper,
             screen_mirroring,
-        })
+            battery,
+            region,
+            warnings,
+            corrections: vec![],
+            hashes: RomHashes::default(),
+        };
+        Ok(rom.finish(raw))
+    }
+
+    pub fn hashes(&self) -> &RomHashes {
+        &self.hashes
+    }
+
+    // a bare PRG image with CHR RAM, for files that aren't cartridge dumps
+    pub fn from_prg(format: RomFormat, prg_rom: Vec<u8>, region: Region) -> Rom {
+        let rom = Rom {
+            format,
+            prg_rom,
+            chr: Chr::Ram(vec![0; DEFAULT_CHR_RAM_SIZE]),
+            trainer: None,
+            mapper: 0,
+            submapper: 0,
+            screen_mirroring: Mirroring::Horizontal,
+            battery: false,
+            region,
+            warnings: vec![],
+            corrections: vec![],
+            hashes: RomHashes::default(),
+        };
+        let raw = rom.prg_rom.clone();
+        rom.finish(&raw)
+    }
+
+    // hashing and database corrections shared by every file format
+    fn finish(mut self, raw: &[u8]) -> Rom {
+        let chr_rom: &[u8] = match &self.chr {
+            Chr::Rom(data) => data,
+            Chr::Ram(_) => &[],
+        };
+        self.hashes = RomHashes {
+            full: Hash::of(&[raw]),
+            prg: Hash::of(&[&self.prg_rom]),
+            chr: Hash::of(&[chr_rom]),
+            prg_chr: Hash::of(&[&self.prg_rom, chr_rom]),
+        };
+        self.apply_db(romdb::builtin());
+        self
+    }
+
+    fn apply_db(&mut self, db: &RomDb) {
+        if let Some(entry) = db.lookup(self.hashes.prg_chr.crc32) {
+            self.corrections = entry.apply(self);
+        }
     }
 }
 
@@ -61,6 +460,7 @@ impl Rom {
 pub mod test {
 
     use super::*;
+    use rand::Rng;
     use std::fs;
 
     struct TestRom {
@@ -114,7 +514,7 @@ pub mod test {
 
         let rom: Rom = Rom::new(&test_rom).unwrap();
 
-        assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.chr, Chr::Rom(vec!(2; 1 * CHR_ROM_PAGE_SIZE)));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
@@ -148,26 +548,254 @@ pub mod test {
 
         let rom: Rom = Rom::new(&test_rom).unwrap();
 
-        assert_eq!(rom.chr_rom, vec!(2; 1 * CHR_ROM_PAGE_SIZE));
+        assert_eq!(rom.trainer, Some(vec![0; TRAINER_SIZE]));
+        assert_eq!(rom.chr, Chr::Rom(vec!(2; 1 * CHR_ROM_PAGE_SIZE)));
         assert_eq!(rom.prg_rom, vec!(1; 2 * PRG_ROM_PAGE_SIZE));
         assert_eq!(rom.mapper, 3);
         assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
     }
 
     #[test]
-    fn test_nes2_is_not_supported() {
+    fn test_nes2() {
         let test_rom = create_rom(TestRom {
             header: vec![
-                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 00, 00, 00, 00, 00, 00, 00, 00,
+                0x4E, 0x45, 0x53, 0x1A, 0x01, 0x01, 0x31, 0x8, 0x21, 00, 00, 00, 00, 00, 00, 00,
             ],
             trainer: None,
-            pgp_rom: vec![1; 1 * PRG_ROM_PAGE_SIZE],
-            chr_rom: vec![2; 1 * CHR_ROM_PAGE_SIZE],
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.format, RomFormat::Nes2);
+        assert_eq!(rom.mapper, 0x103);
+        assert_eq!(rom.submapper, 2);
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+        assert_eq!(rom.chr, Chr::Rom(vec![2; CHR_ROM_PAGE_SIZE]));
+    }
+
+    #[test]
+    fn test_nes2_exponent_size() {
+        // PRG size 2^14 * 1 written in exponent-multiplier form
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0b111000, 0x00, 0x00, 0x8, 00, 0x0F, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.prg_rom.len(), PRG_ROM_PAGE_SIZE);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x21, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let mut rom = Rom::new(&test_rom).unwrap();
+        assert!(rom.chr.is_ram());
+        assert_eq!(rom.chr.as_slice().len(), DEFAULT_CHR_RAM_SIZE);
+        rom.chr.write(0x1234, 0xAB);
+        assert_eq!(rom.chr.read(0x1234), 0xAB);
+    }
+
+    #[test]
+    fn test_nes2_chr_ram_size() {
+        // CHR-RAM shift 7 = 64 << 7 = 8KB, CHR-NVRAM shift 9 = 32KB
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x00, 0x21, 0x8, 00, 00, 00, 0x97, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.chr, Chr::Ram(vec![0; 0x2000 + 0x8000]));
+    }
+
+    #[test]
+    fn test_chr_rom_is_read_only() {
+        let mut chr = Chr::Rom(vec![2; CHR_ROM_PAGE_SIZE]);
+        chr.write(0x0010, 0xFF);
+        assert_eq!(chr.read(0x0010), 2);
+    }
+
+    #[test]
+    fn test_too_short() {
+        assert_eq!(
+            Rom::new(&NES_TAG).err(),
+            Some(RomError::TooShort { len: 4 })
+        );
+    }
+
+    #[test]
+    fn test_bad_magic() {
+        assert_eq!(Rom::new(&[0; 64]).err(), Some(RomError::BadMagic));
+    }
+
+    #[test]
+    fn test_truncated_prg() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![],
+        });
+
+        assert_eq!(
+            Rom::new(&test_rom).err(),
+            Some(RomError::TruncatedPrg {
+                expected: 2 * PRG_ROM_PAGE_SIZE,
+                actual: PRG_ROM_PAGE_SIZE
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated_chr() {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; 100],
         });
-        let rom = Rom::new(&test_rom);
-        match rom {
-            Result::Ok(_) => assert!(false, "should not load rom"),
-            Result::Err(str) => assert_eq!(str, "NES v2 is not supported"),
+
+        assert_eq!(
+            Rom::new(&test_rom).err(),
+            Some(RomError::TruncatedChr {
+                expected: CHR_ROM_PAGE_SIZE,
+                actual: 100
+            })
+        );
+    }
+
+    #[test]
+    fn test_dirty_header() {
+        let mut header = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31];
+        header.extend(b"DiskDude!");
+        let test_rom = create_rom(TestRom {
+            header,
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.mapper, 3);
+        assert_eq!(rom.warnings, vec![RomWarning::DirtyHeader]);
+    }
+
+    #[test]
+    fn test_db_corrects_dirty_header() {
+        // trusting byte 7 would make this mapper 64, the database then only
+        // has the mirroring and battery to put right
+        let mut header = vec![0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x00];
+        header.extend(b"DiskDude!");
+        let test_rom = create_rom(TestRom {
+            header,
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+
+        let mut rom = Rom::new(&test_rom).unwrap();
+        assert_eq!(rom.mapper, 0);
+        assert_eq!(rom.corrections, vec![]);
+        let db = RomDb::parse(&format!(
+            "{:08X} mapper=0 mirroring=v battery=1",
+            rom.hashes().prg_chr.crc32
+        ))
+        .unwrap();
+        rom.apply_db(&db);
+        assert_eq!(
+            rom.corrections,
+            vec![
+                Correction::Mirroring(Mirroring::Horizontal, Mirroring::Vertical),
+                Correction::Battery(false, true)
+            ]
+        );
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+    }
+
+    #[test]
+    fn test_hashes() {
+        let rom = test_rom();
+        let hashes = rom.hashes();
+        assert_eq!(hashes.prg, Hash::of(&[&vec![1; 2 * PRG_ROM_PAGE_SIZE]]));
+        assert_eq!(hashes.chr, Hash::of(&[&vec![2; CHR_ROM_PAGE_SIZE]]));
+        assert_eq!(
+            hashes.prg_chr,
+            Hash::of(&[&rom.prg_rom, rom.chr.as_slice()])
+        );
+        assert_ne!(hashes.full, hashes.prg_chr);
+    }
+
+    #[test]
+    fn test_known_hash() {
+        let hash = Hash::of(&[b"abc"]);
+        assert_eq!(hash.crc32, 0x352441C2);
+        assert_eq!(hash.sha1_hex(), "a9993e364706816aba3e25717850c26c9cd0d89d");
+    }
+
+    #[test]
+    fn test_region() {
+        assert_eq!(test_rom().region, Region::Ntsc);
+
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, 0x31, 0x8, 00, 00, 00, 00, 0x03, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        assert_eq!(Rom::new(&test_rom).unwrap().region, Region::Dendy);
+    }
+
+    #[test]
+    fn test_battery() {
+        assert!(test_rom_with_header_byte6(0x33).battery);
+        assert!(!test_rom_with_header_byte6(0x31).battery);
+    }
+
+    fn test_rom_with_header_byte6(flags: u8) -> Rom {
+        let test_rom = create_rom(TestRom {
+            header: vec![
+                0x4E, 0x45, 0x53, 0x1A, 0x02, 0x01, flags, 00, 00, 00, 00, 00, 00, 00, 00, 00,
+            ],
+            trainer: None,
+            pgp_rom: vec![1; 2 * PRG_ROM_PAGE_SIZE],
+            chr_rom: vec![2; CHR_ROM_PAGE_SIZE],
+        });
+        Rom::new(&test_rom).unwrap()
+    }
+
+    #[test]
+    fn test_garbage_never_panics() {
+        let mut rng = rand::thread_rng();
+        for _ in 0..10_000 {
+            let len = rng.gen_range(0..64);
+            let mut raw: Vec<u8> = (0..len).map(|_| rng.gen()).collect();
+            if len >= 4 {
+                raw[0..4].copy_from_slice(&NES_TAG);
+            }
+            let _ = Rom::new(&raw);
         }
     }
 
@@ -0,0 +1,294 @@
+use super::{Chr, Mirroring, Rom, RomError, RomFormat, RomHashes, DEFAULT_CHR_RAM_SIZE};
+use crate::region::Region;
+
+// UNIF: a 32 byte header followed by chunks of [4 byte id][u32 LE length][data]
+
+pub const UNIF_TAG: &[u8] = b"UNIF";
+const HEADER_SIZE: usize = 32;
+
+// UNIF names the board instead of giving an iNES mapper number. Names are
+// matched without their NES-/UNL-/HVC-/BTL-/BMC- prefix and ignoring case.
+const BOARDS: &[(&str, u16)] = &[
+    ("NROM", 0),
+    ("NROM-128", 0),
+    ("NROM-256", 0),
+    ("RROM", 0),
+    ("RROM-128", 0),
+    ("SAROM", 1),
+    ("SBROM", 1),
+    ("SCROM", 1),
+    ("SEROM", 1),
+    ("SFROM", 1),
+    ("SGROM", 1),
+    ("SHROM", 1),
+    ("SJROM", 1),
+    ("SKROM", 1),
+    ("SLROM", 1),
+    ("SL1ROM", 1),
+    ("SNROM", 1),
+    ("SOROM", 1),
+    ("SUROM", 1),
+    ("SXROM", 1),
+    ("UNROM", 2),
+    ("UOROM", 2),
+    ("CNROM", 3),
+    ("TBROM", 4),
+    ("TEROM", 4),
+    ("TFROM", 4),
+    ("TGROM", 4),
+    ("TKROM", 4),
+    ("TLROM", 4),
+    ("TR1ROM", 4),
+    ("TSROM", 4),
+    ("TVROM", 4),
+    ("B4", 4),
+    ("EKROM", 5),
+    ("ELROM", 5),
+    ("ETROM", 5),
+    ("EWROM", 5),
+    ("AMROM", 7),
+    ("ANROM", 7),
+    ("AOROM", 7),
+    ("PNROM", 9),
+    ("PEEOROM", 9),
+    ("FJROM", 10),
+    ("FKROM", 10),
+    ("CPROM", 13),
+    ("SL1632", 14),
+    ("BNROM", 34),
+    ("GNROM", 66),
+    ("MHROM", 66),
+    ("TLSROM", 118),
+    ("TKSROM", 118),
+    ("TQROM", 119),
+    ("Sachen-8259D", 137),
+    ("Sachen-8259B", 138),
+    ("Sachen-8259C", 139),
+    ("Sachen-8259A", 141),
+    ("SA-72008", 133),
+    ("SA-NROM", 143),
+    ("SA-72007", 145),
+    ("SA-016-1M", 146),
+    ("TC-U01-1.5M", 147),
+    ("SA-0037", 148),
+    ("SA-0036", 149),
+    ("Sachen-74LS374N", 150),
+    ("Supervision16in1", 53),
+    ("MARIO1-MALEE2", 55),
+    ("D1038", 59),
+    ("8237", 215),
+    ("42in1ResetSwitch", 233),
+    ("70in1", 236),
+    ("70in1B", 236),
+];
+
+const PREFIXES: [&str; 5] = ["NES-", "UNL-", "HVC-", "BTL-", "BMC-"];
+
+pub fn board_mapper(board: &str) -> Option<u16> {
+    let name = PREFIXES
+        .iter()
+        .find(|prefix| {
+            board
+                .get(..prefix.len())
+                .is_some_and(|head| head.eq_ignore_ascii_case(prefix))
+        })
+        .map_or(board, |prefix| &board[prefix.len()..]);
+    BOARDS
+        .iter()
+        .find(|(known, _)| known.eq_ignore_ascii_case(name))
+        .map(|&(_, mapper)| mapper)
+}
+
+// PRG0-PRGF and CHR0-CHRF are concatenated in order of their hex suffix
+fn bank_index(id: &[u8], kind: &[u8]) -> Option<usize> {
+    if &id[0..3] != kind {
+        return None;
+    }
+    (id[3] as char).to_digit(16).map(|i| i as usize)
+}
+
+pub fn parse(raw: &[u8]) -> Result<Rom, RomError> {
+    if raw.len() < HEADER_SIZE {
+        return Err(RomError::TooShort { len: raw.len() });
+    }
+
+    let mut board = None;
+    let mut prg_banks: [Option<&[u8]>; 16] = [None; 16];
+    let mut chr_banks: [Option<&[u8]>; 16] = [None; 16];
+    let mut screen_mirroring = Mirroring::Horizontal;
+    let mut battery = false;
+    let mut region = Region::Ntsc;
+
+    let mut pos = HEADER_SIZE;
+    while pos < raw.len() {
+        let header = raw
+            .get(pos..pos + 8)
+            .ok_or_else(|| RomError::TruncatedChunk("header".to_string()))?;
+        let id = &header[0..4];
+        let len = u32::from_le_bytes([header[4], header[5], header[6], header[7]]) as usize;
+        let data = (pos + 8)
+            .checked_add(len)
+            .and_then(|end| raw.get(pos + 8..end))
+            .ok_or_else(|| RomError::TruncatedChunk(String::from_utf8_lossy(id).into_owned()))?;
+        pos += 8 + len;
+
+        if let Some(i) = bank_index(id, b"PRG") {
+            prg_banks[i] = Some(data);
+        } else if let Some(i) = bank_index(id, b"CHR") {
+            chr_banks[i] = Some(data);
+        } else {
+            match id {
+                b"MAPR" => {
+                    let name = data.split(|&b| b == 0).next().unwrap_or_default();
+                    board = Some(String::from_utf8_lossy(name).trim().to_string());
+                }
+                b"MIRR" => {
+                    screen_mirroring = match data.first() {
+                        Some(1) => Mirroring::Vertical,
+                        Some(2) => Mirroring::SingleScreenLower,
+                        Some(3) => Mirroring::SingleScreenUpper,
+                        Some(4) => Mirroring::FourScreen,
+                        // 0 is horizontal, 5 means the mapper controls it
+                        _ => Mirroring::Horizontal,
+                    }
+                }
+                b"BATR" => battery = true,
+                b"TVCI" => {
+                    region = match data.first() {
+                        Some(1) => Region::Pal,
+                        Some(2) => Region::Multi,
+                        _ => Region::Ntsc,
+                    }
+                }
+                // NAME, READ, DINF, CTRL, checksums and the like
+                _ => {}
+            }
+        }
+    }
+
+    let board = board.ok_or(RomError::MissingChunk("MAPR"))?;
+    let mapper = board_mapper(&board).ok_or(RomError::UnknownBoard(board))?;
+
+    let prg_rom: Vec<u8> = prg_banks
+        .iter()
+        .flatten()
+        .flat_map(|b| b.iter())
+        .copied()
+        .collect();
+    let chr_rom: Vec<u8> = chr_banks
+        .iter()
+        .flatten()
+        .flat_map(|b| b.iter())
+        .copied()
+        .collect();
+    if prg_rom.is_empty() {
+        return Err(RomError::NoPrgRom);
+    }
+
+    let chr = if chr_rom.is_empty() {
+        Chr::Ram(vec![0; DEFAULT_CHR_RAM_SIZE])
+    } else {
+        Chr::Rom(chr_rom)
+    };
+
+    let rom = Rom {
+        format: RomFormat::Unif,
+        prg_rom,
+        chr,
+        trainer: None,
+        mapper,
+        submapper: 0,
+        screen_mirroring,
+        battery,
+        region,
+        warnings: vec![],
+        corrections: vec![],
+        hashes: RomHashes::default(),
+    };
+    Ok(rom.finish(raw))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8], data: &[u8]) -> Vec<u8> {
+        let mut out = id.to_vec();
+        out.extend((data.len() as u32).to_le_bytes());
+        out.extend(data);
+        out
+    }
+
+    fn unif(chunks: &[Vec<u8>]) -> Vec<u8> {
+        let mut out = UNIF_TAG.to_vec();
+        out.extend(7u32.to_le_bytes());
+        out.resize(HEADER_SIZE, 0);
+        for c in chunks {
+            out.extend(c);
+        }
+        out
+    }
+
+    #[test]
+    fn test_parse() {
+        let raw = unif(&[
+            chunk(b"MAPR", b"NES-TLROM\0"),
+            chunk(b"NAME", b"Some Game\0"),
+            chunk(b"PRG1", &[2; 16]),
+            chunk(b"PRG0", &[1; 16]),
+            chunk(b"CHR0", &[3; 8]),
+            chunk(b"MIRR", &[1]),
+            chunk(b"BATR", &[1]),
+            chunk(b"TVCI", &[1]),
+        ]);
+
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.format, RomFormat::Unif);
+        assert_eq!(rom.mapper, 4);
+        assert_eq!(rom.prg_rom, [[1; 16], [2; 16]].concat());
+        assert_eq!(rom.chr, Chr::Rom(vec![3; 8]));
+        assert_eq!(rom.screen_mirroring, Mirroring::Vertical);
+        assert!(rom.battery);
+        assert_eq!(rom.region, Region::Pal);
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        let raw = unif(&[chunk(b"MAPR", b"UNL-UNROM\0"), chunk(b"PRG0", &[1; 16])]);
+        let rom = Rom::new(&raw).unwrap();
+        assert_eq!(rom.mapper, 2);
+        assert!(rom.chr.is_ram());
+    }
+
+    #[test]
+    fn test_board_names() {
+        assert_eq!(board_mapper("NES-SNROM"), Some(1));
+        assert_eq!(board_mapper("unl-sachen-8259a"), Some(141));
+        assert_eq!(board_mapper("BMC-70in1B"), Some(236));
+        assert_eq!(board_mapper("FOO"), None);
+    }
+
+    #[test]
+    fn test_unknown_board() {
+        let raw = unif(&[chunk(b"MAPR", b"UNL-NOPE\0"), chunk(b"PRG0", &[1; 16])]);
+        let err = Rom::new(&raw).err().unwrap();
+        assert_eq!(err, RomError::UnknownBoard("UNL-NOPE".to_string()));
+        assert_eq!(err.to_string(), "unknown UNIF board \"UNL-NOPE\"");
+    }
+
+    #[test]
+    fn test_missing_board() {
+        let raw = unif(&[chunk(b"PRG0", &[1; 16])]);
+        assert_eq!(Rom::new(&raw).err(), Some(RomError::MissingChunk("MAPR")));
+    }
+
+    #[test]
+    fn test_truncated_chunk() {
+        let mut raw = unif(&[chunk(b"MAPR", b"NES-NROM\0"), chunk(b"PRG0", &[1; 16])]);
+        raw.truncate(raw.len() - 1);
+        assert_eq!(
+            Rom::new(&raw).err(),
+            Some(RomError::TruncatedChunk("PRG0".to_string()))
+        );
+    }
+}
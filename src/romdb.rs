@@ -0,0 +1,209 @@
+use crate::region::Region;
+use crate::rom::{Mirroring, Rom};
+use lazy_static::lazy_static;
+use std::collections::HashMap;
+use std::fmt;
+
+// Header corrections for dumps whose iNES header is known to be wrong.
+//
+// Entries are keyed by the CRC32 of PRG ROM followed by CHR ROM (no header,
+// no trainer), so a bad header doesn't change the key. Each line is
+//
+//     <crc32> [mapper=N] [submapper=N] [mirroring=h|v|4] [battery=0|1]
+//             [region=ntsc|pal|dendy|multi] [# title]
+//
+// and only the fields that are present override the header.
+
+#[derive(Debug, PartialEq, Default)]
+pub struct DbEntry {
+    pub mapper: Option<u16>,
+    pub submapper: Option<u8>,
+    pub mirroring: Option<Mirroring>,
+    pub battery: Option<bool>,
+    pub region: Option<Region>,
+    pub title: String,
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub enum Correction {
+    Mapper(u16, u16),
+    Submapper(u8, u8),
+    Mirroring(Mirroring, Mirroring),
+    Battery(bool, bool),
+    Region(Region, Region),
+}
+
+impl fmt::Display for Correction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Correction::Mapper(from, to) => write!(f, "mapper {} -> {}", from, to),
+            Correction::Submapper(from, to) => write!(f, "submapper {} -> {}", from, to),
+            Correction::Mirroring(from, to) => write!(f, "mirroring {} -> {}", from, to),
+            Correction::Battery(from, to) => write!(f, "battery {} -> {}", from, to),
+            Correction::Region(from, to) => write!(f, "region {} -> {}", from, to),
+        }
+    }
+}
+
+impl DbEntry {
+    // overrides the header fields this entry knows about, returning what changed
+    pub fn apply(&self, rom: &mut Rom) -> Vec<Correction> {
+        let mut corrections = vec![];
+        if let Some(mapper) = self.mapper.filter(|&m| m != rom.mapper) {
+            corrections.push(Correction::Mapper(rom.mapper, mapper));
+            rom.mapper = mapper;
+        }
+        if let Some(submapper) = self.submapper.filter(|&s| s != rom.submapper) {
+            corrections.push(Correction::Submapper(rom.submapper, submapper));
+            rom.submapper = submapper;
+        }
+        if let Some(mirroring) = self.mirroring.filter(|&m| m != rom.screen_mirroring) {
+            corrections.push(Correction::Mirroring(rom.screen_mirroring, mirroring));
+            rom.screen_mirroring = mirroring;
+        }
+        if let Some(battery) = self.battery.filter(|&b| b != rom.battery) {
+            corrections.push(Correction::Battery(rom.battery, battery));
+            rom.battery = battery;
+        }
+        if let Some(region) = self.region.filter(|&r| r != rom.region) {
+            corrections.push(Correction::Region(rom.region, region));
+            rom.region = region;
+        }
+        corrections
+    }
+}
+
+pub struct RomDb {
+    entries: HashMap<u32, DbEntry>,
+}
+
+impl RomDb {
+    pub fn parse(text: &str) -> Result<RomDb, String> {
+        let mut entries = HashMap::new();
+        for (line_no, line) in text.lines().enumerate() {
+            let (fields, title) = match line.split_once('#') {
+                Some((fields, title)) => (fields, title.trim()),
+                None => (line, ""),
+            };
+            let mut fields = fields.split_whitespace();
+            let crc = match fields.next() {
+                Some(crc) => u32::from_str_radix(crc, 16)
+                    .map_err(|_| format!("line {}: bad crc32 {:?}", line_no + 1, crc))?,
+                None => continue,
+            };
+            let mut entry = DbEntry {
+                title: title.to_string(),
+                ..Default::default()
+            };
+            for field in fields {
+                parse_field(&mut entry, field)
+                    .ok_or_else(|| format!("line {}: bad field {:?}", line_no + 1, field))?;
+            }
+            entries.insert(crc, entry);
+        }
+        Ok(RomDb { entries })
+    }
+
+    pub fn lookup(&self, prg_chr_crc32: u32) -> Option<&DbEntry> {
+        self.entries.get(&prg_chr_crc32)
+    }
+}
+
+fn parse_field(entry: &mut DbEntry, field: &str) -> Option<()> {
+    let (key, value) = field.split_once('=')?;
+    match key {
+        "mapper" => entry.mapper = Some(value.parse().ok()?),
+        "submapper" => entry.submapper = Some(value.parse().ok()?),
+        "mirroring" => {
+            entry.mirroring = Some(match value {
+                "h" => Mirroring::Horizontal,
+                "v" => Mirroring::Vertical,
+                "4" => Mirroring::FourScreen,
+                _ => return None,
+            })
+        }
+        "battery" => {
+            entry.battery = Some(match value {
+                "0" => false,
+                "1" => true,
+                _ => return None,
+            })
+        }
+        "region" => entry.region = Some(Region::from_name(value)?),
+        _ => return None,
+    }
+    Some(())
+}
+
+lazy_static! {
+    static ref BUILTIN_DB: RomDb = RomDb::parse(include_str!("../data/romdb.txt"))
+        .unwrap_or_else(|err| panic!("bundled romdb.txt is invalid: {}", err));
+}
+
+pub fn builtin() -> &'static RomDb {
+    &BUILTIN_DB
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::rom::test::test_rom;
+
+    #[test]
+    fn test_builtin_db() {
+        let entry = builtin().lookup(0x3337EC46).unwrap();
+        assert_eq!(entry.title, "Super Mario Bros. (World)");
+        assert_eq!(entry.mapper, Some(0));
+        assert_eq!(entry.mirroring, Some(Mirroring::Vertical));
+    }
+
+    #[test]
+    fn test_parse() {
+        let db = RomDb::parse(
+            "# comment\n\n\
+             0123ABCD mapper=4 mirroring=v battery=1 region=pal # Some Game (E)\n\
+             deadbeef submapper=2\n",
+        )
+        .unwrap();
+
+        let entry = db.lookup(0x0123ABCD).unwrap();
+        assert_eq!(entry.mapper, Some(4));
+        assert_eq!(entry.submapper, None);
+        assert_eq!(entry.mirroring, Some(Mirroring::Vertical));
+        assert_eq!(entry.battery, Some(true));
+        assert_eq!(entry.region, Some(Region::Pal));
+        assert_eq!(entry.title, "Some Game (E)");
+        assert_eq!(db.lookup(0xDEADBEEF).unwrap().submapper, Some(2));
+        assert!(db.lookup(0).is_none());
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(RomDb::parse("xyz mapper=1").is_err());
+        assert!(RomDb::parse("12345678 mapper=one").is_err());
+        assert!(RomDb::parse("12345678 colour=blue").is_err());
+    }
+
+    #[test]
+    fn test_apply() {
+        let mut rom = test_rom();
+        let entry = DbEntry {
+            mapper: Some(1),
+            mirroring: Some(Mirroring::Vertical),
+            battery: Some(true),
+            ..Default::default()
+        };
+
+        // test_rom is mapper 3, vertical, no battery
+        assert_eq!(
+            entry.apply(&mut rom),
+            vec![Correction::Mapper(3, 1), Correction::Battery(false, true)]
+        );
+        assert_eq!(rom.mapper, 1);
+        assert!(rom.battery);
+        assert_eq!(entry.apply(&mut rom), vec![]);
+
+        let correction = Correction::Mirroring(Mirroring::Horizontal, Mirroring::Vertical);
+        assert_eq!(correction.to_string(), "mirroring horizontal -> vertical");
+    }
+}
@@ -0,0 +1,217 @@
+use std::fmt;
+
+// save states are a flat byte stream: a small header, then every component
+// writes its fields in a fixed order and reads them back in the same order
+
+const STATE_MAGIC: [u8; 4] = *b"NESS";
+const STATE_VERSION: u8 = 9;
+
+#[derive(Debug, PartialEq)]
+pub enum StateError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    SizeMismatch {
+        what: &'static str,
+        expected: usize,
+        actual: usize,
+    },
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::BadMagic => write!(f, "not a save state"),
+            StateError::UnsupportedVersion(version) => {
+                write!(f, "unsupported save state version {}", version)
+            }
+            StateError::Truncated => write!(f, "save state is truncated"),
+            StateError::SizeMismatch {
+                what,
+                expected,
+                actual,
+            } => write!(
+                f,
+                "save state {} is {} bytes, this cartridge has {}",
+                what, actual, expected
+            ),
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+pub trait Snapshot {
+    fn snapshot(&self, w: &mut StateWriter);
+    fn restore(&mut self, r: &mut StateReader) -> Result<(), StateError>;
+}
+
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl Default for StateWriter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl StateWriter {
+    pub fn new() -> Self {
+        let mut buf = STATE_MAGIC.to_vec();
+        buf.push(STATE_VERSION);
+        StateWriter { buf }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend(value.to_le_bytes());
+    }
+
+    pub fn write_bytes(&mut self, data: &[u8]) {
+        self.write_u32(data.len() as u32);
+        self.buf.extend(data);
+    }
+
+    pub fn finish(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(data: &'a [u8]) -> Result<Self, StateError> {
+        if data.len() < STATE_MAGIC.len() + 1 || data[0..4] != STATE_MAGIC {
+            return Err(StateError::BadMagic);
+        }
+        if data[4] != STATE_VERSION {
+            return Err(StateError::UnsupportedVersion(data[4]));
+        }
+        Ok(StateReader { data, pos: 5 })
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        let end = self.pos.checked_add(len).ok_or(StateError::Truncated)?;
+        let slice = self.data.get(self.pos..end).ok_or(StateError::Truncated)?;
+        self.pos = end;
+        Ok(slice)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        let bytes = self.take(4)?;
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    // reads a block that has to fit an existing buffer exactly
+    pub fn read_into(&mut self, what: &'static str, dest: &mut [u8]) -> Result<(), StateError> {
+        let data = self.read_bytes()?;
+        if data.len() != dest.len() {
+            return Err(StateError::SizeMismatch {
+                what,
+                expected: dest.len(),
+                actual: data.len(),
+            });
+        }
+        dest.copy_from_slice(data);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::{Mem, CPU};
+    use crate::rom::test::test_rom;
+    use crate::rom::Chr;
+
+    fn chr_ram_cpu() -> CPU {
+        let mut rom = test_rom();
+        rom.chr = Chr::Ram(vec![0; 0x2000]);
+        CPU::new(Bus::new(rom))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let mut cpu = chr_ram_cpu();
+        cpu.register_a = 0x89;
+        cpu.program_counter = 0x8123;
+        cpu.mem_write(0x0010, 0x67);
+        let state = cpu.save_state();
+
+        let mut restored = chr_ram_cpu();
+        restored.load_state(&state).unwrap();
+        assert_eq!(restored.register_a, 0x89);
+        assert_eq!(restored.program_counter, 0x8123);
+        assert_eq!(restored.mem_read(0x0010), 0x67);
+        assert_eq!(restored.save_state(), state);
+    }
+
+    #[test]
+    fn test_chr_ram_is_saved() {
+        let mut chr = Chr::Ram(vec![0; 0x2000]);
+        chr.write(0x0123, 0x45);
+        let mut w = StateWriter::new();
+        chr.snapshot(&mut w);
+        let state = w.finish();
+
+        let mut restored = Chr::Ram(vec![0; 0x2000]);
+        restored
+            .restore(&mut StateReader::new(&state).unwrap())
+            .unwrap();
+        assert_eq!(restored.read(0x0123), 0x45);
+    }
+
+    #[test]
+    fn test_chr_ram_size_mismatch() {
+        let mut w = StateWriter::new();
+        Chr::Ram(vec![0; 0x2000]).snapshot(&mut w);
+        let state = w.finish();
+
+        let mut chr = Chr::Ram(vec![0; 0x4000]);
+        assert_eq!(
+            chr.restore(&mut StateReader::new(&state).unwrap()),
+            Err(StateError::SizeMismatch {
+                what: "CHR RAM",
+                expected: 0x4000,
+                actual: 0x2000
+            })
+        );
+    }
+
+    #[test]
+    fn test_truncated() {
+        let state = chr_ram_cpu().save_state();
+        let mut cpu = chr_ram_cpu();
+        assert_eq!(
+            cpu.load_state(&state[..state.len() - 1]),
+            Err(StateError::Truncated)
+        );
+        assert_eq!(cpu.load_state(b"junk"), Err(StateError::BadMagic));
+    }
+}
@@ -0,0 +1,132 @@
+use crate::cpu::CPU;
+use crate::opcodes;
+
+// one line per instruction, laid out like the nestest log
+pub fn trace(cpu: &CPU) -> String {
+    let instruction = opcodes::format_instruction(cpu);
+    format!(
+        "{:04X}  {}  A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+        cpu.program_counter,
+        instruction,
+        cpu.register_a,
+        cpu.register_x,
+        cpu.register_y,
+        cpu.status,
+        cpu.stack_pointer
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cpu::Mem;
+    use crate::rom::test::test_rom;
+
+    #[test]
+    fn test_format_trace() {
+        let mut bus = Bus::new(test_rom());
+        bus.mem_write(100, 0xa2);
+        bus.mem_write(101, 0x01);
+        bus.mem_write(102, 0xca);
+        bus.mem_write(103, 0x88);
+        bus.mem_write(104, 0xCD);
+        bus.mem_write(105, 0xF5);
+        bus.mem_write(106, 0xC5);
+        bus.mem_write(107, 0xB0);
+        bus.mem_write(108, 0x04);
+        bus.mem_write(109, 0x00);
+        bus.mem_write(110, 0x00);
+        bus.mem_write(111, 0x00);
+        bus.mem_write(112, 0x00);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        cpu.register_a = 1;
+        cpu.register_x = 2;
+        cpu.register_y = 3;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            result.push(trace(cpu));
+        });
+        assert_eq!(
+            "0064  A2 01     LDX #$01                        A:01 X:02 Y:03 P:24 SP:FD",
+            result[0]
+        );
+        assert_eq!(
+            "0066  CA        DEX                             A:01 X:01 Y:03 P:24 SP:FD",
+            result[1]
+        );
+        assert_eq!(
+            "0067  88        DEY                             A:01 X:00 Y:03 P:26 SP:FD",
+            result[2]
+        );
+        assert_eq!(
+            "0068  CD F5 C5  CMP $C5F5                       A:01 X:00 Y:02 P:24 SP:FD",
+            result[3]
+        );
+        assert_eq!(
+            "006B  B0 04     BCS $0071                       A:01 X:00 Y:02 P:27 SP:FD",
+            result[4]
+        );
+        assert_eq!(cpu.program_counter, 0x71);
+    }
+
+    #[test]
+    fn test_format_mem_access() {
+        let mut bus = Bus::new(test_rom());
+        // ORA ($33), Y
+        bus.mem_write(100, 0x11);
+        bus.mem_write(101, 0x33);
+
+        //data
+        bus.mem_write(0x33, 00);
+        bus.mem_write(0x34, 04);
+
+        //target cell
+        bus.mem_write(0x400, 0xAA);
+
+        let mut cpu = CPU::new(bus);
+        cpu.reset();
+        cpu.program_counter = 0x64;
+        cpu.register_y = 0;
+        let mut result: Vec<String> = vec![];
+        cpu.run_with_callback(|cpu| {
+            result.push(trace(cpu));
+        });
+        assert_eq!(
+            "0064  11 33     ORA ($33),Y = 0400 @ 0400 = AA  A:00 X:00 Y:00 P:24 SP:FD",
+            result[0]
+        );
+    }
+
+    #[test]
+    fn test_trace_has_no_side_effects() {
+        let mut bus = Bus::new(test_rom());
+        // LDA $2002, with the operand shown through (zp),Y too
+        bus.mem_write(100, 0xAD);
+        bus.mem_write(101, 0x02);
+        bus.mem_write(102, 0x20);
+        bus.mem_write(103, 0xB1);
+        bus.mem_write(104, 0x10);
+        bus.mem_write(0x10, 0x02);
+        bus.mem_write(0x11, 0x20);
+        // into vblank, which starts 241 lines in
+        bus.tick(241 * 341 / 3 + 100);
+
+        let mut cpu = CPU::new(bus);
+        cpu.program_counter = 0x64;
+        trace(&cpu);
+        cpu.program_counter = 0x67;
+        assert_eq!(
+            "0067  B1 10     LDA ($10),Y = 2002 @ 2002 = 80  A:00 X:00 Y:00 P:24 SP:FD",
+            trace(&cpu)
+        );
+        assert_eq!(cpu.bus.ppu.peek_status() & 0x80, 0x80);
+        // the real read still clears vblank
+        cpu.program_counter = 0x64;
+        cpu.step();
+        assert_eq!(cpu.register_a & 0x80, 0x80);
+        assert_eq!(cpu.bus.ppu.peek_status() & 0x80, 0);
+    }
+}